@@ -0,0 +1,46 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use kdl_gen::{Generator, GeneratorConfig};
+
+const SEEDS: u64 = 20;
+
+fn configs() -> [(&'static str, GeneratorConfig); 4] {
+    [
+        ("v1", GeneratorConfig::default()),
+        ("v1-ascii", GeneratorConfig::default().ascii_only(true)),
+        ("v2", GeneratorConfig::default().spec_version(2)),
+        ("v2-ascii", GeneratorConfig::default().spec_version(2).ascii_only(true)),
+    ]
+}
+
+// Generates the same set of documents on every iteration, and reports throughput
+// in bytes of KDL written.
+fn generate(c: &mut Criterion) {
+    bench_group(c, "generate");
+}
+
+// The same, compiling every regex again for each string sampled from it, as
+// the generator did before compiled regexes were cached.
+fn generate_uncached(c: &mut Criterion) {
+    kdl_gen::cache_regexes(false);
+    bench_group(c, "generate-uncached");
+    kdl_gen::cache_regexes(true);
+}
+
+fn bench_group(c: &mut Criterion, name: &str) {
+    let mut group = c.benchmark_group(name);
+    for (name, conf) in configs() {
+        let generator = Generator::new(conf).unwrap();
+        let bytes: usize = (0..SEEDS).map(|seed| generator.generate_string(seed).len()).sum();
+        group.throughput(Throughput::Bytes(bytes as u64));
+        group.bench_function(name, |b| b.iter(|| {
+            for seed in 0..SEEDS {
+                let mut out = Vec::new();
+                generator.generate_to_writer(&mut out, seed).unwrap();
+            }
+        }));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, generate, generate_uncached);
+criterion_main!(benches);
@@ -0,0 +1,19 @@
+#![no_main]
+
+use kdl_gen::{Generator, GeneratorConfig};
+use libfuzzer_sys::fuzz_target;
+
+// The first byte picks the spec version, and the rest drive the generator, so
+// every input maps to a valid document. Swap kdl_gen::parse out for the parser
+// under test.
+fuzz_target!(|data: &[u8]| {
+    let Some((&version, bytes)) = data.split_first() else {
+        return;
+    };
+    let spec_version = u32::from(version % 2) + 1;
+    let generator = Generator::new(GeneratorConfig::default().spec_version(spec_version)).unwrap();
+    let doc = generator.generate_from_bytes(bytes);
+    if let Err(e) = kdl_gen::parse::parse(&doc, spec_version) {
+        panic!("{}\n{}", e, doc);
+    }
+});
@@ -0,0 +1,159 @@
+use clap::{Args, Parser};
+
+use crate::json::Json;
+use crate::weights::Weights;
+
+/// Everything which controls the shape of the generated documents. The
+/// defaults match the command line defaults of the kdl-gen binary.
+#[derive(Args, Clone, Debug, PartialEq)]
+pub struct GeneratorConfig {
+    #[clap(default_value_t=3, short, long)]
+    pub depth_max: u32,
+
+    #[clap(default_value_t=3, short, long)]
+    pub nodes_per_child_max: u32,
+
+    #[clap(default_value_t=0, long)]
+    pub nodes_per_child_min: u32,
+
+    /// Write this many nodes in all, spread over the depth and breadth the
+    /// other options allow, rather than picking how many each has at random
+    #[clap(long)]
+    pub total_nodes: Option<u32>,
+
+    /// Never write more than this many bytes, finishing the document with
+    /// the smallest productions that fit as it gets close
+    #[clap(long)]
+    pub max_bytes: Option<usize>,
+
+    #[clap(default_value_t=3, short, long)]
+    pub extra_space_max: u32,
+
+    #[clap(default_value_t=10, short, long)]
+    pub props_or_args_max: u32,
+
+    #[clap(default_value_t=1, short, long)]
+    pub blank_lines_max: u32,
+
+    #[clap(default_value_t=20, short, long)]
+    pub identifier_len_max: u32,
+
+    #[clap(default_value_t=100, short, long)]
+    pub string_len_max: u32,
+
+    #[clap(default_value_t=10, short='l', long)]
+    pub num_len_max: u32,
+
+    #[clap(default_value_t=100, short, long)]
+    pub comment_len_max: u32,
+
+    #[clap(default_value_t=false, short, long)]
+    pub ascii_only: bool,
+
+    #[clap(default_value_t=1, long, value_parser=clap::value_parser!(u32).range(1..=2))]
+    pub spec_version: u32,
+
+    #[clap(default_value_t=false, long)]
+    pub debug: bool,
+
+    /// The most '#'s around a raw string, other than as many as its body
+    /// needs to not end early
+    #[clap(default_value_t=8, long)]
+    pub raw_hash_max: u32,
+
+    /// The most multi-line comments nested inside of each other, not
+    /// counting the outermost one
+    #[clap(default_value_t=4, long)]
+    pub comment_nesting_max: u32,
+
+    /// Break raw string bodies up with '"'s followed by one '#' too few to
+    /// end the string
+    #[clap(default_value_t=false, long)]
+    pub raw_string_near_misses: bool,
+
+    /// Make half of the bare identifiers start or end with a keyword, such
+    /// as `truex` or `is_null`
+    #[clap(default_value_t=false, long)]
+    pub near_keywords: bool,
+
+    /// Weights for the choices made by the grammar, from a config file
+    #[clap(skip)]
+    pub weights: Weights,
+}
+
+impl Default for GeneratorConfig {
+    fn default() -> Self {
+        // Parse an empty command line so the defaults only live in one place
+        #[derive(Parser)]
+        struct Defaults {
+            #[clap(flatten)]
+            conf: GeneratorConfig,
+        }
+
+        Defaults::parse_from(["kdl-gen"]).conf
+    }
+}
+
+impl GeneratorConfig {
+    pub fn to_json(&self) -> Json {
+        let number = |n: u32| Json::Number(n.to_string());
+        Json::object(vec![
+            ("depth_max", number(self.depth_max)),
+            ("nodes_per_child_max", number(self.nodes_per_child_max)),
+            ("nodes_per_child_min", number(self.nodes_per_child_min)),
+            ("total_nodes", self.total_nodes.map_or(Json::Null, number)),
+            ("max_bytes", self.max_bytes.map_or(Json::Null, |n| Json::Number(n.to_string()))),
+            ("extra_space_max", number(self.extra_space_max)),
+            ("props_or_args_max", number(self.props_or_args_max)),
+            ("blank_lines_max", number(self.blank_lines_max)),
+            ("identifier_len_max", number(self.identifier_len_max)),
+            ("string_len_max", number(self.string_len_max)),
+            ("num_len_max", number(self.num_len_max)),
+            ("comment_len_max", number(self.comment_len_max)),
+            ("ascii_only", Json::Bool(self.ascii_only)),
+            ("spec_version", number(self.spec_version)),
+            ("debug", Json::Bool(self.debug)),
+            ("raw_hash_max", number(self.raw_hash_max)),
+            ("comment_nesting_max", number(self.comment_nesting_max)),
+            ("raw_string_near_misses", Json::Bool(self.raw_string_near_misses)),
+            ("near_keywords", Json::Bool(self.near_keywords)),
+            ("weights", self.weights.to_json()),
+        ])
+    }
+}
+
+macro_rules! setters {
+    ($($name:ident: $ty:ty),* $(,)?) => {
+        impl GeneratorConfig {
+            $(
+                pub fn $name(mut self, $name: $ty) -> Self {
+                    self.$name = $name;
+                    self
+                }
+            )*
+        }
+    };
+}
+
+setters! {
+    depth_max: u32,
+    nodes_per_child_max: u32,
+    nodes_per_child_min: u32,
+    total_nodes: Option<u32>,
+    max_bytes: Option<usize>,
+    extra_space_max: u32,
+    props_or_args_max: u32,
+    blank_lines_max: u32,
+    identifier_len_max: u32,
+    string_len_max: u32,
+    num_len_max: u32,
+    comment_len_max: u32,
+    ascii_only: bool,
+    spec_version: u32,
+    debug: bool,
+    raw_hash_max: u32,
+    comment_nesting_max: u32,
+    raw_string_near_misses: bool,
+    near_keywords: bool,
+    weights: Weights,
+}
@@ -0,0 +1,104 @@
+use std::fmt;
+use rand::Rng;
+use rand::seq::SliceRandom;
+
+use crate::json::Json;
+
+/// The kinds of grammar-aware corruption which can be applied to an
+/// otherwise valid document.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Category {
+    /// The closing '}' of a node-children block is removed.
+    UnclosedChildren,
+    /// A bare identifier is replaced by a keyword.
+    KeywordIdentifier,
+    /// An '_' is inserted straight after the '0x' of a hex number.
+    HexLeadingUnderscore,
+    /// A '"' and matching '#'s are inserted into a raw string body.
+    RawStringTerminator,
+    /// The hex digits of a unicode escape are replaced by a surrogate, a
+    /// value past 10FFFF, more than 6 digits or none at all.
+    InvalidUnicodeEscape,
+    /// A '}' is appended with no block to close. Only used when the
+    /// document has no other corruptible sites.
+    StrayBrace,
+}
+
+impl fmt::Display for Category {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Category::UnclosedChildren => "unclosed-children",
+            Category::KeywordIdentifier => "keyword-identifier",
+            Category::HexLeadingUnderscore => "hex-leading-underscore",
+            Category::RawStringTerminator => "raw-string-terminator",
+            Category::InvalidUnicodeEscape => "invalid-unicode-escape",
+            Category::StrayBrace => "stray-brace",
+        })
+    }
+}
+
+/// A span of the output which was produced by a production that can be
+/// corrupted in the given way.
+#[derive(Clone, Copy, Debug)]
+pub struct Site {
+    pub category: Category,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// The corruption which was applied, and the byte offset it was applied at.
+#[derive(Clone, Copy, Debug)]
+pub struct Corruption {
+    pub category: Category,
+    pub offset: usize,
+}
+
+impl Corruption {
+    pub fn to_json(self) -> Json {
+        Json::object(vec![
+            ("category", Json::String(self.category.to_string())),
+            ("offset", Json::Number(self.offset.to_string())),
+        ])
+    }
+}
+
+/// Pick one of the recorded sites and apply its corruption to the document in place.
+pub fn apply<R: Rng>(doc: &mut Vec<u8>, sites: &[Site], spec_version: u32, rng: &mut R) -> Corruption {
+    let stray = Site { category: Category::StrayBrace, start: doc.len(), end: doc.len() };
+    let site = sites.choose(rng).unwrap_or(&stray);
+
+    let (offset, remove, insert) = match site.category {
+        Category::UnclosedChildren => (site.start, site.end - site.start, String::new()),
+        Category::KeywordIdentifier => {
+            let keywords: &[&str] = match spec_version {
+                1 => &["true", "false", "null"],
+                _ => &["true", "false", "null", "inf", "nan"],
+            };
+            (site.start, site.end - site.start, keywords.choose(rng).unwrap().to_string())
+        }
+        Category::HexLeadingUnderscore => (site.start, 0, "_".to_string()),
+        Category::RawStringTerminator => {
+            let body = std::str::from_utf8(&doc[site.start..site.end]).unwrap();
+            let boundaries: Vec<usize> = body.char_indices()
+                .map(|(i, _)| i)
+                .chain(std::iter::once(body.len()))
+                .collect();
+            let offset = site.start + boundaries.choose(rng).unwrap();
+            let hashes = doc[..site.start - 1].iter().rev().take_while(|&&b| b == b'#').count();
+            (offset, 0, format!("\"{}", "#".repeat(hashes)))
+        }
+        Category::InvalidUnicodeEscape => {
+            let digits = match rng.gen_range(0..4) {
+                0 => format!("{:0width$x}", rng.gen_range(0xD800..=0xDFFF), width = rng.gen_range(4..=6)),
+                1 => format!("{:x}", rng.gen_range(0x110000..=0xFFFFFF)),
+                2 => format!("{:0width$x}", rng.gen_range(1..=0xD7FF), width = rng.gen_range(7..=9)),
+                _ => String::new(),
+            };
+            (site.start, site.end - site.start, digits)
+        }
+        Category::StrayBrace => (site.start, 0, "}".to_string()),
+    };
+
+    doc.splice(offset..offset + remove, insert.into_bytes());
+    Corruption { category: site.category, offset }
+}
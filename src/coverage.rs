@@ -0,0 +1,159 @@
+//! Which productions of the grammar, and which alternatives of its choices,
+//! the documents generated so far have used.
+
+use std::fmt;
+
+use crate::config::GeneratorConfig;
+use crate::gen;
+
+// How much more likely an alternative which hasn't been used yet is picked, when directed
+const UNCOVERED_BOOST: f64 = 16.0;
+
+/// Coverage of the grammar for one configuration, added to by every document
+/// generated with it. When directed, alternatives which haven't been used yet
+/// are picked more often, so that later documents fill in the gaps.
+#[derive(Clone, Debug)]
+pub struct Coverage {
+    pub(crate) spec_version: u32,
+    pub(crate) directed: bool,
+    pub(crate) productions: Vec<ProductionCoverage>,
+    pub(crate) choices: Vec<ChoiceCoverage>,
+    // The limits the configuration sets on recursion, and the furthest any
+    // document has gone toward them
+    pub(crate) raw_hash_max: u32,
+    pub(crate) raw_hashes: u32,
+    pub(crate) comment_nesting_max: u32,
+    pub(crate) comment_nesting: u32,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct ProductionCoverage {
+    pub(crate) name: &'static str,
+    pub(crate) reachable: bool,
+    pub(crate) hits: u64,
+}
+
+// A select, with a count for each alternative, or a maybe, with a count for
+// skipping it and one for taking it.
+#[derive(Clone, Debug)]
+pub(crate) struct ChoiceCoverage {
+    pub(crate) production: Option<usize>,
+    pub(crate) ordinal: usize,
+    pub(crate) maybe: bool,
+    pub(crate) labels: Vec<Option<&'static str>>,
+    // Alternatives which can't be used with the configuration, such as those
+    // given a weight of 0, don't count against completeness
+    pub(crate) reachable: Vec<bool>,
+    pub(crate) hits: Vec<u64>,
+}
+
+impl Coverage {
+    /// Empty coverage of the grammar the configuration generates from.
+    pub fn new(conf: &GeneratorConfig) -> Coverage {
+        gen::coverage(conf)
+    }
+
+    pub fn directed(mut self, directed: bool) -> Self {
+        self.directed = directed;
+        self
+    }
+
+    /// Whether every reachable production and alternative has been used.
+    pub fn is_complete(&self) -> bool {
+        let (covered, total) = self.alternatives();
+        self.productions.iter().all(|p| p.hits > 0 || !p.reachable) && covered == total
+    }
+
+    /// How often each production was used, by name.
+    pub fn production_hits(&self) -> impl Iterator<Item = (&'static str, u64)> + '_ {
+        self.productions.iter().map(|p| (p.name, p.hits))
+    }
+
+    /// The most '#'s around any raw string, and the deepest multi-line
+    /// comment nesting, in the documents so far.
+    pub fn recursion(&self) -> (u32, u32) {
+        (self.raw_hashes, self.comment_nesting)
+    }
+
+    /// Add the counts of another coverage of the same grammar to this one.
+    pub fn merge(&mut self, other: &Coverage) {
+        self.raw_hashes = self.raw_hashes.max(other.raw_hashes);
+        self.comment_nesting = self.comment_nesting.max(other.comment_nesting);
+        for (p, other) in self.productions.iter_mut().zip(&other.productions) {
+            p.hits += other.hits;
+        }
+        for (choice, other) in self.choices.iter_mut().zip(&other.choices) {
+            for (hits, other) in choice.hits.iter_mut().zip(&other.hits) {
+                *hits += other;
+            }
+        }
+    }
+
+    // Covered and total reachable alternatives
+    fn alternatives(&self) -> (usize, usize) {
+        self.choices.iter()
+            .flat_map(|choice| choice.reachable.iter().zip(&choice.hits))
+            .filter(|(&reachable, _)| reachable)
+            .fold((0, 0), |(covered, total), (_, &hits)| (covered + (hits > 0) as usize, total + 1))
+    }
+
+    pub(crate) fn hit(&mut self, choice: usize, alternative: usize) {
+        self.choices[choice].hits[alternative] += 1;
+    }
+
+    /// The weights to pick a select's alternatives with, boosting the ones
+    /// which haven't been used yet.
+    pub(crate) fn directed_weights(&self, choice: usize, weights: Option<&[f64]>) -> Vec<f64> {
+        let choice = &self.choices[choice];
+        (0..choice.hits.len())
+            .map(|i| {
+                let weight = weights.map_or(1.0, |weights| weights[i]);
+                if choice.hits[i] == 0 { weight * UNCOVERED_BOOST } else { weight }
+            })
+            .collect()
+    }
+
+    /// The probability to take a maybe with, boosted toward whichever of
+    /// taking or skipping it hasn't happened yet.
+    pub(crate) fn directed_probability(&self, choice: usize, probability: f64) -> f64 {
+        if probability <= 0.0 || probability >= 1.0 {
+            return probability;
+        }
+        let (skip, take) = match self.choices[choice].hits[..] {
+            [0, taken] if taken > 0 => (UNCOVERED_BOOST * (1.0 - probability), probability),
+            [skipped, 0] if skipped > 0 => (1.0 - probability, UNCOVERED_BOOST * probability),
+            _ => return probability,
+        };
+        take / (skip + take)
+    }
+}
+
+impl fmt::Display for Coverage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reachable = self.productions.iter().filter(|p| p.reachable).count();
+        let covered = self.productions.iter().filter(|p| p.reachable && p.hits > 0).count();
+        writeln!(f, "productions: {}/{}", covered, reachable)?;
+        let (covered, total) = self.alternatives();
+        writeln!(f, "alternatives: {}/{}", covered, total)?;
+        writeln!(f, "raw string hashes: {} (max {})", self.raw_hashes, self.raw_hash_max)?;
+        writeln!(f, "comment nesting: {} (max {})", self.comment_nesting, self.comment_nesting_max)?;
+        for p in self.productions.iter().filter(|p| p.reachable && p.hits == 0) {
+            writeln!(f, "  never used: {}", p.name)?;
+        }
+        // Choices in productions which were never used go without saying
+        for choice in self.choices.iter().filter(|choice| choice.production.is_none_or(|id| self.productions[id].hits > 0)) {
+            let production = choice.production.map_or("", |id| self.productions[id].name);
+            for (i, _) in choice.hits.iter().enumerate().filter(|&(i, &hits)| hits == 0 && choice.reachable[i]) {
+                if choice.maybe {
+                    let what = choice.labels[0].map(|label| format!(" ({})", label)).unwrap_or_default();
+                    let taken = if i == 1 { "taken" } else { "skipped" };
+                    writeln!(f, "  never {}: {} maybe {}{}", taken, production, choice.ordinal, what)?;
+                } else {
+                    let what = choice.labels[i].map(|label| format!(" ({})", label.escape_debug())).unwrap_or_default();
+                    writeln!(f, "  never picked: {} select {} alternative {}{}", production, choice.ordinal, i, what)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
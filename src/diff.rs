@@ -0,0 +1,106 @@
+//! Comparing what several parsers make of the same document.
+//!
+//! Each parser prints what it parsed as JSON or KDL, which is normalized so
+//! that parsers which format their output differently can still agree.
+
+use std::process::ExitStatus;
+
+use crate::json::Json;
+use crate::parse;
+
+/// How a parser finished with a document.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// It exited successfully, printing this output, normalized.
+    Parsed(String),
+    /// It exited unsuccessfully with this status, as a parser rejecting the
+    /// document should.
+    Rejected(i32),
+    /// It was killed by a signal, or the shell running it reported one with a
+    /// status over 128.
+    Crashed(Option<i32>),
+    /// It was still running at the timeout, and was killed.
+    TimedOut,
+}
+
+impl Outcome {
+    /// The outcome of a parser which exited with `status` after printing
+    /// `stdout`.
+    pub fn new(status: ExitStatus, stdout: &[u8], spec_version: u32) -> Outcome {
+        match status.code() {
+            Some(0) => Outcome::Parsed(normalize(&String::from_utf8_lossy(stdout), spec_version)),
+            Some(code) if code > 128 => Outcome::Crashed(Some(code - 128)),
+            Some(code) => Outcome::Rejected(code),
+            None => Outcome::Crashed(signal(status)),
+        }
+    }
+
+    /// Whether two parsers agree: both parsed the document to the same
+    /// output, or both rejected it, whatever their statuses.
+    pub fn agrees_with(&self, other: &Outcome) -> bool {
+        match (self, other) {
+            (Outcome::Parsed(a), Outcome::Parsed(b)) => a == b,
+            (Outcome::Rejected(_), Outcome::Rejected(_)) => true,
+            _ => false,
+        }
+    }
+
+    pub fn to_json(&self) -> Json {
+        match self {
+            Outcome::Parsed(output) => Json::object(vec![
+                ("outcome", Json::String("parsed".to_string())),
+                ("output", Json::String(output.clone())),
+            ]),
+            Outcome::Rejected(code) => Json::object(vec![
+                ("outcome", Json::String("rejected".to_string())),
+                ("status", Json::Number(code.to_string())),
+            ]),
+            Outcome::Crashed(signal) => Json::object(vec![
+                ("outcome", Json::String("crashed".to_string())),
+                ("signal", signal.map_or(Json::Null, |signal| Json::Number(signal.to_string()))),
+            ]),
+            Outcome::TimedOut => Json::object(vec![
+                ("outcome", Json::String("timed out".to_string())),
+            ]),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn signal(status: ExitStatus) -> Option<i32> {
+    use std::os::unix::process::ExitStatusExt;
+    status.signal()
+}
+
+#[cfg(not(unix))]
+fn signal(_: ExitStatus) -> Option<i32> {
+    None
+}
+
+/// Why the outcomes of the parsers on one document are worth keeping, if
+/// they are: one of them crashed or timed out, or they didn't all agree.
+pub fn disagreement(outcomes: &[Outcome]) -> Option<&'static str> {
+    if outcomes.iter().any(|outcome| matches!(outcome, Outcome::Crashed(_))) {
+        Some("crash")
+    } else if outcomes.contains(&Outcome::TimedOut) {
+        Some("timeout")
+    } else if outcomes.windows(2).any(|pair| !pair[0].agrees_with(&pair[1])) {
+        Some("disagreement")
+    } else {
+        None
+    }
+}
+
+/// Put a parser's output in a form which can be compared with another's. JSON
+/// is written compactly with the fields of every object sorted and numbers in
+/// one exact form, KDL in the test suite's canonical form, and anything else
+/// with the whitespace around it trimmed.
+pub fn normalize(output: &str, spec_version: u32) -> String {
+    if let Some(json) = Json::parse(output) {
+        return json.normalized().compact();
+    }
+    match parse::parse(output, spec_version) {
+        Ok(doc) => doc.to_model(output, spec_version).to_kdl(spec_version),
+        Err(_) => output.trim().to_string(),
+    }
+}
@@ -0,0 +1,50 @@
+//! Driving the generator from fuzzer input, so that a coverage-guided fuzzer
+//! such as libFuzzer can mutate documents through their decisions.
+
+use rand::{Error, RngCore};
+
+/// An rng which reads its output from a fixed slice of bytes, and returns
+/// zeroes once they have all been used up. Zeroes make every decision take its
+/// first alternative, skip its `maybe`s and repeat the fewest times, which
+/// always brings a document to an end.
+///
+/// Numbers are read big-endian, so that the first byte read for a decision is
+/// the one that matters most to it.
+#[derive(Clone, Debug)]
+pub struct ByteRng {
+    bytes: Vec<u8>,
+    pos: usize,
+}
+
+impl ByteRng {
+    pub fn new(bytes: &[u8]) -> ByteRng {
+        ByteRng { bytes: bytes.to_vec(), pos: 0 }
+    }
+}
+
+impl RngCore for ByteRng {
+    fn next_u32(&mut self) -> u32 {
+        let mut buf = [0; 4];
+        self.fill_bytes(&mut buf);
+        u32::from_be_bytes(buf)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let mut buf = [0; 8];
+        self.fill_bytes(&mut buf);
+        u64::from_be_bytes(buf)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        let rest = &self.bytes[self.pos..];
+        let len = dest.len().min(rest.len());
+        dest[..len].copy_from_slice(&rest[..len]);
+        dest[len..].fill(0);
+        self.pos += len;
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
@@ -1,21 +1,84 @@
+use std::cell::{Cell, OnceCell, RefCell};
+use std::collections::HashMap;
 use std::io;
 use std::io::Write;
-use rand::{Rng, RngCore, Error};
-use rand::seq::SliceRandom;
-use regex_syntax::Parser;
-
-use crate::Configuration;
+use std::ops::RangeInclusive;
+use std::rc::Rc;
+use rand::Rng;
+use regex_syntax::ParserBuilder;
+
+use crate::config::GeneratorConfig;
+use crate::corrupt::{self, Category, Corruption, Site};
+use crate::coverage::Coverage;
+use crate::model::{self, Capture, Scope};
+use crate::parse;
+use crate::tape::Decision;
+use crate::trace::{Trace, TraceSpan};
+use crate::verify;
+
+mod engine;
+mod v2;
+mod variant;
+
+use engine::{ChoiceWeights, Limit, Production, Rule};
 
 struct Context<'t, T: Write, R: Rng> {
-    conf: &'t Configuration,
+    conf: &'t GeneratorConfig,
     out: &'t mut T,
     rng: &'t mut R,
+    weights: &'t ChoiceWeights,
     depth: u32,
+    comment_depth: u32,
+    // How many nodes the next nodes or node-children is to hold, with --total-nodes
+    budget: Option<u32>,
+    pos: usize,
+    last_byte: u8,
+    sites: Option<Vec<Site>>,
+    model: Option<model::Builder>,
+    capture: Option<Vec<u8>>,
+    tape: Option<TapeMode<'t>>,
+    coverage: Option<&'t mut Coverage>,
+    trace: Option<Trace>,
+    // How many traced productions are being written
+    trace_depth: usize,
+    held: Option<Held>,
+    indents: Vec<String>,
+}
+
+/// Whether the decisions made while generating a document are recorded, or
+/// replayed instead of being made at random.
+pub(crate) enum TapeMode<'t> {
+    Record(&'t mut Vec<Decision>),
+    Replay(&'t [Decision]),
+}
+
+// Output which is being held back until what comes before it is known, and
+// how much had been written and recorded when it started.
+struct Held {
+    bytes: Vec<u8>,
+    sites: usize,
+    spans: usize,
+    capture: Option<usize>,
+    last_byte: u8,
 }
 
 impl<'t, T: Write, R: Rng> Write for Context<'t, T, R> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.out.write(buf)
+        let written = match self.held.as_mut() {
+            Some(held) => {
+                held.bytes.extend_from_slice(buf);
+                buf.len()
+            }
+            None => self.out.write(buf)?,
+        };
+        self.pos += written;
+        if let Some(&last) = buf[..written].last() {
+            self.last_byte = last;
+        }
+        if let Some(capture) = self.capture.as_mut() {
+            capture.extend_from_slice(&buf[..written]);
+        }
+        Ok(written)
     }
 
     fn flush(&mut self) -> io::Result<()> {
@@ -23,754 +86,929 @@ impl<'t, T: Write, R: Rng> Write for Context<'t, T, R> {
     }
 }
 
-impl<'t, T: Write, R: Rng> RngCore for Context<'t, T, R> {
-    fn next_u32(&mut self) -> u32 {
-        self.rng.next_u32()
+impl<'t, T: Write, R: Rng> Context<'t, T, R> {
+    fn new(conf: &'t GeneratorConfig, weights: &'t ChoiceWeights, out: &'t mut T, rng: &'t mut R) -> Self {
+        Context {
+            conf,
+            out,
+            rng,
+            weights,
+            depth: 0,
+            comment_depth: 0,
+            budget: conf.total_nodes,
+            pos: 0,
+            last_byte: 0,
+            sites: None,
+            model: None,
+            capture: None,
+            tape: None,
+            coverage: None,
+            trace: None,
+            trace_depth: 0,
+            held: None,
+            indents: Vec::new(),
+        }
     }
 
-    fn next_u64(&mut self) -> u64 {
-        self.rng.next_u64()
+    // Every random decision goes through one of the methods below, so that it
+    // can be recorded or replayed.
+
+    fn choose_index(&mut self, len: usize) -> usize {
+        self.choose_weighted(len, None)
     }
 
-    fn fill_bytes(&mut self, dest: &mut [u8]) {
-        self.rng.fill_bytes(dest)
+    // Picks one of len alternatives, in proportion to their weights if given.
+    // A replayed pick of an alternative with no weight, which doesn't fit in
+    // max_bytes or was weighed out, is made at random instead, and so is
+    // every pick if no alternative has any weight.
+    fn choose_weighted(&mut self, len: usize, weights: Option<&[f64]>) -> usize {
+        let weighed = |i: usize| weights.is_none_or(|weights| weights[i] > 0.0);
+        let index = match self.replay(|d| match d { Decision::Select(i) => Some((*i).min(len - 1)).filter(|&i| weighed(i)), _ => None }) {
+            Some(i) => i,
+            None => match weights.and_then(|weights| Some((weights, weights.iter().rposition(|&weight| weight > 0.0)?))) {
+                Some((weights, last)) => {
+                    let mut left = self.rng.gen::<f64>() * weights.iter().sum::<f64>();
+                    (0..last).find(|&i| { left -= weights[i]; left < 0.0 }).unwrap_or(last)
+                }
+                None => self.rng.gen_range(0..len as u32) as usize,
+            },
+        };
+        self.record(Decision::Select(index));
+        index
     }
 
-    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
-        self.rng.try_fill_bytes(dest)
+    // Takes a maybe with the given probability. A replayed take of one which
+    // can't be taken is made at random instead.
+    fn maybe(&mut self, probability: f64) -> bool {
+        let taken = match self.replay(|d| match d { Decision::Maybe(taken) => Some(*taken).filter(|&taken| !taken || probability > 0.0), _ => None }) {
+            Some(taken) => taken,
+            None if probability >= 1.0 => true,
+            None => self.rng.gen::<f32>() > (1.0 - probability) as f32,
+        };
+        self.record(Decision::Maybe(taken));
+        taken
     }
-}
 
-impl<'t, T: Write, R: Rng> Context<'t, T, R> {
-    fn get_regex_parser(&self) -> Parser {
-        regex_syntax::ParserBuilder::new().unicode(!self.conf.ascii_only).build()
+    fn range(&mut self, range: RangeInclusive<u32>) -> u32 {
+        let n = match self.replay(|d| match d { Decision::Range(n) => Some(*n), _ => None }) {
+            Some(n) => n.clamp(*range.start(), *range.end()),
+            None => self.rng.gen_range(range),
+        };
+        self.record(Decision::Range(n));
+        n
     }
 
-    fn write_debug(&mut self, s: &str) {
-        if self.conf.debug {
-            self.out.write(s.as_bytes()).unwrap();
+    // A replayed sample the regex doesn't match, which an edit to the tape
+    // can line up with the wrong regex, or which is longer than the sampler
+    // could have made it to fit in max_bytes, is drawn at random instead
+    fn sample_re(&mut self, re: &Sampler) -> String {
+        let ascii_only = self.conf.ascii_only;
+        let replayed = self.replay(|d| match d {
+            Decision::Sample(s) => Some(s.clone())
+                .filter(|s| re.is_match(s) && (s.is_ascii() || !ascii_only) && s.len() <= re.sampler.capacity()),
+            _ => None,
+        });
+        let s = match replayed {
+            Some(s) => s,
+            None => self.rng.sample(&re.sampler),
+        };
+        self.record(Decision::Sample(s.clone()));
+        s
+    }
+
+    // The next decision on the tape being replayed, if it is of the kind `take` accepts
+    fn replay<V>(&mut self, take: impl Fn(&Decision) -> Option<V>) -> Option<V> {
+        match self.tape.as_mut() {
+            Some(TapeMode::Replay(rest)) => {
+                let value = take(rest.first()?)?;
+                *rest = &rest[1..];
+                Some(value)
+            }
+            _ => None,
         }
     }
-}
 
-type GenFn<T, R> = dyn Fn(&mut Context<T, R>) -> io::Result<usize>;
+    fn record(&mut self, decision: Decision) {
+        if let Some(TapeMode::Record(decisions)) = self.tape.as_mut() {
+            decisions.push(decision);
+        }
+    }
 
-pub fn document<'t, T: Write + 'static, R: Rng + 'static>(out: &mut T, rng: &mut R, conf: &Configuration) -> io::Result<usize> {
-    let ctx: &mut Context<T, R> = &mut Context {
-        conf,
-        out,
-        rng,
-        depth: 0,
-    };
+    // Starts the span of a traced production, returning where it is in the trace
+    fn trace_start(&mut self, rule: &'static str) -> Option<usize> {
+        let trace = self.trace.as_mut()?;
+        trace.spans.push(TraceSpan {
+            rule,
+            start: self.pos,
+            end: self.pos,
+            start_line: 0,
+            start_column: 0,
+            end_line: 0,
+            end_column: 0,
+            depth: self.trace_depth,
+        });
+        self.trace_depth += 1;
+        Some(trace.spans.len() - 1)
+    }
 
-    let result = nodes()(ctx);
-    ctx.flush().unwrap();
-    result
-}
+    fn trace_end(&mut self, span: Option<usize>) {
+        if let (Some(trace), Some(span)) = (self.trace.as_mut(), span) {
+            trace.spans[span].end = self.pos;
+            self.trace_depth -= 1;
+        }
+    }
 
-// nodes := linespace* (node nodes?)? linespace*
-fn nodes<T: Write + 'static, R: Rng + 'static>() -> Box<GenFn<T, R>> {
-    Box::new(|ctx| {
-        if ctx.depth > ctx.conf.depth_max {
-            return Ok(0);
+    fn write_debug(&mut self, s: &str) {
+        if self.conf.debug {
+            match self.held.as_mut() {
+                Some(held) => held.bytes.extend_from_slice(s.as_bytes()),
+                None => self.out.write_all(s.as_bytes()).unwrap(),
+            }
+            self.pos += s.len();
         }
+    }
 
-        ctx.write_debug("<NODES>");
+    // Holds back everything written until release, which is positioned as
+    // though it had been written already
+    fn hold(&mut self) {
+        assert!(self.held.is_none(), "output is already being held");
+        self.held = Some(Held {
+            bytes: Vec::new(),
+            sites: self.sites.as_ref().map_or(0, Vec::len),
+            spans: self.trace.as_ref().map_or(0, |trace| trace.spans.len()),
+            capture: self.capture.as_ref().map(Vec::len),
+            last_byte: self.last_byte,
+        });
+    }
 
-        ctx.depth += 1;
-        let result = concat(vec![
-            repeat(linespace(), 0, ctx.conf.blank_lines_max),
-            repeat(node(), 0, ctx.conf.nodes_per_child_max),
-            repeat(linespace(), 0, ctx.conf.blank_lines_max),
-        ])(ctx);
-        ctx.depth -= 1;
+    fn held(&self) -> &[u8] {
+        &self.held.as_ref().unwrap().bytes
+    }
 
-        ctx.write_debug("</NODES>");
+    // Writes prefix, then everything held back, moving what was recorded for
+    // the held output along by the length of the prefix
+    fn release(&mut self, prefix: &[u8]) -> io::Result<()> {
+        let held = self.held.take().unwrap();
+        self.out.write_all(prefix)?;
+        self.out.write_all(&held.bytes)?;
+        self.pos += prefix.len();
+        for site in self.sites.iter_mut().flat_map(|sites| &mut sites[held.sites..]) {
+            site.start += prefix.len();
+            site.end += prefix.len();
+        }
+        for span in self.trace.iter_mut().flat_map(|trace| &mut trace.spans[held.spans..]) {
+            span.start += prefix.len();
+            span.end += prefix.len();
+        }
+        if let (Some(capture), Some(at)) = (self.capture.as_mut(), held.capture) {
+            capture.splice(at..at, prefix.iter().copied());
+        }
+        Ok(())
+    }
 
-        result
-    })
+    // Throws away everything held back, and what was recorded for it, as
+    // though it had never been written. Returns how many bytes that was.
+    fn discard(&mut self) -> usize {
+        let held = self.held.take().unwrap();
+        self.pos -= held.bytes.len();
+        self.last_byte = held.last_byte;
+        if let Some(sites) = self.sites.as_mut() {
+            sites.truncate(held.sites);
+        }
+        if let Some(trace) = self.trace.as_mut() {
+            trace.spans.truncate(held.spans);
+        }
+        if let (Some(capture), Some(at)) = (self.capture.as_mut(), held.capture) {
+            capture.truncate(at);
+        }
+        held.bytes.len()
+    }
 }
 
-// node := ('/-' node-space*)? type? identifier (node-space+ node-prop-or-arg)* (node-space* node-children ws*)? node-space* node-terminator
-fn node<T: Write + 'static, R: Rng + 'static>() -> Box<GenFn<T, R>> {
-    Box::new(|ctx| {
-        ctx.write_debug("<NODE>");
-        let result = concat(vec![
-            maybe(concat(vec![
-                write_literal("/-"),
-                repeat(node_space(), 0, ctx.conf.extra_space_max),
-            ])),
-            maybe(type_rule()),
-            identifier(),
-            repeat(concat(vec![
-                repeat(node_space(), 1, ctx.conf.extra_space_max),
-                node_prop_or_arg(),
-            ]), 0, ctx.conf.props_or_args_max),
-            maybe(concat(vec![
-                repeat(node_space(), 0, ctx.conf.extra_space_max),
-                node_children(),
-                repeat(ws(), 0, ctx.conf.extra_space_max),
-            ])),
-            repeat(node_space(), 0, ctx.conf.extra_space_max),
-            node_terminator(),
-        ])(ctx);
+pub(crate) fn document<T: Write, R: Rng>(out: &mut T, rng: &mut R, conf: &GeneratorConfig, tape: Option<TapeMode>) -> io::Result<usize> {
+    let weights = resolve_weights(conf)?;
+    let ctx = &mut Context::new(conf, &weights, out, rng);
+    ctx.tape = tape;
 
-        ctx.write_debug("</NODE>");
-        result
-    })
+    let result = generate(ctx);
+    ctx.flush().unwrap();
+    result
 }
 
-// node-prop-or-arg := ('/-' node-space*)? (prop | value)
-fn node_prop_or_arg<T: Write + 'static, R: Rng + 'static>() -> Box<GenFn<T, R>> {
-    Box::new(|ctx| {
-        ctx.write_debug("<NODE-PROP-OR-ARG>");
+/// Generate a document, adding what it used to the coverage, and picking
+/// what hasn't been used yet more often if the coverage is directed.
+pub(crate) fn document_with_coverage<T: Write, R: Rng>(out: &mut T, rng: &mut R, conf: &GeneratorConfig, coverage: &mut Coverage) -> io::Result<usize> {
+    if coverage.spec_version != conf.spec_version {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "the coverage is of another spec version's grammar"));
+    }
+    let weights = resolve_weights(conf)?;
+    let ctx = &mut Context::new(conf, &weights, out, rng);
+    ctx.coverage = Some(coverage);
 
-        let result = concat(vec![
-            maybe(concat(vec![
-                write_literal("/-"),
-                repeat(node_space(), 0, ctx.conf.extra_space_max),
-            ])),
-            select(vec![prop(), value()]),
-        ])(ctx);
+    let result = generate(ctx);
+    ctx.flush()?;
+    result
+}
+
+/// Generate a document, and trace where each tagged production was written.
+pub(crate) fn document_with_trace<T: Write, R: Rng>(out: &mut T, rng: &mut R, conf: &GeneratorConfig) -> io::Result<Trace> {
+    let mut doc = Vec::new();
+    let weights = resolve_weights(conf)?;
+    let ctx = &mut Context::new(conf, &weights, &mut doc, rng);
+    ctx.trace = Some(Trace::default());
 
-        ctx.write_debug("</NODE-PROP-OR-ARG>");
+    generate(ctx)?;
+    let mut trace = ctx.trace.take().unwrap();
+    trace.locate(&String::from_utf8_lossy(&doc), conf.spec_version);
 
-        result
-    })
+    out.write_all(&doc)?;
+    out.flush()?;
+    Ok(trace)
 }
 
-// node-children := ('/-' node-space*)? {' nodes '}'
-fn node_children<T: Write + 'static, R: Rng + 'static>() -> Box<GenFn<T, R>> {
-    Box::new(|ctx| {
-        ctx.write_debug("<NODE-CHILDREN>");
+/// Generate a document, adding what it used to the coverage if there is one,
+/// and building a model of it, and check it with the parser. The document is
+/// written either way, and a rejection is returned as an InvalidData error.
+pub(crate) fn verified_document<T: Write, R: Rng>(out: &mut T, rng: &mut R, conf: &GeneratorConfig, coverage: Option<&mut Coverage>) -> io::Result<model::Document> {
+    if coverage.as_ref().is_some_and(|coverage| coverage.spec_version != conf.spec_version) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "the coverage is of another spec version's grammar"));
+    }
+    let mut doc = Vec::new();
+    let weights = resolve_weights(conf)?;
+    let ctx = &mut Context::new(conf, &weights, &mut doc, rng);
+    ctx.coverage = coverage;
+    ctx.trace = Some(Trace::default());
+    ctx.model = Some(model::Builder::new(conf.spec_version));
+
+    generate(ctx)?;
+    let mut trace = ctx.trace.take().unwrap();
+    let model = ctx.model.take().unwrap().finish();
+    out.write_all(&doc)?;
+    out.flush()?;
+
+    let doc = String::from_utf8_lossy(&doc);
+    trace.locate(&doc, conf.spec_version);
+    verify::check(&doc, &trace, conf.spec_version).map_err(|rejection| io::Error::new(io::ErrorKind::InvalidData, rejection))?;
+    Ok(model)
+}
+
+/// Generate a document, and build a model of what it means while doing so.
+pub(crate) fn document_and_model<T: Write, R: Rng>(out: &mut T, rng: &mut R, conf: &GeneratorConfig) -> io::Result<model::Document> {
+    let weights = resolve_weights(conf)?;
+    let ctx = &mut Context::new(conf, &weights, out, rng);
+    ctx.model = Some(model::Builder::new(conf.spec_version));
+
+    generate(ctx)?;
+    ctx.flush()?;
+    Ok(ctx.model.take().unwrap().finish())
+}
+
+/// Write a document which means the same as the model but is written
+/// differently, and check with the parser that it does. The model has to be
+/// of a document generated with the same spec version.
+pub(crate) fn variant_document<T: Write, R: Rng>(out: &mut T, rng: &mut R, conf: &GeneratorConfig, model: &model::Document) -> io::Result<usize> {
+    // Only the junk is generated, so the limits on the size of a whole
+    // document don't apply, and tags would change what it means
+    let conf = GeneratorConfig { max_bytes: None, total_nodes: None, debug: false, ..conf.clone() };
+    let mut doc = Vec::new();
+    let weights = resolve_weights(&conf)?;
+    let ctx = &mut Context::new(&conf, &weights, &mut doc, rng);
+    variant::document(ctx, model)?;
+
+    let text = String::from_utf8_lossy(&doc);
+    let expected = model.to_kdl(conf.spec_version);
+    match parse::parse(&text, conf.spec_version) {
+        Ok(parsed) if parsed.to_model(&text, conf.spec_version).to_kdl(conf.spec_version) == expected => {}
+        Ok(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, "the variant means something other than the document")),
+        Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("the variant was rejected at {}", e))),
+    }
+    out.write_all(&doc)?;
+    out.flush()?;
+    Ok(doc.len())
+}
 
-        let result = concat(vec![
-            maybe(concat(vec![
-                write_literal("/-"),
-                repeat(node_space(), 0, ctx.conf.extra_space_max),
-            ])),
-            write_literal("{"),
-            nodes(),
-            write_literal("}"),
-        ])(ctx);
+/// Generate a document, then break it with a single grammar-aware corruption
+/// so that it is no longer valid KDL. Returns what was corrupted and where.
+pub(crate) fn invalid_document<T: Write, R: Rng>(out: &mut T, rng: &mut R, conf: &GeneratorConfig) -> io::Result<Corruption> {
+    let mut doc = Vec::new();
+    let weights = resolve_weights(conf)?;
+    let ctx = &mut Context::new(conf, &weights, &mut doc, rng);
+    ctx.sites = Some(Vec::new());
 
-        ctx.write_debug("</NODE-CHILDREN>");
+    generate(ctx)?;
+    let sites = ctx.sites.take().unwrap();
+    let corruption = corrupt::apply(&mut doc, &sites, conf.spec_version, rng);
 
-        result
-    })
+    out.write_all(&doc)?;
+    out.flush()?;
+    Ok(corruption)
 }
 
-// node-space := ws* escline ws* | ws+
-fn node_space<T: Write + 'static, R: Rng + 'static>() -> Box<GenFn<T, R>> {
-    Box::new(|ctx| {
-        ctx.write_debug("<NODE-SPACE>");
-
-        let result = select(vec![
-            concat(vec![
-                repeat(ws(), 0, ctx.conf.extra_space_max),
-                escline(),
-                repeat(ws(), 0, ctx.conf.extra_space_max),
-            ]),
-            repeat(ws(), 1, ctx.conf.extra_space_max),
-        ])(ctx);
+fn generate<T: Write, R: Rng>(ctx: &mut Context<T, R>) -> io::Result<usize> {
+    engine::grammar(ctx.conf.spec_version).generate(ctx)
+}
 
-        ctx.write_debug("</NODE-SPACE>");
+fn resolve_weights(conf: &GeneratorConfig) -> io::Result<ChoiceWeights> {
+    engine::grammar(conf.spec_version)
+        .resolve(&conf.weights)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
 
-        result
-    })
+pub(crate) fn coverage(conf: &GeneratorConfig) -> Coverage {
+    let grammar = engine::grammar(conf.spec_version);
+    grammar.coverage(conf, &grammar.resolve(&conf.weights).unwrap_or_default())
 }
 
-// node-terminator := single-line-comment | newline | ';' | eof
-fn node_terminator<T: Write + 'static, R: Rng + 'static>() -> Box<GenFn<T, R>> {
-    Box::new(|ctx| {
-        ctx.write_debug("<NODE-TERMINATOR>");
+/// A config file giving every choice in the grammar its default weight.
+pub(crate) fn default_weights(spec_version: u32) -> String {
+    engine::grammar(spec_version).default_weights()
+}
 
-        let result = select(vec![
-            single_line_comment(),
-            newline(),
-            write_literal(";"),
-        ])(ctx);
+// nodes := linespace* (node nodes?)? linespace*
+fn nodes() -> Rule {
+    nested(tagged("nodes", || concat(vec![
+        repeat(linespace(), |_| 0, |c| c.blank_lines_max),
+        budgeted(repeat(node(), |c| c.nodes_per_child_min, |c| c.nodes_per_child_max)),
+        repeat(linespace(), |_| 0, |c| c.blank_lines_max),
+    ])))
+}
 
-        ctx.write_debug("</NODE-TERMINATOR>");
+// node := ('/-' node-space*)? type? identifier (node-space+ node-prop-or-arg)* (node-space* node-children ws*)? node-space* node-terminator
+fn node() -> Rule {
+    tagged("node", || scope(Scope::Node, concat(vec![
+        maybe(concat(vec![
+            write_slashdash(),
+            repeat(node_space(), |_| 0, |c| c.extra_space_max),
+        ])),
+        maybe(type_rule()),
+        capture(Capture::Name, identifier()),
+        repeat(concat(vec![
+            repeat(node_space(), |_| 1, |c| c.extra_space_max),
+            node_prop_or_arg(),
+        ]), |_| 0, |c| c.props_or_args_max),
+        budgeted(maybe(concat(vec![
+            repeat(node_space(), |_| 0, |c| c.extra_space_max),
+            node_children(),
+            repeat(ws(), |_| 0, |c| c.extra_space_max),
+        ]))),
+        repeat(node_space(), |_| 0, |c| c.extra_space_max),
+        node_terminator(),
+    ])))
+}
 
-        result
-    })
+// node-prop-or-arg := ('/-' node-space*)? (prop | value)
+fn node_prop_or_arg() -> Rule {
+    tagged("node-prop-or-arg", || scope(Scope::Entry, concat(vec![
+        maybe(concat(vec![
+            write_slashdash(),
+            repeat(node_space(), |_| 0, |c| c.extra_space_max),
+        ])),
+        select(vec![prop(), value()]),
+    ])))
 }
 
-// identifier := string | bare-identifier
-fn identifier<T: Write + 'static, R: Rng + 'static>() -> Box<GenFn<T, R>> {
-    Box::new(|ctx| {
-        ctx.write_debug("<IDENTIFIER>");
+// node-children := ('/-' node-space*)? {' nodes '}'
+fn node_children() -> Rule {
+    tagged("node-children", || scope(Scope::Children, concat(vec![
+        maybe(concat(vec![
+            write_slashdash(),
+            repeat(node_space(), |_| 0, |c| c.extra_space_max),
+        ])),
+        write_literal("{"),
+        nodes(),
+        site(Category::UnclosedChildren, write_literal("}")),
+    ])))
+}
 
-        let result = select(vec![string_rule(), bare_identifier()])(ctx);
+// node-space := ws* escline ws* | ws+
+fn node_space() -> Rule {
+    tagged("node-space", || select(vec![
+        concat(vec![
+            repeat(ws(), |_| 0, |c| c.extra_space_max),
+            escline(),
+            repeat(ws(), |_| 0, |c| c.extra_space_max),
+        ]),
+        repeat(ws(), |_| 1, |c| c.extra_space_max),
+    ]))
+}
 
-        ctx.write_debug("</IDENTIFIER>");
+// node-terminator := single-line-comment | newline | ';' | eof
+fn node_terminator() -> Rule {
+    tagged("node-terminator", || select(vec![
+        single_line_comment(),
+        newline(),
+        write_literal(";"),
+    ]))
+}
 
-        result
-    })
+// identifier := string | bare-identifier
+fn identifier() -> Rule {
+    tagged("identifier", || select(vec![string_rule(), bare_identifier()]))
 }
 
 // bare-identifier := ((identifier-char - digit - sign) identifier-char*| sign ((identifier-char - digit) identifier-char*)?) - keyword
-fn bare_identifier<T: Write + 'static, R: Rng + 'static>() -> Box<GenFn<T, R>> {
-    Box::new(|ctx| {
-        ctx.write_debug("<BARE-IDENTIFIER>");
-
-        let result = select(vec![
-            concat(vec![
-                identifier_char_minus_digit_and_sign(),
-                repeat(identifier_char(), 0, ctx.conf.identifier_len_max - 1),
-            ]),
-            concat(vec![
-                sign(),
-                maybe(concat(vec![
-                    identifier_char_minus_digit(),
-                    repeat(identifier_char(), 0, ctx.conf.identifier_len_max - 1),
-                ])),
-            ]),
-        ])(ctx);
-
-        ctx.write_debug("</BARE-IDENTIFIER>");
-
-        result
-    })
+fn bare_identifier() -> Rule {
+    tagged("bare-identifier", || site(Category::KeywordIdentifier, not_keyword(KEYWORDS, near_keywords(select(vec![
+        concat(vec![
+            identifier_char_minus_digit_and_sign(),
+            repeat(identifier_char(), |_| 0, |c| c.identifier_len_max - 1),
+        ]),
+        concat(vec![
+            sign(),
+            maybe(concat(vec![
+                identifier_char_minus_digit(),
+                repeat(identifier_char(), |_| 0, |c| c.identifier_len_max - 1),
+            ])),
+        ]),
+    ]), near_keyword()))))
+}
+
+// The words a bare identifier can't be
+const KEYWORDS: &[&str] = &["true", "false", "null"];
+
+// near-keyword := keyword-name identifier-char+ | (identifier-char - digit - sign) identifier-char* keyword-name
+// keyword-name := 'true' | 'false' | 'null'
+fn near_keyword() -> Rule {
+    production("near-keyword", || select(vec![
+        concat(vec![
+            select(KEYWORDS.iter().map(|keyword| write_literal(keyword)).collect()),
+            repeat(identifier_char(), |_| 1, |c| c.identifier_len_max),
+        ]),
+        concat(vec![
+            identifier_char_minus_digit_and_sign(),
+            repeat(identifier_char(), |_| 0, |c| c.identifier_len_max - 1),
+            select(KEYWORDS.iter().map(|keyword| write_literal(keyword)).collect()),
+        ]),
+    ]))
 }
 
 // identifier-char := unicode - linespace - [\/(){}<>;[]=,"]
-//Hax: To avoid generating one of the keywords (true|false|null), we don't use 'u' or 'l'
-fn identifier_char<T: Write + 'static, R: Rng + 'static>()
-    -> Box<GenFn<T, R>> {
+fn identifier_char() -> Rule {
     pick_ascii_or_utf8(
-        write_rand_re("[-+0-9A-Za-km-tv-z]", 1),
-        write_rand_re("[^ul\\\\/\\(\\){}<>;\\[\\]=,\"\
+        write_rand_re("[-+0-9A-Za-z]", |_| 1),
+        write_rand_re("[^\\\\/\\(\\){}<>;\\[\\]=,\"\
                           \u{000D}\u{000A}\u{000C}\u{0085}\u{2028}\
                           \u{2029}\u{0009}\u{0020}\u{00A0}\u{1680}\
                           \u{2000}\u{2001}\u{2002}\u{2003}\u{2004}\
                           \u{2005}\u{2006}\u{2007}\u{2008}\u{2009}\
-                          \u{200A}\u{202F}\u{205F}\u{3000}\u{FEFF}]", 1),
+                          \u{200A}\u{202F}\u{205F}\u{3000}\u{FEFF}]", |_| 1),
     )
 }
 
-fn identifier_char_minus_digit<T: Write + 'static, R: Rng + 'static>()
-    -> Box<GenFn<T, R>> {
+fn identifier_char_minus_digit() -> Rule {
     pick_ascii_or_utf8(
-        write_rand_re("[-+A-Za-km-tv-z]", 1),
-        write_rand_re("[^ul0-9\\\\/\\(\\){}<>;\\[\\]=,\"\
+        write_rand_re("[-+A-Za-z]", |_| 1),
+        write_rand_re("[^0-9\\\\/\\(\\){}<>;\\[\\]=,\"\
                           \u{000D}\u{000A}\u{000C}\u{0085}\u{2028}\
                           \u{2029}\u{0009}\u{0020}\u{00A0}\u{1680}\
                           \u{2000}\u{2001}\u{2002}\u{2003}\u{2004}\
                           \u{2005}\u{2006}\u{2007}\u{2008}\u{2009}\
-                          \u{200A}\u{202F}\u{205F}\u{3000}\u{FEFF}]", 1),
+                          \u{200A}\u{202F}\u{205F}\u{3000}\u{FEFF}]", |_| 1),
     )
 }
 
-fn identifier_char_minus_digit_and_sign<T: Write + 'static, R: Rng + 'static>()
-    -> Box<GenFn<T, R>> {
+fn identifier_char_minus_digit_and_sign() -> Rule {
     pick_ascii_or_utf8(
-        write_rand_re("[A-Za-km-tv-z]", 1),
-        write_rand_re("[^-+ul0-9\\\\/\\(\\){}<>;\\[\\]=,\"\
+        write_rand_re("[A-Za-z]", |_| 1),
+        write_rand_re("[^-+0-9\\\\/\\(\\){}<>;\\[\\]=,\"\
                           \u{000D}\u{000A}\u{000C}\u{0085}\u{2028}\
                           \u{2029}\u{0009}\u{0020}\u{00A0}\u{1680}\
                           \u{2000}\u{2001}\u{2002}\u{2003}\u{2004}\
                           \u{2005}\u{2006}\u{2007}\u{2008}\u{2009}\
-                          \u{200A}\u{202F}\u{205F}\u{3000}\u{FEFF}]", 1),
+                          \u{200A}\u{202F}\u{205F}\u{3000}\u{FEFF}]", |_| 1),
     )
 }
 
 // keyword := boolean | 'null'
-fn keyword<T: Write + 'static, R: Rng + 'static>() -> Box<GenFn<T, R>> {
-    Box::new(|ctx| {
-        ctx.write_debug("<KEYWORD>");
-
-        let result = select(vec![
-            write_literal("true"),
-            write_literal("false"),
-            write_literal("null"),
-        ])(ctx);
-
-        ctx.write_debug("</KEYWORD>");
-
-        result
-    })
+fn keyword() -> Rule {
+    tagged("keyword", || select(vec![
+        write_literal("true"),
+        write_literal("false"),
+        write_literal("null"),
+    ]))
 }
 
 // prop := identifier '=' value
-fn prop<T: Write + 'static, R: Rng + 'static>() -> Box<GenFn<T, R>> {
-    Box::new(|ctx| {
-        ctx.write_debug("<PROP>");
-
-        let result = concat(vec![
-            identifier(),
-            write_literal("="),
-            value(),
-        ])(ctx);
-
-        ctx.write_debug("</PROP>");
-
-        result
-    })
+fn prop() -> Rule {
+    tagged("prop", || concat(vec![
+        capture(Capture::Key, identifier()),
+        write_literal("="),
+        value(),
+    ]))
 }
 
 // value := type? (string | number | keyword)
-fn value<T: Write + 'static, R: Rng + 'static>() -> Box<GenFn<T, R>> {
-    Box::new(|ctx| {
-        ctx.write_debug("<VALUE>");
-
-        let result = concat(vec![
-            maybe(type_rule()),
-            select(vec![string_rule(), number(), keyword()]),
-        ])(ctx);
-
-        ctx.write_debug("</VALUE>");
-
-        result
-    })
+fn value() -> Rule {
+    tagged("value", || concat(vec![
+        maybe(type_rule()),
+        capture(Capture::Value, select(vec![string_rule(), number(), keyword()])),
+    ]))
 }
 
 // type := '(' identifier ')'
-fn type_rule<T: Write + 'static, R: Rng + 'static>() -> Box<GenFn<T, R>> {
-    Box::new(|ctx| {
-        ctx.write_debug("<TYPE>");
-
-        let result = concat(vec![
-            write_literal("("),
-            identifier(),
-            write_literal(")"),
-        ])(ctx);
-
-        ctx.write_debug("</TYPE>");
-
-        result
-    })
+fn type_rule() -> Rule {
+    tagged("type", || concat(vec![
+        write_literal("("),
+        capture(Capture::Type, identifier()),
+        write_literal(")"),
+    ]))
 }
 
 // string := raw-string | escaped-string
-fn string_rule<T: Write + 'static, R: Rng + 'static>() -> Box<GenFn<T, R>> {
-    Box::new(|ctx| {
-        ctx.write_debug("<STRING>");
-
-        let result = select(vec![raw_string(), escaped_string()])(ctx);
-
-        ctx.write_debug("</STRING>");
-
-        result
-    })
+fn string_rule() -> Rule {
+    tagged("string", || select(vec![raw_string(), escaped_string()]))
 }
 
 // escaped-string := '"' character* '"'
-fn escaped_string<T: Write + 'static, R: Rng + 'static>() -> Box<GenFn<T, R>> {
-    Box::new(|ctx| {
-        concat(vec![
-            write_literal("\""),
-            repeat(character(), 0, ctx.conf.string_len_max),
-            write_literal("\""),
-        ])(ctx)
-    })
+fn escaped_string() -> Rule {
+    production("escaped-string", || concat(vec![
+        write_literal("\""),
+        repeat(character(), |_| 0, |c| c.string_len_max),
+        write_literal("\""),
+    ]))
 }
 
 // character := '\' escape | [^\"]
-fn character<T: Write + 'static, R: Rng + 'static>() -> Box<GenFn<T, R>> {
-    Box::new(|ctx| {
-        select(vec![
-            concat(vec![
-                write_literal("\\"),
-                escape(),
-            ]),
-            pick_ascii_or_utf8(
-                write_rand_re("[a-zA-Z0-9 .,;!@\\#\\$%\\^&*()]", 1),
-                write_rand_re("[^\\\\\"]", 1),
-            ),
-        ])(ctx)
-    })
+fn character() -> Rule {
+    production("character", || select(vec![
+        concat(vec![
+            write_literal("\\"),
+            escape(),
+        ]),
+        pick_ascii_or_utf8(
+            write_rand_re("[a-zA-Z0-9 .,;!@\\#\\$%\\^&*()]", |_| 1),
+            write_rand_re("[^\\\\\"]", |_| 1),
+        ),
+    ]))
 }
 
 // escape := ["\\/bfnrt] | 'u{' hex-digit{1, 6} '}'
-fn escape<T: Write + 'static, R: Rng + 'static>() -> Box<GenFn<T, R>> {
-    Box::new(|ctx| {
-        select(vec![
-            write_rand_re("[\"\\\\/bfnrt]", 1),
-            concat(vec![
-                write_literal("u{"),
-                write_rand_unicode_hex(),
-                write_literal("}"),
-            ]),
-        ])(ctx)
-    })
+fn escape() -> Rule {
+    production("escape", || select(vec![
+        write_rand_re("[\"\\\\/bfnrt]", |_| 1),
+        concat(vec![
+            write_literal("u{"),
+            write_rand_unicode_hex(),
+            write_literal("}"),
+        ]),
+    ]))
 }
 
 // raw-string := 'r' raw-string-hash
-fn raw_string<T: Write + 'static, R: Rng + 'static>() -> Box<GenFn<T, R>> {
-    Box::new(|ctx| {
-        concat(vec![
-            write_literal("r"),
-            raw_string_hash(),
-        ])(ctx)
-    })
+fn raw_string() -> Rule {
+    production("raw-string", || concat(vec![
+        write_literal("r"),
+        raw_string_hash(),
+    ]))
 }
 
 // raw-string-hash := '#' raw-string-hash '#' | raw-string-quotes
-fn raw_string_hash<T: Write + 'static, R: Rng + 'static>() -> Box<GenFn<T, R>> {
-    Box::new(|ctx| {
-        select(vec![
-            concat(vec![
-                write_literal("#"),
-                raw_string_hash(),
-                write_literal("#"),
-            ]),
-            raw_string_quotes(),
-        ])(ctx)
-    })
+fn raw_string_hash() -> Rule {
+    production("raw-string-hash", || with_hashes(0, raw_string_quotes()))
 }
 
 // raw-string-quotes := '"' .* '"'
-fn raw_string_quotes<T: Write + 'static, R: Rng + 'static>() -> Box<GenFn<T, R>> {
-    Box::new(|ctx| {
-        concat(vec![
-            write_literal("\""),
-            pick_ascii_or_utf8(
-                write_rand_re("\\w*", ctx.conf.string_len_max),
-                write_rand_re(".*", ctx.conf.string_len_max)),
-            write_literal("\""),
-        ])(ctx)
-    })
+fn raw_string_quotes() -> Rule {
+    production("raw-string-quotes", || concat(vec![
+        write_literal("\""),
+        site(Category::RawStringTerminator, near_misses(pick_ascii_or_utf8(
+            write_rand_re("\\w*", |c| c.string_len_max),
+            write_rand_re(".*", |c| c.string_len_max)))),
+        write_literal("\""),
+    ]))
 }
 
 // number := decimal | hex | octal | binary
-fn number<T: Write + 'static, R: Rng + 'static>() -> Box<GenFn<T, R>> {
-    Box::new(|ctx| {
-        ctx.write_debug("<NUMBER>");
-
-        let result = select(vec![decimal(), hex(), octal(), binary()])(ctx);
-
-        ctx.write_debug("</NUMBER>");
-
-        result
-    })
+fn number() -> Rule {
+    tagged("number", || select(vec![decimal(), hex(), octal(), binary()]))
 }
 
 // decimal := sign? integer ('.' integer)? exponent?
-fn decimal<T: Write + 'static, R: Rng + 'static>() -> Box<GenFn<T, R>> {
-    Box::new(|ctx| {
-        concat(vec![
-            maybe(sign()),
+fn decimal() -> Rule {
+    production("decimal", || concat(vec![
+        maybe(sign()),
+        integer(),
+        maybe(concat(vec![
+            write_literal("."),
             integer(),
-            maybe(concat(vec![
-                write_literal("."),
-                integer(),
-            ])),
-            maybe(exponent()),
-        ])(ctx)
-    })
+        ])),
+        maybe(exponent()),
+    ]))
 }
 
 // exponent := ('e' | 'E') sign? integer
-fn exponent<T: Write + 'static, R: Rng + 'static>() -> Box<GenFn<T, R>> {
-    Box::new(|ctx| {
-        concat(vec![
-            select(vec![
-                write_literal("e"),
-                write_literal("E"),
-            ]),
-            maybe(sign()),
-            integer(),
-        ])(ctx)
-    })
+fn exponent() -> Rule {
+    production("exponent", || concat(vec![
+        select(vec![
+            write_literal("e"),
+            write_literal("E"),
+        ]),
+        maybe(sign()),
+        integer(),
+    ]))
 }
 
 // integer := digit (digit | '_')*
-fn integer<T: Write + 'static, R: Rng + 'static>() -> Box<GenFn<T, R>> {
-    Box::new(|ctx| write_rand_re("[0-9][0-9_]*", ctx.conf.num_len_max)(ctx))
+fn integer() -> Rule {
+    write_rand_re("[0-9][0-9_]*", |c| c.num_len_max)
 }
 
 // sign := '+' | '-'
-fn sign<T: Write + 'static, R: Rng + 'static>() -> Box<GenFn<T, R>> {
-    Box::new(|ctx| {
-        select(vec![
-            write_literal("+"),
-            write_literal("-"),
-        ])(ctx)
-    })
+fn sign() -> Rule {
+    production("sign", || select(vec![
+        write_literal("+"),
+        write_literal("-"),
+    ]))
 }
 
 // hex := sign? '0x' hex-digit (hex-digit | '_')*
-fn hex<T: Write + 'static, R: Rng + 'static>() -> Box<GenFn<T, R>> {
-    Box::new(|ctx| {
-        concat(vec![
-            maybe(sign()),
-            write_literal("0x"),
-            write_rand_re("[0-9A-Fa-f][0-9A-Fa-f_]*", ctx.conf.num_len_max),
-        ])(ctx)
-    })
+fn hex() -> Rule {
+    production("hex", || concat(vec![
+        maybe(sign()),
+        write_literal("0x"),
+        site(Category::HexLeadingUnderscore,
+             write_rand_re("[0-9A-Fa-f][0-9A-Fa-f_]*", |c| c.num_len_max)),
+    ]))
 }
 
 // octal := sign? '0o' [0-7] [0-7_]*
-fn octal<T: Write + 'static, R: Rng + 'static>() -> Box<GenFn<T, R>> {
-    Box::new(|ctx| {
-        concat(vec![
-            maybe(sign()),
-            write_literal("0o"),
-            write_rand_re("[0-7][0-7_]*", ctx.conf.num_len_max),
-        ])(ctx)
-    })
+fn octal() -> Rule {
+    production("octal", || concat(vec![
+        maybe(sign()),
+        write_literal("0o"),
+        write_rand_re("[0-7][0-7_]*", |c| c.num_len_max),
+    ]))
 }
 
 // binary := sign? '0b' ('0' | '1') ('0' | '1' | '_')*
-fn binary<T: Write + 'static, R: Rng + 'static>() -> Box<GenFn<T, R>> {
-    Box::new(|ctx| {
-        concat(vec![
-            maybe(sign()),
-            write_literal("0b"),
-            write_rand_re("[01][01_]*", ctx.conf.num_len_max),
-        ])(ctx)
-    })
+fn binary() -> Rule {
+    production("binary", || concat(vec![
+        maybe(sign()),
+        write_literal("0b"),
+        write_rand_re("[01][01_]*", |c| c.num_len_max),
+    ]))
 }
 
 // escline := '\\' ws* (single-line-comment | newline)
-fn escline<T: Write + 'static, R: Rng + 'static>() -> Box<GenFn<T, R>> {
-    Box::new(|ctx| {
-        ctx.write_debug("<ESCLINE>");
-
-        let result = concat(vec![
-            write_literal("\\"),
-            repeat(ws(), 0, ctx.conf.extra_space_max),
-            select(vec![single_line_comment(), newline()]),
-        ])(ctx);
-
-        ctx.write_debug("</ESCLINE>");
-
-        result
-    })
+fn escline() -> Rule {
+    tagged("escline", || concat(vec![
+        write_literal("\\"),
+        repeat(ws(), |_| 0, |c| c.extra_space_max),
+        select(vec![single_line_comment(), newline()]),
+    ]))
 }
 
 // linespace := newline | ws | single-line-comment
-fn linespace<T: Write + 'static, R: Rng + 'static>() -> Box<GenFn<T, R>> {
-    Box::new(|ctx| {
-        ctx.write_debug("<LINESPACE>");
-
-        let result = select(vec![newline(), ws(), single_line_comment()])(ctx);
-
-        ctx.write_debug("</LINESPACE>");
-
-        result
-    })
+fn linespace() -> Rule {
+    tagged("linespace", || select(vec![newline(), ws(), single_line_comment()]))
 }
 
+const NEWLINES: [&str; 7] = [
+    "\u{000D}", "\u{000A}", "\u{000D}\u{000A}", "\u{0085}", "\u{000C}", "\u{2028}", "\u{2029}",
+];
+
 // newline := See Table (All line-break white_space)
-fn newline<T: Write + 'static, R: Rng + 'static>()
-    -> Box<GenFn<T, R>> {
+fn newline() -> Rule {
     pick_ascii_or_utf8(
-        select(vec![
-            write_literal("\u{000D}"),
-            write_literal("\u{000A}"),
-            write_literal("\u{000D}\u{000A}"),
-        ]),
-        select(vec![
-            write_literal("\u{000D}"),
-            write_literal("\u{000A}"),
-            write_literal("\u{000D}\u{000A}"),
-            write_literal("\u{0085}"),
-            write_literal("\u{000C}"),
-            write_literal("\u{2028}"),
-            write_literal("\u{2029}"),
-        ]),
+        write_newline(&NEWLINES[..3]),
+        write_newline(&NEWLINES),
     )
 }
 
-// ws := bom | unicode-space | multi-line-comment
-fn ws<T: Write + 'static, R: Rng + 'static>() -> Box<GenFn<T, R>> {
-    Box::new(|ctx| {
-        ctx.write_debug("<WS>");
-
-        let result = select(vec![bom(), unicode_space(), multi_line_comment()])(ctx);
-
-        ctx.write_debug("</WS>");
+// Straight after a lone CR an LF would be read as part of the same CRLF, which
+// can swallow a node terminator, so LF is never picked there.
+fn write_newline(options: &'static [&'static str]) -> Rule {
+    Rule::Newline(options)
+}
 
-        result
-    })
+// ws := bom | unicode-space | multi-line-comment
+fn ws() -> Rule {
+    tagged("ws", || select(vec![bom(), unicode_space(), multi_line_comment()]))
 }
 
 // bom := '\u{FEFF}'
-fn bom<T: Write + 'static, R: Rng + 'static>() -> Box<GenFn<T, R>> {
-    Box::new(|ctx| write_literal("\u{FEFF}")(ctx))
+fn bom() -> Rule {
+    write_literal("\u{FEFF}")
 }
 
+const UNICODE_SPACES: [&str; 18] = [
+    "\u{0009}", "\u{0020}", "\u{00A0}", "\u{1680}", "\u{2000}", "\u{2001}",
+    "\u{2002}", "\u{2003}", "\u{2004}", "\u{2005}", "\u{2006}", "\u{2007}",
+    "\u{2008}", "\u{2009}", "\u{200A}", "\u{202F}", "\u{205F}", "\u{3000}",
+];
+
 // unicode-space := See Table (All White_Space unicode characters which are not `newline`)
-fn unicode_space<T: Write + 'static, R: Rng + 'static>() -> Box<GenFn<T, R>> {
-    Box::new(|ctx| {
-        select(vec![
-            write_literal("\u{0009}"),
-            write_literal("\u{0020}"),
-            write_literal("\u{00A0}"),
-            write_literal("\u{1680}"),
-            write_literal("\u{2000}"),
-            write_literal("\u{2001}"),
-            write_literal("\u{2002}"),
-            write_literal("\u{2003}"),
-            write_literal("\u{2004}"),
-            write_literal("\u{2005}"),
-            write_literal("\u{2006}"),
-            write_literal("\u{2007}"),
-            write_literal("\u{2008}"),
-            write_literal("\u{2009}"),
-            write_literal("\u{200A}"),
-            write_literal("\u{202F}"),
-            write_literal("\u{205F}"),
-            write_literal("\u{3000}"),
-        ])(ctx)
-    })
+fn unicode_space() -> Rule {
+    production("unicode-space", || select(UNICODE_SPACES.iter().map(|space| write_literal(space)).collect()))
+}
+
+// A run of unicode-space, used where the same indentation has to be written more than once
+fn rand_indent<T: Write, R: Rng>(ctx: &mut Context<T, R>) -> String {
+    let len = ctx.range(0..=ctx.conf.extra_space_max);
+    (0..len).map(|_| UNICODE_SPACES[ctx.choose_index(UNICODE_SPACES.len())]).collect()
 }
 
 // single-line-comment := '//' ^newline+ (newline | eof)
-fn single_line_comment<T: Write + 'static, R: Rng + 'static>() -> Box<GenFn<T, R>> {
-    Box::new(|ctx| {
-        ctx.write_debug("<SINGLE-LINE-COMMENT>");
+fn single_line_comment() -> Rule {
+    tagged("single-line-comment", || concat(vec![
+        write_literal("//"),
+        pick_ascii_or_utf8(
+            write_rand_re("\\w+", |c| c.comment_len_max),
+            write_rand_re("[^\u{000D}\u{000A}\u{000C}\u{0085}\u{2028}\u{2029}]+", |c| c.comment_len_max),
+        ),
+        newline(),
+    ]))
+}
+
+// multi-line-comment := '/*' commented-block
+fn multi_line_comment() -> Rule {
+    tagged("multi-line-comment", || concat(vec![
+        write_literal("/*"),
+        commented_block(),
+    ]))
+}
 
-        let result = concat(vec![
-            write_literal("//"),
+// commented-block := '*/' | (multi-line-comment | '*' | '/' | [^*/]+) commented-block
+fn commented_block() -> Rule {
+    production("commented-block", || select(vec![
+        write_literal("*/"),
+        concat(vec![
             pick_ascii_or_utf8(
-                write_rand_re("\\w+", ctx.conf.comment_len_max),
-                write_rand_re("[^\u{000D}\u{000A}\u{000C}\u{0085}\u{2028}\u{2029}]+", ctx.conf.comment_len_max),
+                select(vec![
+                    write_rand_re("\\*\\w", |_| 1),
+                    write_rand_re("/\\w", |_| 1),
+                    write_rand_re("\\w+", |c| c.comment_len_max),
+                    nested_comment(multi_line_comment()),
+                ]),
+                select(vec![
+                    write_rand_re("\\*[^*/]", |_| 1),
+                    write_rand_re("/[^*/]", |_| 1),
+                    write_rand_re("[^*/]+", |c| c.comment_len_max),
+                    nested_comment(multi_line_comment()),
+                ]),
             ),
-            newline(),
-        ])(ctx);
+            commented_block(),
+        ]),
+    ]))
+}
 
-        ctx.write_debug("</SINGLE-LINE-COMMENT>");
+fn write_literal(s: &'static str) -> Rule {
+    Rule::Literal(s)
+}
 
-        result
-    })
+fn write_slashdash() -> Rule {
+    Rule::Slashdash
 }
 
-// multi-line-comment := '/*' commented-block
-fn multi_line_comment<T: Write + 'static, R: Rng + 'static>() -> Box<GenFn<T, R>> {
-    Box::new(|ctx| {
-        ctx.write_debug("<MULTI-LINE-COMMENT>");
+// Picks an indentation for everything func writes, which write_indent repeats
+fn with_indent(func: Rule) -> Rule {
+    Rule::Indented(Box::new(func))
+}
 
-        let result = concat(vec![
-            write_literal("/*"),
-            commented_block(),
-        ])(ctx);
+fn write_indent() -> Rule {
+    Rule::Indent
+}
 
-        ctx.write_debug("</MULTI-LINE-COMMENT>");
+// Writes func between enough '#'s that nothing in it looks like the end of a
+// raw string, and at least min of them
+fn with_hashes(min: u32, func: Rule) -> Rule {
+    Rule::Hashed(Box::new(func), min)
+}
 
-        result
-    })
+// With --raw-string-near-misses, breaks up a raw string body with '"'s
+// followed by '#'s, one short of the terminator at most
+fn near_misses(body: Rule) -> Rule {
+    Rule::NearMisses(Box::new(body))
 }
 
-// commented-block := '*/' | (multi-line-comment | '*' | '/' | [^*/]+) commented-block
-fn commented_block<T: Write + 'static, R: Rng + 'static>() -> Box<GenFn<T, R>> {
-    return Box::new(|ctx| {
-        select(vec![
-            write_literal("*/"),
-            concat(vec![
-                pick_ascii_or_utf8(
-                    select(vec![
-                        write_rand_re("\\*\\w", 1),
-                        write_rand_re("/\\w", 1),
-                        write_rand_re("\\w+", ctx.conf.comment_len_max),
-                        multi_line_comment(),
-                    ]),
-                    select(vec![
-                        write_rand_re("\\*[^/]", 1),
-                        write_rand_re("/[^*]", 1),
-                        write_rand_re("[^*/]+", ctx.conf.comment_len_max),
-                        multi_line_comment(),
-                    ]),
-                ),
-                commented_block(),
-            ]),
-        ])(ctx)
-    });
-}
-
-fn write_literal<T: Write + 'static, R: Rng + 'static>(s: &'static str) -> Box<GenFn<T, R>> {
-    Box::new(move |c| c.write(s.as_bytes()))
-}
-
-fn write_rand_unicode_hex<T: Write + 'static, R: Rng + 'static>() -> Box<GenFn<T, R>> {
-    Box::new(|c| {
-        let s = format!("{:#x}", c.gen_range(1..=0x10FFFF));
-        c.write(s[2..].as_bytes()) //Need to slice off the '0x'
-    })
-}
-
-fn pick_ascii_or_utf8<T: Write + 'static, R: Rng + 'static>(
-    ascii: Box<GenFn<T, R>>,
-    unicode: Box<GenFn<T, R>>,
-) -> Box<GenFn<T, R>> {
-    Box::new(move |ctx| {
-        return if ctx.conf.ascii_only {
-            ascii(ctx)
-        } else {
-            unicode(ctx)
-        };
-    })
+fn write_rand_unicode_hex() -> Rule {
+    site(Category::InvalidUnicodeEscape, Rule::UnicodeHex)
 }
 
-fn write_rand_re<T: Write + 'static, R: Rng + 'static>(
-    pattern: &'static str, rep: u32,
-) -> Box<GenFn<T, R>> {
-    Box::new(move |c| {
-        let s = rand_re(c, pattern, rep);
-        c.write(s.as_bytes())
-    })
+// Records the span written by func as a place where a corruption could be applied
+fn site(category: Category, func: Rule) -> Rule {
+    Rule::Site(category, Box::new(func))
 }
 
-fn rand_re<T: Write, R: Rng>(ctx: &mut Context<T, R>, pattern: &str, rep: u32) -> String {
-    let hir = ctx.get_regex_parser().parse(pattern).unwrap();
-    let re = rand_regex::Regex::with_hir(hir, rep).unwrap();
-    ctx.sample(re)
+// Everything func writes belongs to a new scope of the model
+fn scope(scope: Scope, func: Rule) -> Rule {
+    Rule::Scope(scope, Box::new(func))
 }
 
-fn maybe<T: Write + 'static, R: Rng + 'static>(
-    func: Box<GenFn<T, R>>,
-) -> Box<GenFn<T, R>> {
-    Box::new(move |c| {
-        let rnd: f32 = c.gen();
-        return if rnd > 0.5 {
-            func(c)
-        } else {
-            Ok(0)
-        };
-    })
-}
-
-fn repeat<T: Write + 'static, R: Rng + 'static>(
-    func: Box<GenFn<T, R>>,
-    min_times: u32, max_times: u32,
-) -> Box<GenFn<T, R>> {
-    Box::new(move |c| {
-        let times = c.gen_range(min_times..=max_times);
-        let mut size = 0;
-        for _ in 0..times {
-            match func(c) {
-                Err(e) => return Err(e),
-                Ok(s) => size += s
-            }
-        }
+// Hands the text written by func to the model
+fn capture(capture: Capture, func: Rule) -> Rule {
+    Rule::Capture(capture, Box::new(func))
+}
 
-        Ok(size)
-    })
+// A production of the grammar, which is built once however often it is used
+fn production(name: &'static str, body: fn() -> Rule) -> Rule {
+    Rule::Production(Production { name, tagged: false, body })
 }
 
-fn select<T: Write + 'static, R: Rng + 'static>(
-    options: Vec<Box<GenFn<T, R>>>,
-) -> Box<GenFn<T, R>> {
-    Box::new(move |c| {
-        options.choose(c.rng).unwrap()(c)
-    })
+// A production which --debug writes tags around
+fn tagged(name: &'static str, body: fn() -> Rule) -> Rule {
+    Rule::Production(Production { name, tagged: true, body })
 }
 
-fn concat<T: Write + 'static, R: Rng + 'static>(
-    calls: Vec<Box<GenFn<T, R>>>,
-) -> Box<GenFn<T, R>> {
-    Box::new(move |c| {
-        let mut size = 0;
-        for call in calls.iter() {
-            match call(c) {
-                Err(e) => return Err(e),
-                Ok(s) => size += s,
-            }
-        }
+// Goes one level of nodes deeper, or writes nothing past depth_max
+fn nested(func: Rule) -> Rule {
+    Rule::Nested(Box::new(func))
+}
+
+// With --total-nodes, the budget decides how many times a repeat of nodes
+// writes one, and whether a maybe of a node's children is taken
+fn budgeted(func: Rule) -> Rule {
+    Rule::Budgeted(Box::new(func))
+}
+
+// Writes func again for as long as what it wrote is exactly one of the
+// keywords
+fn not_keyword(keywords: &'static [&'static str], func: Rule) -> Rule {
+    Rule::NotKeyword(Box::new(func), keywords)
+}
+
+// func, or with near_keywords, half the time near instead
+fn near_keywords(func: Rule, near: Rule) -> Rule {
+    Rule::NearKeywords(Box::new(func), Box::new(near))
+}
+
+// Goes one level of multi-line comments deeper, or writes nothing past
+// comment_nesting_max
+fn nested_comment(func: Rule) -> Rule {
+    Rule::NestedComment(Box::new(func))
+}
+
+fn pick_ascii_or_utf8(ascii: Rule, unicode: Rule) -> Rule {
+    Rule::AsciiOrUtf8(Box::new(ascii), Box::new(unicode))
+}
+
+fn write_rand_re(pattern: &'static str, rep: Limit) -> Rule {
+    Rule::Regex(pattern, rep)
+}
+
+// A regex compiled to sample strings from, and to check replayed ones with.
+// The matcher is only compiled once there is a sample to check.
+struct Sampler {
+    pattern: &'static str,
+    sampler: rand_regex::Regex,
+    matcher: OnceCell<regex::Regex>,
+}
+
+impl Sampler {
+    fn is_match(&self, s: &str) -> bool {
+        self.matcher.get_or_init(|| regex::Regex::new(&format!("^(?:{})$", self.pattern)).unwrap()).is_match(s)
+    }
+}
+
+// Compiled samplers, by pattern, rep and ascii_only
+type RegexCache = HashMap<(&'static str, u32, bool), Rc<Sampler>>;
+
+thread_local! {
+    // Kept for the life of the thread, so they are shared by every document generated on it
+    static REGEXES: RefCell<RegexCache> = RefCell::default();
+    static CACHE_REGEXES: Cell<bool> = const { Cell::new(true) };
+}
+
+/// Whether compiled regex samplers are cached on this thread, which they are
+/// unless this turns it off. Without the cache every sample compiles its
+/// regex again, as the generator once did, which the benchmarks measure the
+/// cache against.
+pub fn cache_regexes(cache: bool) {
+    CACHE_REGEXES.with(|cached| cached.set(cache));
+}
+
+fn compiled_re(pattern: &'static str, rep: u32, ascii_only: bool) -> Rc<Sampler> {
+    let compile = || {
+        let hir = ParserBuilder::new().unicode(!ascii_only).build().parse(pattern).unwrap();
+        Rc::new(Sampler {
+            pattern,
+            sampler: rand_regex::Regex::with_hir(hir, rep).unwrap(),
+            matcher: OnceCell::new(),
+        })
+    };
+    if !CACHE_REGEXES.with(Cell::get) {
+        return compile();
+    }
+    REGEXES.with(|regexes| regexes.borrow_mut().entry((pattern, rep, ascii_only)).or_insert_with(compile).clone())
+}
+
+fn rand_re<T: Write, R: Rng>(ctx: &mut Context<T, R>, pattern: &'static str, rep: u32) -> String {
+    let re = compiled_re(pattern, rep, ctx.conf.ascii_only);
+    ctx.sample_re(&re)
+}
+
+fn maybe(func: Rule) -> Rule {
+    Rule::Maybe(Box::new(func), 0)
+}
+
+fn repeat(func: Rule, min_times: Limit, max_times: Limit) -> Rule {
+    Rule::Repeat(Box::new(func), min_times, max_times)
+}
+
+fn select(options: Vec<Rule>) -> Rule {
+    Rule::Select(options, 0)
+}
 
-        Ok(size)
-    })
+fn concat(calls: Vec<Rule>) -> Rule {
+    Rule::Concat(calls)
 }
@@ -0,0 +1,901 @@
+//! The grammar as data, and the walk over it which writes a document.
+//!
+//! The rule functions in the parent module describe the grammar with the
+//! combinators at the bottom of it, which return plain `Rule` values. A grammar
+//! is built from them once per spec version: every production is added to an
+//! arena the first time it is reached, and every other mention of it becomes a
+//! `Ref` to that entry, which is also what lets productions recurse.
+//!
+//! Every `select` and `maybe` is numbered as it is added, and remembers the
+//! production it was written in, so a configuration can give it weights.
+
+use std::collections::HashMap;
+use std::io;
+use std::io::Write;
+use std::sync::OnceLock;
+use rand::Rng;
+
+use super::*;
+use crate::coverage::{ChoiceCoverage, Coverage, ProductionCoverage};
+use crate::weights::{self, Key, Weights, WeightsError};
+
+/// A number which depends on the configuration, such as a repeat count limit.
+pub(super) type Limit = fn(&GeneratorConfig) -> u32;
+
+#[derive(Debug)]
+pub(super) enum Rule {
+    Literal(&'static str),
+    Regex(&'static str, Limit),
+    AsciiOrUtf8(Box<Rule>, Box<Rule>),
+    // The number is the choice's index in the grammar, set when it is added
+    Select(Vec<Rule>, usize),
+    Concat(Vec<Rule>),
+    Maybe(Box<Rule>, usize),
+    Repeat(Box<Rule>, Limit, Limit),
+    // One of the given line breaks, other than LF straight after a lone CR
+    Newline(&'static [&'static str]),
+    // A unicode scalar value other than 0, in hex
+    UnicodeHex,
+    Slashdash,
+    Site(Category, Box<Rule>),
+    Scope(Scope, Box<Rule>),
+    Capture(Capture, Box<Rule>),
+    // Picks an indentation for the rule, which `Indent` then writes
+    Indented(Box<Rule>),
+    Indent,
+    // Writes the rule between as many '#'s as keep it from ending a raw string
+    // early, and at least the given number
+    Hashed(Box<Rule>, u32),
+    // Writes the raw string body, broken up by near misses of the terminator
+    // when they are asked for
+    NearMisses(Box<Rule>),
+    // Goes one level deeper, unless already at the configured depth_max
+    Nested(Box<Rule>),
+    // Goes one multi-line comment deeper, unless already at comment_nesting_max
+    NestedComment(Box<Rule>),
+    // A repeat of nodes or a maybe of children, which a node budget decides
+    // instead when there is one
+    Budgeted(Box<Rule>),
+    // Writes the rule again for as long as it writes exactly one of the keywords
+    NotKeyword(Box<Rule>, &'static [&'static str]),
+    // The first rule, or with near_keywords, half the time the second, which
+    // starts or ends with a keyword
+    NearKeywords(Box<Rule>, Box<Rule>),
+    // A production which hasn't been added to the grammar yet
+    Production(Production),
+    Ref(usize),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(super) struct Production {
+    pub(super) name: &'static str,
+    // Whether --debug tags are written around it
+    pub(super) tagged: bool,
+    pub(super) body: fn() -> Rule,
+}
+
+#[derive(Debug)]
+pub(super) struct Grammar {
+    productions: Vec<(Production, Rule)>,
+    choices: Vec<Choice>,
+    start: Rule,
+}
+
+// A select or maybe, with the name of each alternative (or of what a maybe
+// wraps) where it has one.
+#[derive(Debug)]
+struct Choice {
+    production: Option<usize>,
+    maybe: bool,
+    labels: Vec<Option<&'static str>>,
+}
+
+/// The weights of a configuration, by choice: the weight of each alternative
+/// of a select, or the probability of taking a maybe.
+#[derive(Debug, Default)]
+pub(super) struct ChoiceWeights(Vec<Option<Vec<f64>>>);
+
+impl ChoiceWeights {
+    pub(super) fn get(&self, choice: usize) -> Option<&[f64]> {
+        self.0.get(choice)?.as_deref()
+    }
+}
+
+/// The grammar for a spec version, built the first time it is asked for.
+pub(super) fn grammar(spec_version: u32) -> &'static Grammar {
+    static GRAMMARS: OnceLock<[Grammar; 2]> = OnceLock::new();
+    let grammars = GRAMMARS.get_or_init(|| [
+        Grammar::build(nodes()),
+        Grammar::build(v2::document()),
+    ]);
+    &grammars[spec_version as usize - 1]
+}
+
+impl Grammar {
+    fn build(start: Rule) -> Grammar {
+        let mut grammar = Grammar { productions: Vec::new(), choices: Vec::new(), start: Rule::Literal("") };
+        grammar.start = grammar.add(start, None, &mut HashMap::new());
+        grammar
+    }
+
+    // Adds the productions in rule to the arena, replacing them with refs. They
+    // are keyed by their body function as well as their name, since both spec
+    // versions have productions with the same name. Choices are numbered in the
+    // order they are written, and belong to the production `owner`.
+    fn add(&mut self, rule: Rule, owner: Option<usize>, ids: &mut HashMap<(&'static str, usize), usize>) -> Rule {
+        let mut add = |rule: Box<Rule>| Box::new(self.add(*rule, owner, ids));
+        match rule {
+            Rule::AsciiOrUtf8(ascii, unicode) => {
+                let ascii = add(ascii);
+                Rule::AsciiOrUtf8(ascii, add(unicode))
+            }
+            Rule::Select(rules, _) => {
+                let choice = self.choices.len();
+                self.choices.push(Choice { production: owner, maybe: false, labels: Vec::new() });
+                let rules: Vec<Rule> = rules.into_iter().map(|rule| self.add(rule, owner, ids)).collect();
+                self.choices[choice].labels = rules.iter().map(|rule| self.label(rule)).collect();
+                Rule::Select(rules, choice)
+            }
+            Rule::Concat(rules) => Rule::Concat(rules.into_iter().map(|rule| self.add(rule, owner, ids)).collect()),
+            Rule::Maybe(rule, _) => {
+                let choice = self.choices.len();
+                self.choices.push(Choice { production: owner, maybe: true, labels: Vec::new() });
+                let rule = self.add(*rule, owner, ids);
+                // A maybe of several rules, like v2's `type? node-space*`, is
+                // called by the first of them
+                let label = match &rule {
+                    Rule::Concat(rules) => rules.first().and_then(|rule| self.label(rule)),
+                    rule => self.label(rule),
+                };
+                self.choices[choice].labels = vec![label];
+                Rule::Maybe(Box::new(rule), choice)
+            }
+            Rule::Repeat(rule, min, max) => Rule::Repeat(add(rule), min, max),
+            Rule::Site(category, rule) => Rule::Site(category, add(rule)),
+            Rule::Scope(scope, rule) => Rule::Scope(scope, add(rule)),
+            Rule::Capture(capture, rule) => Rule::Capture(capture, add(rule)),
+            Rule::Indented(rule) => Rule::Indented(add(rule)),
+            Rule::Nested(rule) => Rule::Nested(add(rule)),
+            Rule::NestedComment(rule) => Rule::NestedComment(add(rule)),
+            Rule::Budgeted(rule) => Rule::Budgeted(add(rule)),
+            Rule::NotKeyword(rule, keywords) => Rule::NotKeyword(add(rule), keywords),
+            Rule::NearKeywords(rule, near) => {
+                let rule = add(rule);
+                Rule::NearKeywords(rule, add(near))
+            }
+            Rule::Hashed(rule, min) => Rule::Hashed(add(rule), min),
+            Rule::NearMisses(rule) => Rule::NearMisses(add(rule)),
+            Rule::Production(production) => {
+                let key = (production.name, production.body as usize);
+                if let Some(&id) = ids.get(&key) {
+                    return Rule::Ref(id);
+                }
+                let id = self.productions.len();
+                ids.insert(key, id);
+                self.productions.push((production, Rule::Literal("")));
+                self.productions[id].1 = self.add((production.body)(), Some(id), ids);
+                Rule::Ref(id)
+            }
+            leaf => leaf,
+        }
+    }
+
+    // What a config file can call an alternative: the production or literal it is
+    fn label(&self, rule: &Rule) -> Option<&'static str> {
+        match rule {
+            Rule::Literal(s) => Some(s),
+            Rule::Ref(id) => Some(self.productions[*id].0.name),
+            Rule::Site(_, rule) | Rule::Scope(_, rule) | Rule::Capture(_, rule) | Rule::Nested(rule)
+                | Rule::NestedComment(rule) => self.label(rule),
+            _ => None,
+        }
+    }
+
+    // The choices written in the productions with the given name, in order
+    fn choices_of<'g>(&'g self, name: &'g str, maybe: bool) -> impl Iterator<Item = (usize, &'g Choice)> + 'g {
+        self.choices.iter()
+            .enumerate()
+            .filter(move |(_, choice)| choice.maybe == maybe
+                && choice.production.is_some_and(|id| self.productions[id].0.name == name))
+    }
+
+    /// Match the weights of a configuration up with the choices they are for.
+    pub(super) fn resolve(&self, weights: &Weights) -> Result<ChoiceWeights, WeightsError> {
+        let mut resolved = ChoiceWeights(Vec::new());
+        if weights.productions.is_empty() {
+            return Ok(resolved);
+        }
+        resolved.0.resize(self.choices.len(), None);
+        let find = |labels: &[Option<&str>], key: &Key, production: &str| -> Result<Vec<usize>, WeightsError> {
+            let found: Vec<usize> = match key {
+                Key::Index(i) => (*i < labels.len()).then_some(*i).into_iter().collect(),
+                Key::Name(name) => (0..labels.len()).filter(|&i| labels[i] == Some(name.as_str())).collect(),
+            };
+            if found.is_empty() {
+                return Err(WeightsError(format!("{} has no choice for {}", production, key)));
+            }
+            Ok(found)
+        };
+        for production in &weights.productions {
+            let name = production.name.as_str();
+            if !self.productions.iter().any(|(p, _)| p.name == name) {
+                return Err(WeightsError(format!("there is no production named {}", name)));
+            }
+            let selects: Vec<_> = self.choices_of(name, false).collect();
+            if production.selects.len() > selects.len() {
+                return Err(WeightsError(format!("{} only has {} selects", name, selects.len())));
+            }
+            for (set, (choice, select)) in production.selects.iter().zip(selects) {
+                if set.is_empty() {
+                    continue;
+                }
+                let mut alternatives = vec![1.0; select.labels.len()];
+                for (key, weight) in set {
+                    for i in find(&select.labels, key, name)? {
+                        alternatives[i] = *weight;
+                    }
+                }
+                if alternatives.iter().all(|&weight| weight == 0.0) {
+                    return Err(WeightsError(format!("every alternative of a select in {} has a weight of 0", name)));
+                }
+                resolved.0[choice] = Some(alternatives);
+            }
+            let maybes: Vec<_> = self.choices_of(name, true).collect();
+            let labels: Vec<_> = maybes.iter().map(|(_, maybe)| maybe.labels[0]).collect();
+            for (key, probability) in &production.maybes {
+                for i in find(&labels, key, name)? {
+                    resolved.0[maybes[i].0] = Some(vec![*probability]);
+                }
+            }
+        }
+        Ok(resolved)
+    }
+
+    /// Empty coverage of the grammar, knowing which parts of it can be reached
+    /// with the configuration and weights.
+    pub(super) fn coverage(&self, conf: &GeneratorConfig, weights: &ChoiceWeights) -> Coverage {
+        let mut coverage = Coverage {
+            spec_version: conf.spec_version,
+            directed: false,
+            productions: self.productions.iter()
+                .map(|(production, _)| ProductionCoverage { name: production.name, reachable: false, hits: 0 })
+                .collect(),
+            choices: Vec::new(),
+            raw_hash_max: conf.raw_hash_max,
+            raw_hashes: 0,
+            comment_nesting_max: conf.comment_nesting_max,
+            comment_nesting: 0,
+        };
+        for (i, choice) in self.choices.iter().enumerate() {
+            let ordinal = self.choices[..i].iter()
+                .filter(|other| other.production == choice.production && other.maybe == choice.maybe)
+                .count();
+            let len = if choice.maybe { 2 } else { choice.labels.len() };
+            coverage.choices.push(ChoiceCoverage {
+                production: choice.production,
+                ordinal,
+                maybe: choice.maybe,
+                labels: choice.labels.clone(),
+                reachable: vec![false; len],
+                hits: vec![0; len],
+            });
+        }
+        self.reach(&self.start, conf, weights, 0, &mut coverage);
+        coverage
+    }
+
+    // Marks what can be reached from rule, without running it
+    fn reach(&self, rule: &Rule, conf: &GeneratorConfig, weights: &ChoiceWeights, depth: u32, coverage: &mut Coverage) {
+        match rule {
+            Rule::AsciiOrUtf8(ascii, unicode) => {
+                let rule = if conf.ascii_only { ascii } else { unicode };
+                self.reach(rule, conf, weights, depth, coverage);
+            }
+            Rule::Select(options, choice) => {
+                for (i, option) in options.iter().enumerate() {
+                    if weights.get(*choice).is_none_or(|weights| weights[i] > 0.0) {
+                        coverage.choices[*choice].reachable[i] = true;
+                        self.reach(option, conf, weights, depth, coverage);
+                    }
+                }
+            }
+            Rule::Concat(rules) => {
+                for rule in rules {
+                    self.reach(rule, conf, weights, depth, coverage);
+                }
+            }
+            Rule::Maybe(rule, choice) => {
+                let probability = weights.get(*choice).map_or(0.5, |weights| weights[0]);
+                coverage.choices[*choice].reachable[0] = probability < 1.0;
+                if probability > 0.0 {
+                    coverage.choices[*choice].reachable[1] = true;
+                    self.reach(rule, conf, weights, depth, coverage);
+                }
+            }
+            Rule::Repeat(rule, min_times, max_times) if min_times(conf) > 0 || max_times(conf) > 0 => {
+                self.reach(rule, conf, weights, depth, coverage);
+            }
+            Rule::Site(_, rule) | Rule::Scope(_, rule) | Rule::Capture(_, rule) | Rule::Indented(rule)
+                | Rule::Hashed(rule, _) | Rule::NearMisses(rule) | Rule::Budgeted(rule) | Rule::NotKeyword(rule, _) => {
+                self.reach(rule, conf, weights, depth, coverage);
+            }
+            Rule::NearKeywords(rule, near) => {
+                self.reach(rule, conf, weights, depth, coverage);
+                if conf.near_keywords {
+                    self.reach(near, conf, weights, depth, coverage);
+                }
+            }
+            Rule::Nested(rule) if depth <= conf.depth_max => {
+                self.reach(rule, conf, weights, depth + 1, coverage);
+            }
+            Rule::NestedComment(rule) if conf.comment_nesting_max > 0 => {
+                self.reach(rule, conf, weights, depth, coverage);
+            }
+            Rule::Ref(id) if !coverage.productions[*id].reachable => {
+                coverage.productions[*id].reachable = true;
+                self.reach(&self.productions[*id].1, conf, weights, depth, coverage);
+            }
+            _ => {}
+        }
+    }
+
+    /// A config file which sets every weight in the grammar to its default.
+    pub(super) fn default_weights(&self) -> String {
+        let mut out = String::from("weights {\n");
+        for (id, (production, _)) in self.productions.iter().enumerate() {
+            let owned = |maybe: bool| self.choices.iter().filter(move |choice| choice.maybe == maybe && choice.production == Some(id));
+            if owned(false).next().is_none() && owned(true).next().is_none() {
+                continue;
+            }
+            out.push_str(&format!("    {} {{\n", production.name));
+            for select in owned(false) {
+                out.push_str(&format!("        select{}\n", entries(&select.labels, "1")));
+            }
+            let maybes: Vec<_> = owned(true).map(|maybe| maybe.labels[0]).collect();
+            if !maybes.is_empty() {
+                out.push_str(&format!("        maybe{}\n", entries(&maybes, "0.5")));
+            }
+            out.push_str("    }\n");
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    /// Write a document, starting from the top of the grammar.
+    pub(super) fn generate<T: Write, R: Rng>(&self, ctx: &mut Context<T, R>) -> io::Result<usize> {
+        self.run(&self.start, ctx)
+    }
+
+    /// Write the first production with the given name, as it would be written
+    /// anywhere in a document.
+    pub(super) fn generate_production<T: Write, R: Rng>(&self, name: &str, ctx: &mut Context<T, R>) -> io::Result<usize> {
+        let id = self.productions.iter()
+            .position(|(production, _)| production.name == name)
+            .unwrap_or_else(|| panic!("the grammar has no production named {}", name));
+        self.run(&Rule::Ref(id), ctx)
+    }
+
+    // The fewest bytes each production can be written in with the
+    // configuration and weights, found by shrinking every size from unbounded
+    // until none of them change
+    fn smallest(&self, conf: &GeneratorConfig, weights: &ChoiceWeights) -> Vec<usize> {
+        let mut sizes = Smallest { grammar: self, conf, weights, productions: vec![usize::MAX; self.productions.len()] };
+        loop {
+            let mut changed = false;
+            for id in 0..self.productions.len() {
+                let size = sizes.size(&self.productions[id].1);
+                if size < sizes.productions[id] {
+                    sizes.productions[id] = size;
+                    changed = true;
+                }
+            }
+            if !changed {
+                return sizes.productions;
+            }
+        }
+    }
+
+    // Walks the rule with a stack of steps rather than by recursing, so that
+    // nodes and comments can be nested as deep as asked without running out of
+    // native stack. A rule which does something once what it contains is
+    // written pushes a step for that before the rules it contains, which the
+    // stack then runs first.
+    //
+    // With max_bytes, the stack also knows the fewest bytes what is on it can
+    // be written in, and every choice is limited to the ones which leave room
+    // for that, so that the document can always be finished under the cap.
+    fn run<'g, T: Write, R: Rng>(&'g self, rule: &'g Rule, ctx: &mut Context<T, R>) -> io::Result<usize> {
+        let smallest = ctx.conf.max_bytes.map(|_| Smallest {
+            grammar: self,
+            conf: ctx.conf,
+            weights: ctx.weights,
+            productions: self.smallest(ctx.conf, ctx.weights),
+        });
+        let mut stack = Stack { steps: Vec::new(), smallest, pending: 0 };
+        stack.push(Step::Run(rule));
+        if let Some(max) = ctx.conf.max_bytes.filter(|&max| stack.pending > max) {
+            let message = match stack.pending {
+                usize::MAX => "max_bytes can't be kept to when only depth_max stops nodes from having children".to_string(),
+                smallest => format!("max_bytes of {} is less than the smallest document, {} bytes", max, smallest),
+            };
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+        }
+        let mut size = 0;
+        while let Some(step) = stack.pop() {
+            let room = stack.room(ctx);
+            let rule = match step {
+                Step::Run(rule) => rule,
+                Step::Repeat(rule, times) => {
+                    if times > 0 {
+                        stack.push(Step::Repeat(rule, times - 1));
+                        stack.push(Step::Run(rule));
+                    }
+                    continue;
+                }
+                Step::Siblings(rule, mut budgets) => {
+                    if let Some(budget) = budgets.pop().filter(|_| stack.size(rule) <= room) {
+                        ctx.budget = Some(budget);
+                        stack.push(Step::Siblings(rule, budgets));
+                        stack.push(Step::Run(rule));
+                    }
+                    continue;
+                }
+                Step::NearMisses(rule, times) => {
+                    if times > 0 {
+                        // Leaves room for the one more '#' the terminator needs
+                        let hashes = NEAR_MISS_HASHES_MAX.min(ctx.conf.raw_hash_max.saturating_sub(1)).max(1);
+                        let near_miss = format!("\"{}", "#".repeat(ctx.range(1..=hashes) as usize));
+                        size += ctx.write(near_miss.as_bytes())?;
+                        stack.push(Step::NearMisses(rule, times - 1));
+                        stack.push(Step::Run(rule));
+                    }
+                    continue;
+                }
+                Step::StartNearMisses(rule) => {
+                    let most = NEAR_MISSES_MAX.min((room / stack.near_miss_size(rule)).min(u32::MAX as usize) as u32);
+                    if ctx.conf.raw_string_near_misses && most > 0 {
+                        let times = ctx.range(1..=most);
+                        stack.push(Step::NearMisses(rule, times));
+                    }
+                    continue;
+                }
+                Step::EndSite(category, start) => {
+                    if let Some(sites) = ctx.sites.as_mut() {
+                        sites.push(Site { category, start, end: ctx.pos });
+                    }
+                    continue;
+                }
+                Step::EndScope => {
+                    if let Some(model) = ctx.model.as_mut() {
+                        model.end();
+                    }
+                    continue;
+                }
+                Step::EndCapture(capture, outer) => {
+                    let text = std::mem::replace(&mut ctx.capture, outer).unwrap();
+                    ctx.model.as_mut().unwrap().capture(capture, &String::from_utf8(text).unwrap());
+                    continue;
+                }
+                Step::EndIndent => {
+                    ctx.indents.pop();
+                    continue;
+                }
+                Step::EndHashed(min, _) => {
+                    let mut hashes = min.max(hashes_needed(ctx.held()));
+                    if !ctx.conf.raw_string_near_misses {
+                        while hashes < ctx.conf.raw_hash_max && 2 * (hashes as usize + 1) <= room && ctx.maybe(0.5) {
+                            hashes += 1;
+                        }
+                    }
+                    if let Some(coverage) = ctx.coverage.as_mut() {
+                        coverage.raw_hashes = coverage.raw_hashes.max(hashes);
+                    }
+                    let hashes = "#".repeat(hashes as usize);
+                    ctx.release(hashes.as_bytes())?;
+                    size += hashes.len() + ctx.write(hashes.as_bytes())?;
+                    continue;
+                }
+                Step::EndNotKeyword(rule, keywords) => {
+                    if keywords.iter().any(|keyword| keyword.as_bytes() == ctx.held()) {
+                        size -= ctx.discard();
+                        ctx.hold();
+                        stack.push(Step::EndNotKeyword(rule, keywords));
+                        stack.push(Step::Run(rule));
+                    } else {
+                        ctx.release(b"")?;
+                    }
+                    continue;
+                }
+                Step::EndNested => {
+                    ctx.depth -= 1;
+                    continue;
+                }
+                Step::EndNestedComment => {
+                    ctx.comment_depth -= 1;
+                    continue;
+                }
+                Step::EndTag(name, span) => {
+                    ctx.trace_end(span);
+                    ctx.write_debug(&format!("</{}>", name.to_uppercase()));
+                    continue;
+                }
+            };
+            match rule {
+                Rule::Literal(s) => size += ctx.write(s.as_bytes())?,
+                Rule::Regex(pattern, rep) => {
+                    let mut rep = rep(ctx.conf);
+                    if stack.smallest.is_some() && compiled_re(pattern, rep, ctx.conf.ascii_only).sampler.capacity() > room {
+                        rep = rep.min(1);
+                    }
+                    let s = rand_re(ctx, pattern, rep);
+                    size += ctx.write(s.as_bytes())?;
+                }
+                Rule::AsciiOrUtf8(ascii, unicode) => {
+                    stack.push(Step::Run(if ctx.conf.ascii_only { ascii } else { unicode }));
+                }
+                Rule::Select(options, choice) => {
+                    let mut weights = ctx.weights.get(*choice).map(<[f64]>::to_vec);
+                    if let Some(coverage) = ctx.coverage.as_deref().filter(|coverage| coverage.directed) {
+                        weights = Some(coverage.directed_weights(*choice, weights.as_deref()));
+                    }
+                    if options.iter().any(|option| stack.size(option) > room) {
+                        let mut fitting = weights.unwrap_or_else(|| vec![1.0; options.len()]);
+                        for (weight, option) in fitting.iter_mut().zip(options) {
+                            if stack.size(option) > room {
+                                *weight = 0.0;
+                            }
+                        }
+                        weights = Some(fitting);
+                    }
+                    let index = ctx.choose_weighted(options.len(), weights.as_deref());
+                    if let Some(coverage) = ctx.coverage.as_mut() {
+                        coverage.hit(*choice, index);
+                    }
+                    stack.push(Step::Run(&options[index]));
+                }
+                Rule::Concat(rules) => {
+                    for rule in rules.iter().rev() {
+                        stack.push(Step::Run(rule));
+                    }
+                }
+                Rule::Maybe(rule, choice) => {
+                    let mut probability = ctx.weights.get(*choice).map_or(0.5, |weights| weights[0]);
+                    if let Some(coverage) = ctx.coverage.as_deref().filter(|coverage| coverage.directed) {
+                        probability = coverage.directed_probability(*choice, probability);
+                    }
+                    if stack.size(rule) > room {
+                        probability = 0.0;
+                    }
+                    let taken = ctx.maybe(probability);
+                    if let Some(coverage) = ctx.coverage.as_mut() {
+                        coverage.hit(*choice, taken as usize);
+                    }
+                    if taken {
+                        stack.push(Step::Run(rule));
+                    }
+                }
+                Rule::Repeat(rule, min_times, max_times) => {
+                    let min_times = min_times(ctx.conf);
+                    let mut max_times = max_times(ctx.conf).max(min_times);
+                    if let Some(fitting) = room.checked_div(stack.size(rule)) {
+                        max_times = max_times.min(fitting.min(u32::MAX as usize) as u32).max(min_times);
+                    }
+                    let times = ctx.range(min_times..=max_times);
+                    stack.push(Step::Repeat(rule, times));
+                }
+                Rule::Newline(options) => {
+                    let after_cr = ctx.last_byte == b'\r';
+                    let options: Vec<&str> = options.iter()
+                        .copied()
+                        .filter(|newline| !(after_cr && newline.starts_with('\n')))
+                        .collect();
+                    let index = ctx.choose_index(options.len());
+                    size += ctx.write(options[index].as_bytes())?;
+                }
+                Rule::UnicodeHex => {
+                    // Skips over the surrogates, which aren't unicode scalar values
+                    let mut c = ctx.range(1..=0x10FFFF - 0x800);
+                    if c >= 0xD800 {
+                        c += 0x800;
+                    }
+                    let s = format!("{:x}", c);
+                    size += ctx.write(s.as_bytes())?;
+                }
+                Rule::Slashdash => {
+                    if let Some(model) = ctx.model.as_mut() {
+                        model.slashdash();
+                    }
+                    size += ctx.write(b"/-")?;
+                }
+                Rule::Site(category, rule) => {
+                    stack.push(Step::EndSite(*category, ctx.pos));
+                    stack.push(Step::Run(rule));
+                }
+                Rule::Scope(scope, rule) => {
+                    if let Some(model) = ctx.model.as_mut() {
+                        model.begin(*scope);
+                        stack.push(Step::EndScope);
+                    }
+                    stack.push(Step::Run(rule));
+                }
+                Rule::Capture(capture, rule) => {
+                    if ctx.model.is_some() {
+                        let outer = ctx.capture.replace(Vec::new());
+                        stack.push(Step::EndCapture(*capture, outer));
+                    }
+                    stack.push(Step::Run(rule));
+                }
+                Rule::Indented(rule) => {
+                    let indent = rand_indent(ctx);
+                    ctx.indents.push(indent);
+                    stack.push(Step::EndIndent);
+                    stack.push(Step::Run(rule));
+                }
+                Rule::Indent => {
+                    let indent = ctx.indents.last().cloned().unwrap_or_default();
+                    size += ctx.write(indent.as_bytes())?;
+                }
+                Rule::Hashed(rule, min) => {
+                    // The body can't need more '#'s than it has bytes, so it
+                    // gets a third of the room, and its '#'s the rest
+                    let body = stack.size(rule);
+                    let reserved = match stack.smallest {
+                        Some(_) => room.saturating_sub(body.max(room.saturating_sub(2 * *min as usize) / 3)),
+                        None => 0,
+                    };
+                    ctx.hold();
+                    stack.push(Step::EndHashed(*min, reserved));
+                    stack.push(Step::Run(rule));
+                }
+                Rule::NearMisses(rule) => {
+                    stack.push(Step::StartNearMisses(rule));
+                    stack.push(Step::Run(rule));
+                }
+                Rule::NotKeyword(rule, keywords) => {
+                    ctx.hold();
+                    stack.push(Step::EndNotKeyword(rule, keywords));
+                    stack.push(Step::Run(rule));
+                }
+                Rule::NearKeywords(rule, near) => {
+                    let taken = ctx.conf.near_keywords && stack.size(near) <= room && ctx.maybe(0.5);
+                    stack.push(Step::Run(if taken { near } else { rule }));
+                }
+                Rule::Nested(rule) => {
+                    if ctx.depth <= ctx.conf.depth_max {
+                        ctx.depth += 1;
+                        stack.push(Step::EndNested);
+                        stack.push(Step::Run(rule));
+                    }
+                }
+                Rule::NestedComment(rule) => {
+                    if ctx.comment_depth < ctx.conf.comment_nesting_max {
+                        ctx.comment_depth += 1;
+                        if let Some(coverage) = ctx.coverage.as_mut() {
+                            coverage.comment_nesting = coverage.comment_nesting.max(ctx.comment_depth);
+                        }
+                        stack.push(Step::EndNestedComment);
+                        stack.push(Step::Run(rule));
+                    }
+                }
+                Rule::Budgeted(rule) => match &**rule {
+                    _ if ctx.conf.total_nodes.is_none() => stack.push(Step::Run(rule)),
+                    Rule::Repeat(node, _, _) => {
+                        let budget = ctx.budget.take().unwrap_or(0);
+                        let mut budgets = split_budget(ctx, budget);
+                        budgets.reverse();
+                        stack.push(Step::Siblings(node, budgets));
+                    }
+                    Rule::Maybe(children, choice) => {
+                        let taken = ctx.budget.is_some_and(|budget| budget > 0) && stack.size(children) <= room;
+                        if let Some(coverage) = ctx.coverage.as_mut() {
+                            coverage.hit(*choice, taken as usize);
+                        }
+                        if taken {
+                            stack.push(Step::Run(children));
+                        }
+                    }
+                    _ => unreachable!("only repeats and maybes have a budget"),
+                },
+                Rule::Ref(id) => {
+                    let (production, rule) = &self.productions[*id];
+                    if let Some(coverage) = ctx.coverage.as_mut() {
+                        coverage.productions[*id].hits += 1;
+                    }
+                    if production.tagged {
+                        ctx.write_debug(&format!("<{}>", production.name.to_uppercase()));
+                        let span = ctx.trace_start(production.name);
+                        stack.push(Step::EndTag(production.name, span));
+                    }
+                    stack.push(Step::Run(rule));
+                }
+                Rule::Production(production) => unreachable!("{} was never added to the grammar", production.name),
+            }
+        }
+        Ok(size)
+    }
+}
+
+// What is left to do on the stack of a walk over the grammar: a rule to run,
+// or the rest of one which was started before the rules it contains.
+enum Step<'g> {
+    Run(&'g Rule),
+    // The rule, and how many more times to write it
+    Repeat(&'g Rule, u32),
+    // The node, and the budgets of the ones left to write, last first
+    Siblings(&'g Rule, Vec<u32>),
+    // The raw string body, and how many more near misses to write before it
+    NearMisses(&'g Rule, u32),
+    StartNearMisses(&'g Rule),
+    EndSite(Category, usize),
+    EndScope,
+    // The capture to hand to the model, and the one it was written inside of
+    EndCapture(Capture, Option<Vec<u8>>),
+    EndIndent,
+    // The fewest '#'s, and the room kept for them under max_bytes
+    EndHashed(u32, usize),
+    // The rule to write again if what it wrote is one of the keywords
+    EndNotKeyword(&'g Rule, &'static [&'static str]),
+    EndNested,
+    EndNestedComment,
+    EndTag(&'static str, Option<usize>),
+}
+
+// The steps left in a walk, and with max_bytes, the fewest bytes they can be
+// written in.
+struct Stack<'g, 'c> {
+    steps: Vec<Step<'g>>,
+    smallest: Option<Smallest<'c>>,
+    pending: usize,
+}
+
+impl<'g, 'c> Stack<'g, 'c> {
+    fn push(&mut self, step: Step<'g>) {
+        self.pending += self.cost(&step);
+        self.steps.push(step);
+    }
+
+    fn pop(&mut self) -> Option<Step<'g>> {
+        let step = self.steps.pop()?;
+        self.pending -= self.cost(&step);
+        Some(step)
+    }
+
+    // How many more bytes the rule being run can write, over the fewest it can
+    fn room<T: Write, R: Rng>(&self, ctx: &Context<T, R>) -> usize {
+        ctx.conf.max_bytes.map_or(usize::MAX, |max| max.saturating_sub(ctx.pos + self.pending))
+    }
+
+    fn size(&self, rule: &Rule) -> usize {
+        self.smallest.as_ref().map_or(0, |smallest| smallest.size(rule))
+    }
+
+    // The most a near miss and the body after it can take
+    fn near_miss_size(&self, rule: &Rule) -> usize {
+        1 + NEAR_MISS_HASHES_MAX as usize + self.size(rule)
+    }
+
+    fn cost(&self, step: &Step) -> usize {
+        let Some(smallest) = &self.smallest else {
+            return 0;
+        };
+        match step {
+            Step::Run(rule) => smallest.size(rule),
+            Step::Repeat(rule, times) => smallest.size(rule).saturating_mul(*times as usize),
+            Step::NearMisses(rule, times) => self.near_miss_size(rule).saturating_mul(*times as usize),
+            Step::EndHashed(_, reserved) => *reserved,
+            Step::EndTag(name, _) if smallest.conf.debug => name.len() + 3,
+            _ => 0,
+        }
+    }
+}
+
+// Sizes rules by the fewest bytes they can be written in, or for the ones which
+// can write a varying number of bytes however their choices go, the most.
+struct Smallest<'c> {
+    grammar: &'c Grammar,
+    conf: &'c GeneratorConfig,
+    weights: &'c ChoiceWeights,
+    productions: Vec<usize>,
+}
+
+impl<'c> Smallest<'c> {
+    fn size(&self, rule: &Rule) -> usize {
+        match rule {
+            Rule::Literal(s) => s.len(),
+            Rule::Regex(pattern, rep) => compiled_re(pattern, rep(self.conf).min(1), self.conf.ascii_only).sampler.capacity(),
+            Rule::AsciiOrUtf8(ascii, unicode) => self.size(if self.conf.ascii_only { ascii } else { unicode }),
+            Rule::Select(options, choice) => options.iter()
+                .enumerate()
+                .filter(|&(i, _)| self.weights.get(*choice).is_none_or(|weights| weights[i] > 0.0))
+                .map(|(_, option)| self.size(option))
+                .min()
+                .unwrap_or(usize::MAX),
+            Rule::Concat(rules) => rules.iter().fold(0, |size, rule| size.saturating_add(self.size(rule))),
+            Rule::Maybe(rule, choice) => match self.weights.get(*choice) {
+                Some(weights) if weights[0] >= 1.0 => self.size(rule),
+                _ => 0,
+            },
+            Rule::Repeat(rule, min_times, _) => self.size(rule).saturating_mul(min_times(self.conf) as usize),
+            Rule::Newline(options) => options.iter().map(|newline| newline.len()).max().unwrap_or(0),
+            Rule::UnicodeHex => 6,
+            Rule::Slashdash => 2,
+            Rule::Indent => self.conf.extra_space_max as usize * UNICODE_SPACES.iter().map(|space| space.len()).max().unwrap(),
+            Rule::Site(_, rule) | Rule::Scope(_, rule) | Rule::Capture(_, rule) | Rule::Indented(rule)
+                | Rule::NearMisses(rule) | Rule::Nested(rule) | Rule::NestedComment(rule) | Rule::Budgeted(rule)
+                | Rule::NotKeyword(rule, _) | Rule::NearKeywords(rule, _) => self.size(rule),
+            Rule::Hashed(rule, min) => {
+                let body = self.size(rule);
+                body.saturating_add(body.max(*min as usize).saturating_mul(2))
+            }
+            Rule::Ref(id) => {
+                let (production, _) = &self.grammar.productions[*id];
+                let tags = if production.tagged && self.conf.debug { 2 * production.name.len() + 5 } else { 0 };
+                self.productions[*id].saturating_add(tags)
+            }
+            Rule::Production(production) => unreachable!("{} was never added to the grammar", production.name),
+        }
+    }
+}
+
+// The values for one choice in a config file: by name when every alternative
+// has a name of its own, and by position otherwise.
+fn entries(labels: &[Option<&str>], value: &str) -> String {
+    let named = labels.iter().enumerate().all(|(i, label)| label.is_some() && !labels[..i].contains(label));
+    labels.iter()
+        .map(|label| match label {
+            Some(name) if named => format!(" {}={}", weights::key(name), value),
+            _ => format!(" {}", value),
+        })
+        .collect()
+}
+
+// Picks how many nodes a nodes with the given budget writes, and how much of
+// what is left each of their children get. Nodes are only wider than
+// nodes_per_child_max where the levels below them, down to depth_max, can't
+// hold the budget otherwise.
+fn split_budget<T: Write, R: Rng>(ctx: &mut Context<T, R>, budget: u32) -> Vec<u32> {
+    if budget == 0 {
+        return Vec::new();
+    }
+    let width = ctx.conf.nodes_per_child_max.max(1) as u64;
+    let levels_below = (ctx.conf.depth_max + 1).saturating_sub(ctx.depth);
+    // The most nodes the children of one node can hold
+    let below = match width {
+        1 => levels_below as u64,
+        _ => width.checked_pow(levels_below).map_or(u64::MAX, |n| (n - 1) / (width - 1) * width),
+    };
+    let fewest = (budget as u64).div_ceil(below.saturating_add(1)).max(1) as u32;
+    let count = ctx.range(fewest..=budget.min(fewest.max(width as u32)));
+    let mut left = budget - count;
+    let mut budgets = Vec::with_capacity(count as usize);
+    for i in 0..count {
+        let others = (count - i - 1) as u64;
+        let share = if others == 0 {
+            left
+        } else {
+            // At least what the others can't hold, and around an even share
+            // at most, so that the first node doesn't take most of it
+            let least = (left as u64).saturating_sub(others.saturating_mul(below)) as u32;
+            let most = (left as u64).min(below).min(2 * left as u64 / (others + 1)) as u32;
+            ctx.range(least..=most.max(least))
+        };
+        left -= share;
+        budgets.push(share);
+    }
+    budgets
+}
+
+const NEAR_MISSES_MAX: u32 = 3;
+const NEAR_MISS_HASHES_MAX: u32 = 3;
+
+// The fewest '#'s after a closing '"' which nothing in the quoted text, other
+// than that last '"', could be mistaken for
+fn hashes_needed(quoted: &[u8]) -> u32 {
+    let body = &quoted[..quoted.len().saturating_sub(1)];
+    body.iter()
+        .enumerate()
+        .filter(|&(_, &b)| b == b'"')
+        .map(|(i, _)| body[i + 1..].iter().take_while(|&&b| b == b'#').count() as u32 + 1)
+        .max()
+        .unwrap_or(0)
+}
@@ -0,0 +1,471 @@
+//! Productions for the KDL 2.0 grammar. Rules which are unchanged from 1.0
+//! (numbers, signs, unicode-space, ...) are shared with the parent module.
+
+use super::*;
+
+// The character classes which differ between the two spec versions. These are
+// macros rather than consts so they can be spliced into patterns with concat!.
+macro_rules! newline_chars {
+    () => { "\\r\\n\\x0B\\x0C\\x{85}\\x{2028}\\x{2029}" };
+}
+
+macro_rules! unicode_space_chars {
+    () => { "\\t \\xA0\\x{1680}\\x{2000}-\\x{200A}\\x{202F}\\x{205F}\\x{3000}" };
+}
+
+macro_rules! disallowed_chars {
+    () => { "\\x00-\\x08\\x0E-\\x1F\\x7F\\x{200E}\\x{200F}\\x{202A}-\\x{202E}\\x{2066}-\\x{2069}\\x{FEFF}" };
+}
+
+const MULTI_LINE_STRING_LINES_MAX: u32 = 3;
+
+// document := bom? nodes
+pub(super) fn document() -> Rule {
+    production("document", || concat(vec![
+        maybe(bom()),
+        nodes(),
+    ]))
+}
+
+// nodes := (line-space* node)* line-space*
+fn nodes() -> Rule {
+    nested(tagged("nodes", || concat(vec![
+        budgeted(repeat(concat(vec![
+            repeat(line_space(), |_| 0, |c| c.blank_lines_max),
+            node(),
+        ]), |c| c.nodes_per_child_min, |c| c.nodes_per_child_max)),
+        repeat(line_space(), |_| 0, |c| c.blank_lines_max),
+    ])))
+}
+
+// node := slashdash? type? node-space* string
+//     (node-space+ slashdash? node-prop-or-arg)*
+//     (node-space+ slashdash? node-children)?
+//     node-space* node-terminator
+fn node() -> Rule {
+    tagged("node", || scope(Scope::Node, concat(vec![
+        maybe(slashdash()),
+        maybe(concat(vec![
+            type_rule(),
+            repeat(node_space(), |_| 0, |c| c.extra_space_max),
+        ])),
+        capture(Capture::Name, string_rule()),
+        repeat(concat(vec![
+            repeat(node_space(), |_| 1, |c| c.extra_space_max),
+            scope(Scope::Entry, concat(vec![
+                maybe(slashdash()),
+                node_prop_or_arg(),
+            ])),
+        ]), |_| 0, |c| c.props_or_args_max),
+        budgeted(maybe(concat(vec![
+            repeat(node_space(), |_| 1, |c| c.extra_space_max),
+            scope(Scope::Children, concat(vec![
+                maybe(slashdash()),
+                node_children(),
+            ])),
+        ]))),
+        repeat(node_space(), |_| 0, |c| c.extra_space_max),
+        node_terminator(),
+    ])))
+}
+
+// slashdash := '/-' line-space*
+fn slashdash() -> Rule {
+    production("slashdash", || concat(vec![
+        write_slashdash(),
+        repeat(line_space(), |_| 0, |c| c.extra_space_max),
+    ]))
+}
+
+// node-prop-or-arg := prop | value
+fn node_prop_or_arg() -> Rule {
+    tagged("node-prop-or-arg", || select(vec![prop(), value()]))
+}
+
+// node-children := '{' nodes '}'
+fn node_children() -> Rule {
+    tagged("node-children", || concat(vec![
+        write_literal("{"),
+        nodes(),
+        site(Category::UnclosedChildren, write_literal("}")),
+    ]))
+}
+
+// node-terminator := single-line-comment | newline | ';' | eof
+fn node_terminator() -> Rule {
+    tagged("node-terminator", || select(vec![
+        single_line_comment(),
+        newline(),
+        write_literal(";"),
+    ]))
+}
+
+// prop := string node-space* '=' node-space* value
+fn prop() -> Rule {
+    tagged("prop", || concat(vec![
+        capture(Capture::Key, string_rule()),
+        repeat(node_space(), |_| 0, |c| c.extra_space_max),
+        write_literal("="),
+        repeat(node_space(), |_| 0, |c| c.extra_space_max),
+        value(),
+    ]))
+}
+
+// value := type? node-space* (string | number | keyword)
+fn value() -> Rule {
+    tagged("value", || concat(vec![
+        maybe(concat(vec![
+            type_rule(),
+            repeat(node_space(), |_| 0, |c| c.extra_space_max),
+        ])),
+        capture(Capture::Value, select(vec![string_rule(), number(), keyword()])),
+    ]))
+}
+
+// type := '(' node-space* string node-space* ')'
+fn type_rule() -> Rule {
+    tagged("type", || concat(vec![
+        write_literal("("),
+        repeat(node_space(), |_| 0, |c| c.extra_space_max),
+        capture(Capture::Type, string_rule()),
+        repeat(node_space(), |_| 0, |c| c.extra_space_max),
+        write_literal(")"),
+    ]))
+}
+
+// string := identifier-string | quoted-string | raw-string
+fn string_rule() -> Rule {
+    tagged("string", || select(vec![identifier_string(), quoted_string(), raw_string()]))
+}
+
+// identifier-string := unambiguous-ident | signed-ident | dotted-ident
+fn identifier_string() -> Rule {
+    tagged("identifier-string", || site(Category::KeywordIdentifier, not_keyword(KEYWORD_STRINGS, near_keywords(select(vec![
+        unambiguous_ident(),
+        signed_ident(),
+        dotted_ident(),
+    ]), near_keyword()))))
+}
+
+// disallowed-keyword-strings := 'true' | 'false' | 'null' | 'inf' | '-inf' | 'nan'
+const KEYWORD_STRINGS: &[&str] = &["true", "false", "null", "inf", "-inf", "nan"];
+
+// near-keyword := disallowed-keyword-strings identifier-char+ | (identifier-char - digit - sign - '.') identifier-char* disallowed-keyword-strings
+fn near_keyword() -> Rule {
+    production("near-keyword", || select(vec![
+        concat(vec![
+            select(KEYWORD_STRINGS.iter().map(|keyword| write_literal(keyword)).collect()),
+            repeat(identifier_char(), |_| 1, |c| c.identifier_len_max),
+        ]),
+        concat(vec![
+            identifier_char_minus_digit_sign_and_dot(),
+            repeat(identifier_char(), |_| 0, |c| c.identifier_len_max - 1),
+            select(KEYWORD_STRINGS.iter().map(|keyword| write_literal(keyword)).collect()),
+        ]),
+    ]))
+}
+
+// unambiguous-ident := ((identifier-char - digit - sign - '.') identifier-char*) - disallowed-keyword-strings
+fn unambiguous_ident() -> Rule {
+    production("unambiguous-ident", || concat(vec![
+        identifier_char_minus_digit_sign_and_dot(),
+        repeat(identifier_char(), |_| 0, |c| c.identifier_len_max - 1),
+    ]))
+}
+
+// signed-ident := sign ((identifier-char - digit - '.') identifier-char*)?
+fn signed_ident() -> Rule {
+    production("signed-ident", || concat(vec![
+        sign(),
+        maybe(concat(vec![
+            identifier_char_minus_digit_and_dot(),
+            repeat(identifier_char(), |_| 0, |c| c.identifier_len_max - 1),
+        ])),
+    ]))
+}
+
+// dotted-ident := sign? '.' ((identifier-char - digit) identifier-char*)?
+fn dotted_ident() -> Rule {
+    production("dotted-ident", || concat(vec![
+        maybe(sign()),
+        write_literal("."),
+        maybe(concat(vec![
+            identifier_char_minus_digit(),
+            repeat(identifier_char(), |_| 0, |c| c.identifier_len_max - 1),
+        ])),
+    ]))
+}
+
+// identifier-char := unicode - unicode-space - newline - [\\/(){};\[\]"#=] - disallowed-literal-code-points
+fn identifier_char() -> Rule {
+    pick_ascii_or_utf8(
+        write_rand_re("[-+.0-9A-Za-z]", |_| 1),
+        write_rand_re(concat!("[^\\\\/\\(\\){};\\[\\]\"#=",
+                              unicode_space_chars!(), newline_chars!(), disallowed_chars!(), "]"), |_| 1),
+    )
+}
+
+fn identifier_char_minus_digit() -> Rule {
+    pick_ascii_or_utf8(
+        write_rand_re("[-+.A-Za-z]", |_| 1),
+        write_rand_re(concat!("[^0-9\\\\/\\(\\){};\\[\\]\"#=",
+                              unicode_space_chars!(), newline_chars!(), disallowed_chars!(), "]"), |_| 1),
+    )
+}
+
+fn identifier_char_minus_digit_and_dot() -> Rule {
+    pick_ascii_or_utf8(
+        write_rand_re("[-+A-Za-z]", |_| 1),
+        write_rand_re(concat!("[^0-9.\\\\/\\(\\){};\\[\\]\"#=",
+                              unicode_space_chars!(), newline_chars!(), disallowed_chars!(), "]"), |_| 1),
+    )
+}
+
+fn identifier_char_minus_digit_sign_and_dot() -> Rule {
+    pick_ascii_or_utf8(
+        write_rand_re("[A-Za-z]", |_| 1),
+        write_rand_re(concat!("[^-+0-9.\\\\/\\(\\){};\\[\\]\"#=",
+                              unicode_space_chars!(), newline_chars!(), disallowed_chars!(), "]"), |_| 1),
+    )
+}
+
+// quoted-string := '"' single-line-string-body '"' | multi-line-string
+fn quoted_string() -> Rule {
+    production("quoted-string", || select(vec![
+        concat(vec![
+            write_literal("\""),
+            repeat(string_character(), |_| 0, |c| c.string_len_max),
+            write_literal("\""),
+        ]),
+        multi_line_string(),
+    ]))
+}
+
+// multi-line-string := '"""' newline (multi-line-string-body newline)? unicode-space* '"""'
+// Every body line starts with the same whitespace as the closing line so that
+// dedenting always succeeds.
+fn multi_line_string() -> Rule {
+    production("multi-line-string", || with_indent(concat(vec![
+        write_literal("\"\"\""),
+        newline(),
+        repeat(concat(vec![
+            write_indent(),
+            repeat(multi_line_string_character(), |_| 0, |c| c.string_len_max),
+            newline(),
+        ]), |_| 0, |_| MULTI_LINE_STRING_LINES_MAX),
+        write_indent(),
+        write_literal("\"\"\""),
+    ])))
+}
+
+// string-character := '\' escape | ws-escape | [^\\"] - newline - disallowed-literal-code-points
+fn string_character() -> Rule {
+    production("string-character", || select(vec![
+        concat(vec![
+            write_literal("\\"),
+            escape(),
+        ]),
+        ws_escape(),
+        pick_ascii_or_utf8(
+            write_rand_re("[a-zA-Z0-9 .,;!@\\#\\$%\\^&*()]", |_| 1),
+            write_rand_re(concat!("[^\\\\\"", newline_chars!(), disallowed_chars!(), "]"), |_| 1),
+        ),
+    ]))
+}
+
+// Within multi-line strings whitespace escapes are always followed by something
+// other than whitespace, so they can't swallow a newline and break dedenting.
+fn multi_line_string_character() -> Rule {
+    production("multi-line-string-character", || select(vec![
+        concat(vec![
+            write_literal("\\"),
+            escape(),
+        ]),
+        concat(vec![
+            write_literal("\\"),
+            repeat(unicode_space(), |_| 1, |c| c.extra_space_max),
+            pick_ascii_or_utf8(
+                write_rand_re("[a-zA-Z0-9.,;!@\\#\\$%\\^&*()]", |_| 1),
+                write_rand_re(concat!("[^\\\\\"", unicode_space_chars!(), newline_chars!(), disallowed_chars!(), "]"), |_| 1),
+            ),
+        ]),
+        pick_ascii_or_utf8(
+            write_rand_re("[a-zA-Z0-9 .,;!@\\#\\$%\\^&*()]", |_| 1),
+            write_rand_re(concat!("[^\\\\\"", newline_chars!(), disallowed_chars!(), "]"), |_| 1),
+        ),
+    ]))
+}
+
+// ws-escape := '\' (unicode-space | newline)+
+fn ws_escape() -> Rule {
+    production("ws-escape", || concat(vec![
+        write_literal("\\"),
+        repeat(select(vec![unicode_space(), newline()]), |_| 1, |c| c.extra_space_max),
+    ]))
+}
+
+// escape := ["\\bfnrts] | 'u{' hex-unicode '}'
+fn escape() -> Rule {
+    production("escape", || select(vec![
+        write_rand_re("[\"\\\\bfnrts]", |_| 1),
+        concat(vec![
+            write_literal("u{"),
+            write_rand_unicode_hex(),
+            write_literal("}"),
+        ]),
+    ]))
+}
+
+// raw-string := '#' raw-string-quotes '#' | '#' raw-string '#'
+fn raw_string() -> Rule {
+    production("raw-string", || with_hashes(1, raw_string_quotes()))
+}
+
+// raw-string-quotes := '"' single-line-raw-string-body '"' | multi-line-raw-string
+// Bodies only contain '"' as near misses, which are always followed by a '#',
+// so a single-line body never starts with '""'.
+fn raw_string_quotes() -> Rule {
+    production("raw-string-quotes", || select(vec![
+        concat(vec![
+            write_literal("\""),
+            site(Category::RawStringTerminator, raw_string_body(|c| c.string_len_max)),
+            write_literal("\""),
+        ]),
+        multi_line_raw_string(),
+    ]))
+}
+
+// multi-line-raw-string := '"""' newline (multi-line-raw-string-body newline)? unicode-space* '"""'
+fn multi_line_raw_string() -> Rule {
+    production("multi-line-raw-string", || with_indent(concat(vec![
+        write_literal("\"\"\""),
+        newline(),
+        repeat(concat(vec![
+            write_indent(),
+            raw_string_body(|c| c.string_len_max),
+            newline(),
+        ]), |_| 0, |_| MULTI_LINE_STRING_LINES_MAX),
+        write_indent(),
+        write_literal("\"\"\""),
+    ])))
+}
+
+fn raw_string_body(len_max: Limit) -> Rule {
+    near_misses(pick_ascii_or_utf8(
+        write_rand_re("\\w*", len_max),
+        write_rand_re(concat!("[^\"", newline_chars!(), disallowed_chars!(), "]*"), len_max),
+    ))
+}
+
+// number := keyword-number | hex | octal | binary | decimal
+fn number() -> Rule {
+    tagged("number", || select(vec![keyword_number(), decimal(), hex(), octal(), binary()]))
+}
+
+// keyword-number := '#inf' | '#-inf' | '#nan'
+fn keyword_number() -> Rule {
+    production("keyword-number", || select(vec![
+        write_literal("#inf"),
+        write_literal("#-inf"),
+        write_literal("#nan"),
+    ]))
+}
+
+// keyword := boolean | '#null'
+// boolean := '#true' | '#false'
+fn keyword() -> Rule {
+    tagged("keyword", || select(vec![
+        write_literal("#true"),
+        write_literal("#false"),
+        write_literal("#null"),
+    ]))
+}
+
+// escline := '\\' ws* (single-line-comment | newline | eof)
+fn escline() -> Rule {
+    tagged("escline", || concat(vec![
+        write_literal("\\"),
+        repeat(ws(), |_| 0, |c| c.extra_space_max),
+        select(vec![single_line_comment(), newline()]),
+    ]))
+}
+
+// line-space := node-space | newline | single-line-comment
+fn line_space() -> Rule {
+    tagged("line-space", || select(vec![node_space(), newline(), single_line_comment()]))
+}
+
+// node-space := ws* escline ws* | ws+
+fn node_space() -> Rule {
+    tagged("node-space", || select(vec![
+        concat(vec![
+            repeat(ws(), |_| 0, |c| c.extra_space_max),
+            escline(),
+            repeat(ws(), |_| 0, |c| c.extra_space_max),
+        ]),
+        repeat(ws(), |_| 1, |c| c.extra_space_max),
+    ]))
+}
+
+const NEWLINES: [&str; 8] = [
+    "\u{000D}", "\u{000A}", "\u{000D}\u{000A}", "\u{0085}", "\u{000B}", "\u{000C}", "\u{2028}", "\u{2029}",
+];
+
+// newline := See Table (All Newline White_Space)
+fn newline() -> Rule {
+    pick_ascii_or_utf8(
+        write_newline(&NEWLINES[..3]),
+        write_newline(&NEWLINES),
+    )
+}
+
+// ws := unicode-space | multi-line-comment
+fn ws() -> Rule {
+    tagged("ws", || select(vec![unicode_space(), multi_line_comment()]))
+}
+
+// single-line-comment := '//' ^newline* (newline | eof)
+fn single_line_comment() -> Rule {
+    tagged("single-line-comment", || concat(vec![
+        write_literal("//"),
+        pick_ascii_or_utf8(
+            write_rand_re("\\w*", |c| c.comment_len_max),
+            write_rand_re(concat!("[^", newline_chars!(), disallowed_chars!(), "]*"), |c| c.comment_len_max),
+        ),
+        newline(),
+    ]))
+}
+
+// multi-line-comment := '/*' commented-block
+fn multi_line_comment() -> Rule {
+    tagged("multi-line-comment", || concat(vec![
+        write_literal("/*"),
+        commented_block(),
+    ]))
+}
+
+// commented-block := '*/' | (multi-line-comment | '*' | '/' | [^*/]+) commented-block
+// A lone '*' or '/' is never followed by the other, which would close the
+// comment early or open a nested one.
+fn commented_block() -> Rule {
+    production("commented-block", || select(vec![
+        write_literal("*/"),
+        concat(vec![
+            pick_ascii_or_utf8(
+                select(vec![
+                    write_rand_re("\\*\\w", |_| 1),
+                    write_rand_re("/\\w", |_| 1),
+                    write_rand_re("\\w+", |c| c.comment_len_max),
+                    nested_comment(multi_line_comment()),
+                ]),
+                select(vec![
+                    write_rand_re(concat!("\\*[^*/", disallowed_chars!(), "]"), |_| 1),
+                    write_rand_re(concat!("/[^*/", disallowed_chars!(), "]"), |_| 1),
+                    write_rand_re(concat!("[^*/", disallowed_chars!(), "]+"), |c| c.comment_len_max),
+                    nested_comment(multi_line_comment()),
+                ]),
+            ),
+            commented_block(),
+        ]),
+    ]))
+}
@@ -0,0 +1,424 @@
+//! Writing a document which means the same as a model, but looks different.
+//!
+//! What the model holds is written in one of the forms the spec allows for
+//! it: strings bare, quoted with any of their characters escaped, raw, or over
+//! several lines, integers in any radix with any sign, leading zeros and
+//! underscores they can have, and props in any order among the args. What
+//! doesn't change the meaning, the whitespace, line continuations, comments
+//! and slashdashed junk between the parts, is written by the grammar's own
+//! productions.
+
+use super::*;
+use crate::model::{Document, Entry, Node, Value};
+
+// How often slashdashed junk is written wherever it can be
+const JUNK: f64 = 0.2;
+
+pub(super) fn document<T: Write, R: Rng>(ctx: &mut Context<T, R>, doc: &Document) -> io::Result<()> {
+    nodes(ctx, &doc.nodes, 1)
+}
+
+// nodes, each after any line-space and junk nodes, at the given depth. The
+// nodes whose children blocks are open are kept on a stack rather than
+// recursed into, so a model of any depth can be written.
+fn nodes<T: Write, R: Rng>(ctx: &mut Context<T, R>, nodes: &[Node], depth: u32) -> io::Result<()> {
+    let mut stack = vec![(nodes.iter(), depth)];
+    while let Some((siblings, depth)) = stack.last_mut() {
+        let depth = *depth;
+        match siblings.next() {
+            Some(node) => {
+                line_space(ctx, depth)?;
+                match node_head(ctx, node, depth)? {
+                    true => stack.push((node.children.iter(), depth + 1)),
+                    false => node_end(ctx, depth)?,
+                }
+            }
+            None => {
+                line_space(ctx, depth)?;
+                stack.pop();
+                if !stack.is_empty() {
+                    ctx.write_all(b"}")?;
+                    node_end(ctx, depth - 1)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// A node up to its children, opening a children block if it has one to
+// write. Returns whether it did.
+fn node_head<T: Write, R: Rng>(ctx: &mut Context<T, R>, node: &Node, depth: u32) -> io::Result<bool> {
+    let v2 = ctx.conf.spec_version > 1;
+    if let Some(ty) = &node.ty {
+        type_annotation(ctx, ty)?;
+        if v2 {
+            spaces(ctx, "node-space", 0)?;
+        }
+    }
+    string(ctx, &node.name, true)?;
+
+    // Props can go anywhere among the args, in any order
+    let mut entries: Vec<(Option<&str>, &Entry)> = node.args.iter().map(|arg| (None, arg)).collect();
+    let mut props: Vec<_> = node.props.iter().collect();
+    while !props.is_empty() {
+        let (key, prop) = props.remove(ctx.choose_index(props.len()));
+        let at = ctx.choose_index(entries.len() + 1);
+        entries.insert(at, (Some(key.as_str()), prop));
+    }
+    for (key, entry) in entries {
+        let name = if ctx.maybe(0.5) { "value" } else { "prop" };
+        junk(ctx, name, depth)?;
+        spaces(ctx, "node-space", 1)?;
+        if let Some(key) = key {
+            string(ctx, key, true)?;
+            if v2 {
+                spaces(ctx, "node-space", 0)?;
+            }
+            ctx.write_all(b"=")?;
+            if v2 {
+                spaces(ctx, "node-space", 0)?;
+            }
+        }
+        value(ctx, entry)?;
+    }
+
+    // v1 allows only one children block, slashdashed or not, where v2 allows
+    // slashdashed ones before the one that counts
+    let slashdashed = (v2 || node.children.is_empty()) && junk(ctx, "node-children", depth)?;
+    if !node.children.is_empty() || ((v2 || !slashdashed) && ctx.maybe(JUNK)) {
+        spaces(ctx, "node-space", if v2 { 1 } else { 0 })?;
+        ctx.write_all(b"{")?;
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+// What follows a node's children block, or the rest of the node if it has none
+fn node_end<T: Write, R: Rng>(ctx: &mut Context<T, R>, depth: u32) -> io::Result<()> {
+    spaces(ctx, "node-space", 0)?;
+    production(ctx, "node-terminator", depth)
+}
+
+// line-space, between nodes and around them, with some of it junk nodes
+fn line_space<T: Write, R: Rng>(ctx: &mut Context<T, R>, depth: u32) -> io::Result<()> {
+    let name = match ctx.conf.spec_version {
+        1 => "linespace",
+        _ => "line-space",
+    };
+    for _ in 0..ctx.range(0..=ctx.conf.blank_lines_max) {
+        match ctx.maybe(JUNK) {
+            true => junk_node(ctx, depth)?,
+            false => production(ctx, name, depth)?,
+        }
+    }
+    Ok(())
+}
+
+// A node from the grammar, slashdashed unless it already is
+fn junk_node<T: Write, R: Rng>(ctx: &mut Context<T, R>, depth: u32) -> io::Result<()> {
+    let node = generated(ctx, "node", depth)?;
+    if !node.starts_with(b"/-") {
+        ctx.write_all(b"/-")?;
+    }
+    ctx.write_all(&node)
+}
+
+// Sometimes, the named production slashdashed after node-space, where it
+// leaves the node meaning the same. Returns whether it was written.
+fn junk<T: Write, R: Rng>(ctx: &mut Context<T, R>, name: &str, depth: u32) -> io::Result<bool> {
+    if !ctx.maybe(JUNK) {
+        return Ok(false);
+    }
+    spaces(ctx, "node-space", 1)?;
+    match ctx.conf.spec_version {
+        1 => {
+            ctx.write_all(b"/-")?;
+            spaces(ctx, "node-space", 0)?;
+        }
+        _ => production(ctx, "slashdash", depth)?,
+    }
+    // v1 node-children can be slashdashed already
+    let junk = generated(ctx, name, depth)?;
+    match junk.starts_with(b"/-") {
+        true => ctx.write_all(b"{}")?,
+        false => ctx.write_all(&junk)?,
+    }
+    Ok(true)
+}
+
+fn production<T: Write, R: Rng>(ctx: &mut Context<T, R>, name: &str, depth: u32) -> io::Result<()> {
+    ctx.depth = depth;
+    engine::grammar(ctx.conf.spec_version).generate_production(name, ctx)?;
+    Ok(())
+}
+
+// The named production, written apart so that it can be looked at first. The
+// engine holds output back itself, so it can't be held here.
+fn generated<T: Write, R: Rng>(ctx: &mut Context<T, R>, name: &str, depth: u32) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    let apart = &mut Context::new(ctx.conf, ctx.weights, &mut out, &mut *ctx.rng);
+    apart.last_byte = ctx.last_byte;
+    production(apart, name, depth)?;
+    Ok(out)
+}
+
+// At least min of the named production, and up to extra_space_max more
+fn spaces<T: Write, R: Rng>(ctx: &mut Context<T, R>, name: &str, min: u32) -> io::Result<()> {
+    for _ in 0..ctx.range(min..=min + ctx.conf.extra_space_max) {
+        production(ctx, name, ctx.depth)?;
+    }
+    Ok(())
+}
+
+// type := '(' identifier ')', or in v2, '(' node-space* string node-space* ')'
+fn type_annotation<T: Write, R: Rng>(ctx: &mut Context<T, R>, ty: &str) -> io::Result<()> {
+    let v2 = ctx.conf.spec_version > 1;
+    ctx.write_all(b"(")?;
+    if v2 {
+        spaces(ctx, "node-space", 0)?;
+    }
+    string(ctx, ty, true)?;
+    if v2 {
+        spaces(ctx, "node-space", 0)?;
+    }
+    ctx.write_all(b")")
+}
+
+fn value<T: Write, R: Rng>(ctx: &mut Context<T, R>, entry: &Entry) -> io::Result<()> {
+    let v2 = ctx.conf.spec_version > 1;
+    if let Some(ty) = &entry.ty {
+        type_annotation(ctx, ty)?;
+        if v2 {
+            spaces(ctx, "node-space", 0)?;
+        }
+    }
+    let keyword = |name: &str| match v2 {
+        true => format!("#{}", name),
+        false => name.to_string(),
+    };
+    match &entry.value {
+        // v1 values can't be bare
+        Value::String(s) => string(ctx, s, v2),
+        Value::Number(n) => number(ctx, n),
+        Value::NonFinite(name) => ctx.write_all(keyword(name).as_bytes()),
+        Value::Bool(b) => ctx.write_all(keyword(&b.to_string()).as_bytes()),
+        Value::Null => ctx.write_all(keyword("null").as_bytes()),
+    }
+}
+
+// The ways a string can be written
+#[derive(Clone, Copy)]
+enum Form {
+    Bare,
+    Quoted,
+    Raw,
+    MultiLine,
+}
+
+fn string<T: Write, R: Rng>(ctx: &mut Context<T, R>, s: &str, bare: bool) -> io::Result<()> {
+    let v2 = ctx.conf.spec_version > 1;
+    let mut forms = vec![Form::Quoted];
+    if bare && model::is_bare_identifier(s, ctx.conf.spec_version) {
+        forms.push(Form::Bare);
+    }
+    // v2 raw strings can't hold a newline or a disallowed code point, and one
+    // which starts with '"' would start with '"""' when it is only '"'
+    if !v2 || !(s.starts_with('"') || s.chars().any(|c| parse::is_newline(c, 2) || parse::is_disallowed(c))) {
+        forms.push(Form::Raw);
+    }
+    if v2 {
+        forms.push(Form::MultiLine);
+    }
+    let text = match forms[ctx.choose_index(forms.len())] {
+        Form::Bare => s.to_string(),
+        Form::Quoted => return quoted(ctx, s),
+        Form::Raw => {
+            // More hashes than follow any '"' in the string, and at least one in v2
+            let mut hashes = s.match_indices('"')
+                .map(|(i, _)| s[i + 1..].len() - s[i + 1..].trim_start_matches('#').len() + 1)
+                .max()
+                .unwrap_or(0)
+                .max(v2 as usize);
+            hashes += ctx.range(0..=2) as usize;
+            let hashes = "#".repeat(hashes);
+            match v2 {
+                true => format!("{}\"{}\"{}", hashes, s, hashes),
+                false => format!("r{}\"{}\"{}", hashes, s, hashes),
+            }
+        }
+        Form::MultiLine => return multi_line(ctx, s),
+    };
+    ctx.write_all(text.as_bytes())
+}
+
+// A quoted string with any of its characters escaped, and in v2, whitespace
+// escapes before any which isn't whitespace written as it is
+fn quoted<T: Write, R: Rng>(ctx: &mut Context<T, R>, s: &str) -> io::Result<()> {
+    let v2 = ctx.conf.spec_version > 1;
+    ctx.write_all(b"\"")?;
+    for c in s.chars() {
+        let text = character(ctx, c, must_escape(c, v2) || c == '"');
+        if v2 && (text.starts_with('\\') || !model_whitespace(c)) && ctx.maybe(JUNK) {
+            production(ctx, "ws-escape", ctx.depth)?;
+        }
+        ctx.write_all(text.as_bytes())?;
+    }
+    if v2 && ctx.maybe(JUNK) {
+        production(ctx, "ws-escape", ctx.depth)?;
+    }
+    ctx.write_all(b"\"")
+}
+
+// A multi-line string, with every line indented like the closing quotes. A
+// line of only whitespace would be read as empty, so its first character is
+// escaped.
+fn multi_line<T: Write, R: Rng>(ctx: &mut Context<T, R>, s: &str) -> io::Result<()> {
+    let indent = rand_indent(ctx);
+    ctx.write_all(b"\"\"\"\n")?;
+    if !s.is_empty() {
+        for line in s.split('\n') {
+            let blank = line.chars().all(model_whitespace);
+            if !line.is_empty() {
+                ctx.write_all(indent.as_bytes())?;
+            }
+            for (i, c) in line.chars().enumerate() {
+                let text = character(ctx, c, must_escape(c, true) || c == '"' || (blank && i == 0));
+                ctx.write_all(text.as_bytes())?;
+            }
+            ctx.write_all(b"\n")?;
+        }
+    }
+    ctx.write_all(indent.as_bytes())?;
+    ctx.write_all(b"\"\"\"")
+}
+
+// A character as it is, or escaped, which it has to be if must is set
+fn character<T: Write, R: Rng>(ctx: &mut Context<T, R>, c: char, must: bool) -> String {
+    if !must && !ctx.maybe(JUNK) {
+        return c.to_string();
+    }
+    let named = match c {
+        '"' => Some('"'),
+        '\\' => Some('\\'),
+        '\n' => Some('n'),
+        '\r' => Some('r'),
+        '\t' => Some('t'),
+        '\u{8}' => Some('b'),
+        '\u{c}' => Some('f'),
+        '/' if ctx.conf.spec_version == 1 => Some('/'),
+        ' ' if ctx.conf.spec_version > 1 => Some('s'),
+        _ => None,
+    };
+    match named {
+        Some(named) if ctx.maybe(0.5) => format!("\\{}", named),
+        _ => {
+            let hex = format!("{:x}", c as u32);
+            let hex = "0".repeat(ctx.range(0..=6 - hex.len() as u32) as usize) + &hex;
+            format!("\\u{{{}}}", if ctx.maybe(0.5) { hex.to_uppercase() } else { hex })
+        }
+    }
+}
+
+// Whether a character can't be written as it is in a quoted string, other than '"'
+fn must_escape(c: char, v2: bool) -> bool {
+    c == '\\' || (v2 && (parse::is_newline(c, 2) || parse::is_disallowed(c)))
+}
+
+// Whether a character would be taken up by a whitespace escape before it
+fn model_whitespace(c: char) -> bool {
+    parse::is_unicode_space(c) || parse::is_newline(c, 2)
+}
+
+// A number from decode_number, in decimal with leading zeros, underscores
+// and an explicit sign where it has none, or if it is an integer, in hex,
+// octal or binary. Negative zero is only negative in decimal.
+fn number<T: Write, R: Rng>(ctx: &mut Context<T, R>, n: &str) -> io::Result<()> {
+    let (sign, unsigned) = match n.strip_prefix('-') {
+        Some(unsigned) => ("-", unsigned),
+        None if ctx.maybe(0.5) => ("+", n),
+        None => ("", n),
+    };
+    let integer = unsigned.chars().all(|c| c.is_ascii_digit());
+    let radix = match ctx.choose_index(4) {
+        1 if integer && n != "-0" => 16,
+        2 if integer && n != "-0" => 8,
+        3 if integer && n != "-0" => 2,
+        _ => 10,
+    };
+    let mut text = sign.to_string();
+    if radix != 10 {
+        text.push_str(match radix { 16 => "0x", 8 => "0o", _ => "0b" });
+        let digits = from_decimal(unsigned, radix);
+        let digits = match radix == 16 && ctx.maybe(0.5) {
+            true => digits.to_uppercase(),
+            false => digits,
+        };
+        let zeros = "0".repeat(ctx.range(0..=2) as usize);
+        text.push_str(&underscored(ctx, &(zeros + &digits)));
+        return ctx.write_all(text.as_bytes());
+    }
+    let (mantissa, exponent) = match unsigned.split_once('e') {
+        Some((mantissa, exponent)) => (mantissa, Some(exponent)),
+        None => (unsigned, None),
+    };
+    let (whole, fraction) = match mantissa.split_once('.') {
+        Some((whole, fraction)) => (whole, Some(fraction)),
+        None => (mantissa, None),
+    };
+    let zeros = "0".repeat(ctx.range(0..=2) as usize);
+    text.push_str(&underscored(ctx, &(zeros + whole)));
+    if let Some(fraction) = fraction {
+        text.push('.');
+        text.push_str(&underscored(ctx, fraction));
+    }
+    if let Some(exponent) = exponent {
+        text.push(if ctx.maybe(0.5) { 'E' } else { 'e' });
+        let exponent = match exponent.strip_prefix('-') {
+            Some(digits) => {
+                text.push('-');
+                digits
+            }
+            None => {
+                if ctx.maybe(0.5) {
+                    text.push('+');
+                }
+                exponent
+            }
+        };
+        text.push_str(&underscored(ctx, exponent));
+    }
+    ctx.write_all(text.as_bytes())
+}
+
+// Digits with underscores after some of them
+fn underscored<T: Write, R: Rng>(ctx: &mut Context<T, R>, digits: &str) -> String {
+    let mut out = String::new();
+    for c in digits.chars() {
+        out.push(c);
+        if ctx.maybe(JUNK / 2.0) {
+            out.push('_');
+        }
+    }
+    out
+}
+
+// Decimal digits in another radix, however many of them there are
+fn from_decimal(digits: &str, radix: u32) -> String {
+    let mut decimal: Vec<u32> = digits.chars().map(|c| c.to_digit(10).unwrap()).collect();
+    let mut out = Vec::new();
+    while decimal.iter().any(|&d| d > 0) {
+        // Long division of the decimal digits by the radix
+        let mut remainder = 0;
+        for d in decimal.iter_mut() {
+            let v = remainder * 10 + *d;
+            *d = v / radix;
+            remainder = v % radix;
+        }
+        out.push(char::from_digit(remainder, radix).unwrap());
+    }
+    if out.is_empty() {
+        out.push('0');
+    }
+    out.iter().rev().collect()
+}
@@ -0,0 +1,146 @@
+use std::io;
+use std::io::Write;
+use rand::{Rng, RngCore, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use crate::config::GeneratorConfig;
+use crate::corrupt::Corruption;
+use crate::coverage::Coverage;
+use crate::fuzz::ByteRng;
+use crate::gen::{self, TapeMode};
+use crate::model::Document;
+use crate::tape::Tape;
+use crate::trace::Trace;
+
+/// Generates random KDL documents according to a GeneratorConfig.
+///
+/// ```
+/// use kdl_gen::{Generator, GeneratorConfig};
+///
+/// let generator = Generator::new(GeneratorConfig::default().spec_version(2).depth_max(2)).unwrap();
+/// let doc = generator.generate_string(42);
+/// assert_eq!(doc, generator.generate_string(42));
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Generator {
+    conf: GeneratorConfig,
+}
+
+impl Generator {
+    /// A generator for the configuration, or an InvalidInput error if the
+    /// configuration asks for a spec version other than 1 or 2.
+    pub fn new(conf: GeneratorConfig) -> io::Result<Generator> {
+        check(&conf)?;
+        Ok(Generator { conf })
+    }
+
+    pub fn config(&self) -> &GeneratorConfig {
+        &self.conf
+    }
+
+    /// The rng a seed maps to. The same seed always gives the same document
+    /// for the same configuration.
+    pub fn rng_for_seed(seed: u64) -> ChaCha8Rng {
+        ChaCha8Rng::seed_from_u64(seed)
+    }
+
+    /// The seed of the document at `index` in a batch generated from `seed`.
+    /// Each index draws from its own stream of the seed's rng, so a document
+    /// doesn't depend on any other in the batch.
+    pub fn derive_seed(seed: u64, index: u64) -> u64 {
+        let mut rng = Self::rng_for_seed(seed);
+        rng.set_stream(index);
+        rng.next_u64()
+    }
+
+    /// Write the document for the given seed, returning the number of bytes written.
+    pub fn generate_to_writer<W: Write>(&self, out: &mut W, seed: u64) -> io::Result<usize> {
+        self.generate_with_rng(out, &mut Self::rng_for_seed(seed))
+    }
+
+    /// The document for the given seed.
+    pub fn generate_string(&self, seed: u64) -> String {
+        let mut out = Vec::new();
+        self.generate_to_writer(&mut out, seed).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    /// Write a document using decisions drawn from the given rng.
+    pub fn generate_with_rng<W: Write, R: Rng>(&self, out: &mut W, rng: &mut R) -> io::Result<usize> {
+        gen::document(out, rng, &self.conf, None)
+    }
+
+    /// The document for a fuzzer's input. Every decision is read from the
+    /// bytes, so small changes to them make small changes to the document.
+    pub fn generate_from_bytes(&self, bytes: &[u8]) -> String {
+        let mut out = Vec::new();
+        self.generate_with_rng(&mut out, &mut ByteRng::new(bytes)).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    /// Write a document, adding the productions and alternatives it used to
+    /// `coverage`. Directed coverage steers it toward the ones not used yet.
+    pub fn generate_with_coverage<W: Write, R: Rng>(&self, out: &mut W, rng: &mut R, coverage: &mut Coverage) -> io::Result<usize> {
+        gen::document_with_coverage(out, rng, &self.conf, coverage)
+    }
+
+    /// Write a document, and return a tape of every decision made for it.
+    pub fn generate_recording<W: Write, R: Rng>(&self, out: &mut W, rng: &mut R) -> io::Result<Tape> {
+        let mut tape = Tape::new(&self.conf);
+        gen::document(out, rng, &self.conf, Some(TapeMode::Record(&mut tape.decisions)))?;
+        Ok(tape)
+    }
+
+    /// Write the document recorded on a tape. The tape's header holds every
+    /// option it was recorded with, and those are used instead of the
+    /// generator's own.
+    pub fn replay<W: Write>(&self, out: &mut W, tape: &Tape) -> io::Result<usize> {
+        check(&tape.conf)?;
+        gen::document(out, &mut Self::rng_for_seed(0), &tape.conf, Some(TapeMode::Replay(&tape.decisions)))
+    }
+
+    /// Write a document, and return the span each production with a
+    /// `--debug` tag wrote, without writing the tags.
+    pub fn generate_with_trace<W: Write, R: Rng>(&self, out: &mut W, rng: &mut R) -> io::Result<Trace> {
+        gen::document_with_trace(out, rng, &self.conf)
+    }
+
+    /// Write a document, adding what it used to `coverage` if there is one,
+    /// and check it with the crate's own parser, returning a model of it like
+    /// `generate_with_model`. A rejected document is still written, and the
+    /// error is of kind InvalidData, wrapping a
+    /// [`Rejection`](crate::verify::Rejection) which says where and why.
+    /// `--debug` documents aren't KDL, so they are always rejected.
+    pub fn generate_verified<W: Write, R: Rng>(&self, out: &mut W, rng: &mut R, coverage: Option<&mut Coverage>) -> io::Result<Document> {
+        gen::verified_document(out, rng, &self.conf, coverage)
+    }
+
+    /// Write a document, and return a model of what a parser should make of it.
+    pub fn generate_with_model<W: Write, R: Rng>(&self, out: &mut W, rng: &mut R) -> io::Result<Document> {
+        gen::document_and_model(out, rng, &self.conf)
+    }
+
+    /// Write a document which means the same as `model`, a model of a
+    /// document from this generator, but is written differently: strings,
+    /// numbers and props in other equivalent forms and orders, and fresh
+    /// whitespace, comments and slashdashed junk around them. The variant is
+    /// checked with the crate's own parser, and one which doesn't mean the
+    /// same is an InvalidData error instead.
+    pub fn generate_variant<W: Write, R: Rng>(&self, out: &mut W, rng: &mut R, model: &Document) -> io::Result<usize> {
+        gen::variant_document(out, rng, &self.conf, model)
+    }
+
+    /// Write a document which has been broken by a single corruption, and
+    /// return what was corrupted.
+    pub fn generate_invalid<W: Write, R: Rng>(&self, out: &mut W, rng: &mut R) -> io::Result<Corruption> {
+        gen::invalid_document(out, rng, &self.conf)
+    }
+}
+
+// The grammar is only written for the spec versions there are
+fn check(conf: &GeneratorConfig) -> io::Result<()> {
+    match conf.spec_version {
+        1 | 2 => Ok(()),
+        version => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("there is no KDL spec version {}", version))),
+    }
+}
@@ -0,0 +1,419 @@
+use std::fmt::Write;
+
+/// A minimal JSON value, used for the various side files the generator writes.
+/// Numbers are kept as their textual representation so that arbitrarily large
+/// KDL integers survive unchanged.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn object<K: Into<String>>(fields: Vec<(K, Json)>) -> Json {
+        Json::Object(fields.into_iter().map(|(k, v)| (k.into(), v)).collect())
+    }
+
+    /// Parse a JSON text, keeping numbers as they are written. Returns None
+    /// if it isn't JSON.
+    pub fn parse(text: &str) -> Option<Json> {
+        let mut parser = Parser { text, pos: 0 };
+        let value = parser.value()?;
+        parser.skip_space();
+        (parser.pos == text.len()).then_some(value)
+    }
+
+    /// The value with the fields of every object sorted by key, and every
+    /// number in the same exact form, so that two serializations of the same
+    /// value can be compared.
+    pub fn normalized(&self) -> Json {
+        let Some(root) = self.items() else {
+            return self.normalized_scalar();
+        };
+        // Built from a stack of the arrays and objects being normalized, each
+        // with its key, the items it has left and the ones already normalized,
+        // rather than recursively
+        let mut stack = vec![(String::new(), root, Vec::new())];
+        loop {
+            let (_, left, done) = stack.last_mut().unwrap();
+            if let Some((key, item)) = left.next() {
+                let key = key.cloned().unwrap_or_default();
+                match item.items() {
+                    Some(items) => stack.push((key, items, Vec::new())),
+                    None => done.push((key, item.normalized_scalar())),
+                }
+                continue;
+            }
+            let (key, left, mut done) = stack.pop().unwrap();
+            let value = match left {
+                Items::Array(_) => Json::Array(done.into_iter().map(|(_, value)| value).collect()),
+                Items::Object(_) => {
+                    done.sort_by(|(a, _), (b, _)| a.cmp(b));
+                    Json::Object(done)
+                }
+            };
+            match stack.last_mut() {
+                Some((_, _, siblings)) => siblings.push((key, value)),
+                None => return value,
+            }
+        }
+    }
+
+    fn normalized_scalar(&self) -> Json {
+        match self {
+            Json::Number(n) => Json::Number(exact_number(n)),
+            value => value.clone(),
+        }
+    }
+
+    // The items of an array or object
+    fn items(&self) -> Option<Items<'_>> {
+        match self {
+            Json::Array(items) => Some(Items::Array(items.iter())),
+            Json::Object(fields) => Some(Items::Object(fields.iter())),
+            _ => None,
+        }
+    }
+
+    pub fn pretty(&self) -> String {
+        let mut out = String::new();
+        self.write(&mut out, true);
+        out.push('\n');
+        out
+    }
+
+    /// The value on one line, without any whitespace between tokens.
+    pub fn compact(&self) -> String {
+        let mut out = String::new();
+        self.write(&mut out, false);
+        out
+    }
+
+    // Writes from a stack of the arrays and objects being written, with the
+    // items each has left and whether it has written any yet, rather than
+    // recursively, so that values nested to any depth can be written
+    fn write(&self, out: &mut String, pretty: bool) {
+        let mut stack: Vec<(Items, bool)> = Vec::new();
+        let mut next = Some(self);
+        loop {
+            match next.take() {
+                Some(Json::Array(items)) if !items.is_empty() => {
+                    out.push('[');
+                    stack.push((Items::Array(items.iter()), false));
+                }
+                Some(Json::Object(fields)) if !fields.is_empty() => {
+                    out.push('{');
+                    stack.push((Items::Object(fields.iter()), false));
+                }
+                Some(value) => value.write_scalar(out),
+                None => {}
+            }
+            let indent = stack.len();
+            let Some((items, started)) = stack.last_mut() else {
+                return;
+            };
+            match items.next() {
+                Some((key, value)) => {
+                    if *started {
+                        out.push(',');
+                    }
+                    *started = true;
+                    if pretty {
+                        newline(out, indent);
+                    }
+                    if let Some(key) = key {
+                        write_string(out, key);
+                        out.push_str(if pretty { ": " } else { ":" });
+                    }
+                    next = Some(value);
+                }
+                None => {
+                    let close = match items {
+                        Items::Array(_) => ']',
+                        Items::Object(_) => '}',
+                    };
+                    stack.pop();
+                    if pretty {
+                        newline(out, indent - 1);
+                    }
+                    out.push(close);
+                }
+            }
+        }
+    }
+
+    // Anything but an array or object with items in it
+    fn write_scalar(&self, out: &mut String) {
+        match self {
+            Json::Null => out.push_str("null"),
+            Json::Bool(b) => write!(out, "{}", b).unwrap(),
+            Json::Number(n) => out.push_str(n),
+            Json::String(s) => write_string(out, s),
+            Json::Array(_) => out.push_str("[]"),
+            Json::Object(_) => out.push_str("{}"),
+        }
+    }
+}
+
+// Values are freed from a stack rather than recursively, so that dropping a
+// deeply nested one doesn't overflow the call stack.
+impl Drop for Json {
+    fn drop(&mut self) {
+        let mut values = match self {
+            Json::Array(items) => std::mem::take(items),
+            Json::Object(fields) => fields.drain(..).map(|(_, value)| value).collect(),
+            _ => return,
+        };
+        while let Some(mut value) = values.pop() {
+            match &mut value {
+                Json::Array(items) => values.append(items),
+                Json::Object(fields) => values.extend(fields.drain(..).map(|(_, value)| value)),
+                _ => {}
+            }
+        }
+    }
+}
+
+// The items of an array or object left to go through
+enum Items<'j> {
+    Array(std::slice::Iter<'j, Json>),
+    Object(std::slice::Iter<'j, (String, Json)>),
+}
+
+impl<'j> Iterator for Items<'j> {
+    // The next item, with its key if it is a field
+    type Item = (Option<&'j String>, &'j Json);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Items::Array(items) => items.next().map(|item| (None, item)),
+            Items::Object(fields) => fields.next().map(|(key, value)| (Some(key), value)),
+        }
+    }
+}
+
+// A number written the same way however it was written, without losing any
+// precision: its significant digits with a point after the first, and the
+// power of ten they are multiplied by, so that 100, 1e2 and 100.0 are all 1e2.
+// One with an exponent too large to work with is left as it is.
+fn exact_number(n: &str) -> String {
+    let (sign, unsigned) = match n.strip_prefix('-') {
+        Some(unsigned) => ("-", unsigned),
+        None => ("", n),
+    };
+    let (mantissa, exponent) = match unsigned.find(['e', 'E']) {
+        Some(i) => (&unsigned[..i], &unsigned[i + 1..]),
+        None => (unsigned, "0"),
+    };
+    let (integer, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    let digits = format!("{}{}", integer, fraction);
+    let Some(first) = digits.find(|c| c != '0') else {
+        return "0".to_string();
+    };
+    let significant = digits[first..].trim_end_matches('0');
+    let exponent = exponent.strip_prefix('+').unwrap_or(exponent).parse::<i64>().ok()
+        .and_then(|exponent| exponent.checked_add(integer.len() as i64 - 1 - first as i64));
+    let Some(exponent) = exponent else {
+        return n.to_string();
+    };
+    let mut out = format!("{}{}", sign, &significant[..1]);
+    if significant.len() > 1 {
+        out.push('.');
+        out.push_str(&significant[1..]);
+    }
+    if exponent != 0 {
+        write!(out, "e{}", exponent).unwrap();
+    }
+    out
+}
+
+fn newline(out: &mut String, indent: usize) {
+    out.push('\n');
+    for _ in 0..indent {
+        out.push_str("  ");
+    }
+}
+
+fn write_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\u{8}' => out.push_str("\\b"),
+            '\u{c}' => out.push_str("\\f"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+struct Parser<'t> {
+    text: &'t str,
+    pos: usize,
+}
+
+// An array or object still being parsed
+enum Open {
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>, String),
+}
+
+impl<'t> Parser<'t> {
+    fn skip_space(&mut self) {
+        let rest = &self.text[self.pos..];
+        self.pos += rest.len() - rest.trim_start_matches([' ', '\t', '\n', '\r']).len();
+    }
+
+    fn eat(&mut self, s: &str) -> bool {
+        self.skip_space();
+        let found = self.text[self.pos..].starts_with(s);
+        if found {
+            self.pos += s.len();
+        }
+        found
+    }
+
+    // Parses arrays and objects from a stack of the ones still open, each with
+    // the items parsed so far and, for an object, the key of the next one,
+    // rather than recursively, so that values nested to any depth can be read
+    fn value(&mut self) -> Option<Json> {
+        let mut stack: Vec<Open> = Vec::new();
+        loop {
+            self.skip_space();
+            let mut value = match self.text[self.pos..].chars().next()? {
+                '[' => {
+                    self.pos += 1;
+                    if !self.eat("]") {
+                        stack.push(Open::Array(Vec::new()));
+                        continue;
+                    }
+                    Json::Array(Vec::new())
+                }
+                '{' => {
+                    self.pos += 1;
+                    if !self.eat("}") {
+                        let key = self.key()?;
+                        stack.push(Open::Object(Vec::new(), key));
+                        continue;
+                    }
+                    Json::Object(Vec::new())
+                }
+                _ => self.scalar()?,
+            };
+            // Add the value to the array or object it is in, and close each one
+            // it completes
+            loop {
+                let Some(open) = stack.pop() else {
+                    return Some(value);
+                };
+                match open {
+                    Open::Array(mut items) => {
+                        items.push(value);
+                        if self.eat(",") {
+                            stack.push(Open::Array(items));
+                            break;
+                        }
+                        if !self.eat("]") {
+                            return None;
+                        }
+                        value = Json::Array(items);
+                    }
+                    Open::Object(mut fields, key) => {
+                        fields.push((key, value));
+                        if self.eat(",") {
+                            let key = self.key()?;
+                            stack.push(Open::Object(fields, key));
+                            break;
+                        }
+                        if !self.eat("}") {
+                            return None;
+                        }
+                        value = Json::Object(fields);
+                    }
+                }
+            }
+        }
+    }
+
+    // The key of a field, and the ':' after it
+    fn key(&mut self) -> Option<String> {
+        self.skip_space();
+        let key = self.string()?;
+        self.eat(":").then_some(key)
+    }
+
+    fn scalar(&mut self) -> Option<Json> {
+        let rest = &self.text[self.pos..];
+        match rest.chars().next()? {
+            '"' => self.string().map(Json::String),
+            _ if self.eat("null") => Some(Json::Null),
+            _ if self.eat("true") => Some(Json::Bool(true)),
+            _ if self.eat("false") => Some(Json::Bool(false)),
+            _ => {
+                let len = rest.find(|c: char| !matches!(c, '0'..='9' | '-' | '+' | '.' | 'e' | 'E')).unwrap_or(rest.len());
+                let number = &rest[..len];
+                let digits = number.strip_prefix('-').unwrap_or(number);
+                if !digits.starts_with(|c: char| c.is_ascii_digit()) || !number.parse::<f64>().is_ok_and(f64::is_finite) {
+                    return None;
+                }
+                self.pos += len;
+                Some(Json::Number(number.to_string()))
+            }
+        }
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let mut chars = self.text[self.pos..].strip_prefix('"')?.char_indices();
+        let mut out = String::new();
+        loop {
+            let (i, c) = chars.next()?;
+            match c {
+                '"' => {
+                    self.pos += i + 2;
+                    return Some(out);
+                }
+                '\\' => match chars.next()?.1 {
+                    'n' => out.push('\n'),
+                    'r' => out.push('\r'),
+                    't' => out.push('\t'),
+                    'b' => out.push('\u{8}'),
+                    'f' => out.push('\u{c}'),
+                    'u' => {
+                        let mut code = self.hex4(&mut chars)?;
+                        if (0xD800..0xDC00).contains(&code) {
+                            // The high half of a surrogate pair, whose low half follows
+                            if chars.next()?.1 != '\\' || chars.next()?.1 != 'u' {
+                                return None;
+                            }
+                            let low = self.hex4(&mut chars)?;
+                            if !(0xDC00..0xE000).contains(&low) {
+                                return None;
+                            }
+                            code = 0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00);
+                        }
+                        out.push(char::from_u32(code)?);
+                    }
+                    c => out.push(c),
+                },
+                c => out.push(c),
+            }
+        }
+    }
+
+    fn hex4(&self, chars: &mut std::str::CharIndices) -> Option<u32> {
+        let hex: String = chars.take(4).map(|(_, c)| c).collect();
+        match hex.len() {
+            4 => u32::from_str_radix(&hex, 16).ok(),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,27 @@
+//! A generator of random KDL documents, for fuzzing and testing KDL parsers.
+//!
+//! All documents generated should be valid KDL according to the specification
+//! at [kdl.dev](https://kdl.dev), unless one of the negative testing modes is used.
+
+mod config;
+mod corrupt;
+mod coverage;
+mod gen;
+mod generator;
+pub mod diff;
+pub mod fuzz;
+pub mod json;
+pub mod model;
+pub mod parse;
+pub mod shrink;
+pub mod tape;
+pub mod trace;
+pub mod verify;
+pub mod weights;
+
+pub use config::GeneratorConfig;
+pub use corrupt::{Category, Corruption};
+pub use coverage::Coverage;
+pub use generator::Generator;
+#[doc(hidden)]
+pub use gen::cache_regexes;
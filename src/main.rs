@@ -1,67 +1,604 @@
-use std::io::{BufWriter, Write};
-use std::process;
-use clap::Parser;
-use rand::{RngCore, SeedableRng, thread_rng};
+use std::error::Error;
+use std::fs;
+use std::io::{self, BufWriter, Read, Write};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::process::{self, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+use clap::{Parser, Subcommand};
+use rand::{RngCore, thread_rng};
 
-mod gen;
+use kdl_gen::{Coverage, Generator, GeneratorConfig};
+use kdl_gen::diff::{self, Outcome};
+use kdl_gen::json::Json;
+use kdl_gen::parse;
+use kdl_gen::model::Value;
+use kdl_gen::tape::Tape;
+use kdl_gen::verify::Rejection;
+use kdl_gen::weights::Weights;
 
-#[derive(Parser,Default,Debug)]
+#[derive(Parser,Debug)]
 #[clap(author="Hannah Kolbeck", version, about="A KDL Document Generator")]
+#[clap(args_conflicts_with_subcommands=true, args_override_self=true)]
 pub struct Configuration {
-    #[clap(default_value_t=3, short, long)]
-    pub depth_max: u32,
+    #[clap(subcommand)]
+    pub command: Option<Command>,
 
-    #[clap(default_value_t=3, short, long)]
-    pub nodes_per_child_max: u32,
+    #[clap(flatten)]
+    pub gen: GeneratorConfig,
 
-    #[clap(default_value_t=3, short, long)]
-    pub extra_space_max: u32,
+    #[clap(long="seed")]
+    pub rand_seed: Option<u64>,
 
-    #[clap(default_value_t=10, short, long)]
-    pub props_or_args_max: u32,
+    /// Read options and choice weights from CONFIG_FILE, a KDL document.
+    /// Options given on the command line take precedence
+    #[clap(long, value_name="CONFIG_FILE")]
+    pub config: Option<PathBuf>,
 
-    #[clap(default_value_t=1, short, long)]
-    pub blank_lines_max: u32,
+    /// Print a config file with the default weight of every choice in the
+    /// grammar, then exit
+    #[clap(long)]
+    pub print_weights: bool,
 
-    #[clap(default_value_t=20, short, long)]
-    pub identifier_len_max: u32,
+    /// Break the generated document with a single corruption, and write its
+    /// category and byte offset to ERROR_FILE as JSON
+    #[clap(long, value_name="ERROR_FILE")]
+    pub invalid: Option<PathBuf>,
 
-    #[clap(default_value_t=100, short, long)]
-    pub string_len_max: u32,
+    /// Write the parse tree a parser is expected to produce for the document
+    /// to JSON_FILE
+    #[clap(long, value_name="JSON_FILE", conflicts_with="invalid")]
+    pub expected_json: Option<PathBuf>,
 
-    #[clap(default_value_t=10, short='l', long)]
-    pub num_len_max: u32,
+    /// Write the document in the canonical form of the KDL test suite's
+    /// expected_kdl files to KDL_FILE
+    #[clap(long, value_name="KDL_FILE", conflicts_with="invalid")]
+    pub expected_kdl: Option<PathBuf>,
 
-    #[clap(default_value_t=100, short, long)]
-    pub comment_len_max: u32,
+    /// Record every decision made while generating the document to TAPE_FILE
+    #[clap(long, value_name="TAPE_FILE", conflicts_with_all=["invalid", "expected_json", "expected_kdl"])]
+    pub record_tape: Option<PathBuf>,
 
-    #[clap(default_value_t=false, short, long)]
-    pub ascii_only: bool,
+    /// Regenerate the document recorded in TAPE_FILE, instead of from a seed
+    #[clap(long, value_name="TAPE_FILE", conflicts_with_all=["rand_seed", "invalid", "expected_json", "expected_kdl", "record_tape"])]
+    pub replay_tape: Option<PathBuf>,
 
-    #[clap(default_value_t=false, long)]
-    pub debug: bool,
+    /// Write the rule, byte span, line, column and depth of every production
+    /// --debug would tag to TRACE_FILE as JSON, keeping the document valid
+    #[clap(long, value_name="TRACE_FILE", conflicts_with_all=["invalid", "expected_json", "expected_kdl", "record_tape", "replay_tape", "coverage", "out_dir"])]
+    pub trace: Option<PathBuf>,
 
-    #[clap(long="seed")]
-    pub rand_seed: Option<u64>,
+    /// Print which productions and alternatives of the grammar were used to stderr
+    #[clap(long, conflicts_with_all=["invalid", "expected_json", "expected_kdl", "record_tape", "replay_tape"])]
+    pub coverage: bool,
+
+    /// Check every document with the crate's own KDL parser, and fail with
+    /// the seed and span of any it rejects
+    #[clap(long, conflicts_with_all=["debug", "invalid", "expected_json", "expected_kdl", "record_tape", "replay_tape", "trace"])]
+    pub verify: bool,
+
+    /// Write numbered documents to DIR, each with a seed derived from the
+    /// main one, along with a manifest.json describing them
+    #[clap(long, value_name="DIR", conflicts_with_all=["invalid", "expected_json", "expected_kdl", "record_tape", "replay_tape"])]
+    pub out_dir: Option<PathBuf>,
+
+    /// Lay the --out-dir out like the KDL test suite: each document in
+    /// input/, and its canonical form under the same name in expected_kdl/.
+    /// The documents are checked as with --verify
+    #[clap(long, requires="out_dir", conflicts_with="debug")]
+    pub test_suite: bool,
+
+    /// How many documents to write to the --out-dir [default: 1, or at most
+    /// 1000 with --until-covered]
+    #[clap(long, value_name="N", requires="out_dir")]
+    pub count: Option<usize>,
+
+    /// Steer each document toward the productions and alternatives the ones
+    /// before it didn't use, and stop once every one has been used
+    #[clap(long, requires="out_dir")]
+    pub until_covered: bool,
+
+    /// How many threads to write the --out-dir documents with. The documents
+    /// are the same whatever the number [default: the number of CPUs]
+    #[clap(long, value_name="N", requires="out_dir")]
+    pub threads: Option<NonZeroUsize>,
+
+    /// Write N variants beside each --out-dir document, as NNNN-1.kdl to
+    /// NNNN-N.kdl, which mean the same but are written differently. Each is
+    /// checked with the crate's own parser, and the documents as with --verify
+    #[clap(long, value_name="N", requires="out_dir", conflicts_with="debug")]
+    pub variants: Option<usize>,
+}
+
+#[derive(Subcommand,Debug)]
+pub enum Command {
+    /// Shrink a document which makes a parser fail, keeping it valid KDL and
+    /// keeping the failure
+    Shrink {
+        /// The parser invocation, run with sh. The document is written to its
+        /// stdin, or to a temporary file whose path replaces `{}`
+        #[clap(long)]
+        cmd: String,
+
+        #[clap(long, default_value_t=1, value_parser=clap::value_parser!(u32).range(1..=2))]
+        spec_version: u32,
+
+        /// Where to write the shrunk document, instead of stdout
+        #[clap(short, long)]
+        out: Option<PathBuf>,
+
+        /// How many seconds the command gets for each document. One still
+        /// running then is killed, and its failure is that it timed out
+        #[clap(long, value_name="SECONDS", default_value_t=10)]
+        timeout: u64,
+
+        input: PathBuf,
+    },
+    /// Run several parsers on each of a run of generated documents, and keep
+    /// every document they disagree on, crash on or time out on
+    Diff {
+        /// A parser, as NAME=COMMAND. The command is run with sh, with the
+        /// document on its stdin, and should print what it parsed as JSON or
+        /// KDL and exit unsuccessfully if it rejects the document
+        #[clap(long="parser", value_name="NAME=COMMAND", required=true, value_parser=parse_parser)]
+        parsers: Vec<(String, String)>,
+
+        /// How many documents to generate
+        #[clap(long, default_value_t=100)]
+        count: usize,
+
+        /// How many seconds each parser gets for each document
+        #[clap(long, value_name="SECONDS", default_value_t=10)]
+        timeout: u64,
+
+        /// Where to keep the documents, with the seed and what each parser
+        /// made of them
+        #[clap(long, value_name="DIR", default_value="diff")]
+        out_dir: PathBuf,
+
+        #[clap(long="seed")]
+        rand_seed: Option<u64>,
+
+        #[clap(flatten)]
+        gen: GeneratorConfig,
+    },
 }
 
 fn main() {
-    let conf = Configuration::parse();
+    let mut conf = Configuration::parse();
+    if let Some(path) = &conf.config {
+        conf = match load_config(path) {
+            Ok(conf) => conf,
+            Err(e) => {
+                std::io::stderr().write_all(format!("{}: {}\n", path.display(), e).as_bytes()).unwrap();
+                process::exit(1);
+            }
+        };
+    }
+    if conf.print_weights {
+        print!("{}", Weights::defaults(conf.gen.spec_version));
+        process::exit(0);
+    }
+    if let Some(Command::Shrink { cmd, spec_version, out, timeout, input }) = &conf.command {
+        if let Err(e) = shrink(cmd, *spec_version, out.as_deref(), Duration::from_secs(*timeout), input) {
+            std::io::stderr().write_all(format!("{}\n", e).as_bytes()).unwrap();
+            process::exit(1);
+        }
+        process::exit(0);
+    }
+    if let Some(Command::Diff { parsers, count, timeout, out_dir, rand_seed, gen }) = &conf.command {
+        match diff(parsers, *count, Duration::from_secs(*timeout), out_dir, *rand_seed, gen) {
+            Ok(0) => process::exit(0),
+            Ok(_) => process::exit(1),
+            Err(e) => {
+                std::io::stderr().write_all(format!("{}\n", e).as_bytes()).unwrap();
+                process::exit(2);
+            }
+        }
+    }
     let mut out = BufWriter::new(std::io::stdout());
 
+    if let Some(path) = &conf.replay_tape {
+        let result = fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|text| Tape::parse(&text).map_err(|e| e.to_string()))
+            .and_then(|tape| Generator::new(conf.gen)
+                .and_then(|generator| generator.replay(&mut out, &tape))
+                .map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            std::io::stderr().write_all(format!("{}\n", e).as_bytes()).unwrap();
+            process::exit(1);
+        }
+        process::exit(0);
+    }
+
     let seed = match conf.rand_seed {
         Some(seed) => seed,
         None => thread_rng().next_u64(),
     };
 
-    std::io::stderr().write(format!("seed: {}\n", seed).as_bytes()).unwrap();
-    let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(seed);
+    std::io::stderr().write_all(format!("seed: {}\n", seed).as_bytes()).unwrap();
+    let mut rng = Generator::rng_for_seed(seed);
+    let generator = match Generator::new(conf.gen.clone()) {
+        Ok(generator) => generator,
+        Err(e) => {
+            std::io::stderr().write_all(format!("{}\n", e).as_bytes()).unwrap();
+            process::exit(1);
+        }
+    };
+
+    if let Some(dir) = &conf.out_dir {
+        let result = batch(&generator, seed, &conf, dir);
+        if let Err(e) = result {
+            std::io::stderr().write_all(format!("{}\n", e).as_bytes()).unwrap();
+            process::exit(1);
+        }
+        process::exit(0);
+    }
+
+    let result = match (&conf.invalid, &conf.expected_json, &conf.expected_kdl) {
+        (Some(path), ..) => generator.generate_invalid(&mut out, &mut rng)
+            .and_then(|corruption| fs::write(path, corruption.to_json().pretty()))
+            .map(|_| 0),
+        (_, None, None) => match (&conf.record_tape, &conf.trace) {
+            (Some(path), _) => generator.generate_recording(&mut out, &mut rng)
+                .and_then(|tape| fs::write(path, tape.to_string()))
+                .map(|_| 0),
+            (_, Some(path)) => generator.generate_with_trace(&mut out, &mut rng)
+                .and_then(|trace| fs::write(path, trace.to_json().pretty()))
+                .map(|_| 0),
+            _ if conf.coverage => {
+                let mut coverage = Coverage::new(generator.config());
+                let result = match conf.verify {
+                    true => generator.generate_verified(&mut out, &mut rng, Some(&mut coverage))
+                        .map(|_| 0)
+                        .map_err(|e| rejected(e, &format!("seed {}", seed))),
+                    false => generator.generate_with_coverage(&mut out, &mut rng, &mut coverage),
+                };
+                std::io::stderr().write_all(coverage.to_string().as_bytes()).unwrap();
+                result
+            }
+            _ if conf.verify => generator.generate_verified(&mut out, &mut rng, None)
+                .map(|_| 0)
+                .map_err(|e| rejected(e, &format!("seed {}", seed))),
+            _ => generator.generate_with_rng(&mut out, &mut rng),
+        },
+        (_, json, kdl) => generator.generate_with_model(&mut out, &mut rng)
+            .and_then(|model| {
+                if let Some(path) = json {
+                    fs::write(path, model.to_json().compact() + "\n")?;
+                }
+                if let Some(path) = kdl {
+                    fs::write(path, model.to_kdl(conf.gen.spec_version))?;
+                }
+                Ok(0)
+            }),
+    };
 
-    match gen::document(&mut out, &mut rng, &conf) {
+    match result {
         Err(e) => {
-            std::io::stderr().write(e.to_string().as_bytes()).unwrap();
+            eprintln!("{}", e);
             process::exit(1);
         },
         Ok(_) => process::exit(0),
     };
 }
+
+const COVERAGE_DOCUMENTS_MAX: usize = 1000;
+
+// Write --count documents to dir, or fewer if they cover the grammar first,
+// and a manifest with the seed, size and production counts of each, and the
+// most raw string hashes and comment nesting in any of them. Threads take the
+// next document number as they finish one. Directed coverage depends on every
+// document before, so it uses a single thread.
+fn batch(generator: &Generator, seed: u64, conf: &Configuration, dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    if conf.test_suite {
+        fs::create_dir_all(dir.join("input"))?;
+        fs::create_dir_all(dir.join("expected_kdl"))?;
+    }
+    let count = conf.count.unwrap_or(if conf.until_covered { COVERAGE_DOCUMENTS_MAX } else { 1 });
+    let threads = match conf.threads {
+        _ if conf.until_covered => 1,
+        Some(threads) => threads.get(),
+        None => thread::available_parallelism().map_or(1, NonZeroUsize::get),
+    };
+    let width = count.saturating_sub(1).to_string().len().max(4);
+    let next = AtomicUsize::new(0);
+    let results = thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads.min(count.max(1)))
+            .map(|_| scope.spawn(|| {
+                let mut coverage = Coverage::new(generator.config()).directed(conf.until_covered);
+                let mut documents = Vec::new();
+                loop {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    if index >= count || (conf.until_covered && coverage.is_complete()) {
+                        return Ok((documents, coverage));
+                    }
+                    let file = format!("{:0width$}.kdl", index, width = width);
+                    let doc_seed = Generator::derive_seed(seed, index as u64);
+                    documents.push((index, write_document(generator, dir, file, doc_seed, &mut coverage, conf)?));
+                }
+            }))
+            .collect();
+        workers.into_iter().map(|worker| worker.join().unwrap()).collect::<io::Result<Vec<_>>>()
+    })?;
+
+    let mut coverage = Coverage::new(generator.config());
+    let mut documents = Vec::new();
+    for (written, worker_coverage) in results {
+        documents.extend(written);
+        coverage.merge(&worker_coverage);
+    }
+    documents.sort_by_key(|(index, _)| *index);
+    let written = documents.len();
+    let (raw_hashes, comment_nesting) = coverage.recursion();
+    let manifest = Json::object(vec![
+        ("seed", Json::Number(seed.to_string())),
+        ("config", generator.config().to_json()),
+        ("raw_hashes", Json::Number(raw_hashes.to_string())),
+        ("comment_nesting", Json::Number(comment_nesting.to_string())),
+        ("documents", Json::Array(documents.into_iter().map(|(_, document)| document).collect())),
+    ]);
+    fs::write(dir.join("manifest.json"), manifest.pretty())?;
+    if conf.until_covered || conf.coverage {
+        io::stderr().write_all(format!("{} documents\n{}", written, coverage).as_bytes())?;
+    }
+    Ok(())
+}
+
+// Write one document of a batch, checking it with --verify, and with
+// --test-suite, its canonical form beside it, then any --variants of it.
+// Returns its entry in the manifest
+fn write_document(generator: &Generator, dir: &Path, file: String, seed: u64, coverage: &mut Coverage, conf: &Configuration) -> io::Result<Json> {
+    let before: Vec<u64> = coverage.production_hits().map(|(_, hits)| hits).collect();
+    let mut doc = Vec::new();
+    let rng = &mut Generator::rng_for_seed(seed);
+    let variants = conf.variants.unwrap_or(0);
+    let model = match conf.verify || conf.test_suite || variants > 0 {
+        true => generator.generate_verified(&mut doc, rng, Some(coverage)).map(Some),
+        false => generator.generate_with_coverage(&mut doc, rng, coverage).map(|_| None),
+    };
+    let file = match conf.test_suite {
+        true => format!("input/{}", file),
+        false => file,
+    };
+    fs::write(dir.join(&file), &doc)?;
+    let model = model.map_err(|e| rejected(e, &format!("{} (seed {})", file, seed)))?;
+    let spec_version = generator.config().spec_version;
+    let mut files = vec![file.clone()];
+    for variant in 1..=variants {
+        let mut doc = Vec::new();
+        let file = format!("{}-{}.kdl", file.trim_end_matches(".kdl"), variant);
+        generator.generate_variant(&mut doc, rng, model.as_ref().unwrap())
+            .map_err(|e| io::Error::new(e.kind(), format!("{} (seed {}): {}\n", file, seed, e)))?;
+        fs::write(dir.join(&file), &doc)?;
+        files.push(file);
+    }
+    if let Some(model) = model.filter(|_| conf.test_suite) {
+        for file in &files {
+            let expected = dir.join("expected_kdl").join(Path::new(file).file_name().unwrap());
+            fs::write(expected, model.to_kdl(spec_version))?;
+        }
+    }
+    let size = doc.len();
+
+    let mut rules: Vec<(String, u64)> = Vec::new();
+    for ((name, after), before) in coverage.production_hits().zip(before) {
+        match rules.iter_mut().find(|(n, _)| n == name) {
+            Some((_, hits)) => *hits += after - before,
+            None if after > before => rules.push((name.to_string(), after - before)),
+            None => {}
+        }
+    }
+    let mut entry = vec![
+        ("file", Json::String(file)),
+        ("seed", Json::Number(seed.to_string())),
+        ("size", Json::Number(size.to_string())),
+        ("rules", Json::object(rules.into_iter().map(|(name, hits)| (name, Json::Number(hits.to_string()))).collect())),
+    ];
+    if variants > 0 {
+        entry.push(("variants", Json::Array(files.into_iter().skip(1).map(Json::String).collect())));
+    }
+    Ok(Json::object(entry))
+}
+
+// Says which document --verify rejected, leaving other errors as they are
+fn rejected(e: io::Error, document: &str) -> io::Error {
+    match e.get_ref().is_some_and(|inner| inner.is::<Rejection>()) {
+        true => io::Error::new(e.kind(), format!("{} was rejected at {}\n", document, e)),
+        false => e,
+    }
+}
+
+// Read a config file, whose `weights` node holds the weights and whose other
+// nodes are options named like their flags, and parse the command line again
+// with those options in front of it so the ones given there win.
+fn load_config(path: &Path) -> Result<Configuration, Box<dyn Error>> {
+    let text = fs::read_to_string(path)?;
+    let (doc, spec_version) = match parse::parse(&text, 2) {
+        Ok(doc) => (doc, 2),
+        Err(e) => (parse::parse(&text, 1).map_err(|_| e)?, 1),
+    };
+    let mut args = std::env::args_os().take(1).collect::<Vec<_>>();
+    let mut weights = Weights::default();
+    for node in doc.to_model(&text, spec_version).nodes {
+        if node.name == "weights" {
+            weights = Weights::from_nodes(&node.children)?;
+            continue;
+        }
+        let value = match node.args.as_slice() {
+            [entry] => &entry.value,
+            _ => return Err(format!("option {} takes a single value", node.name).into()),
+        };
+        match value {
+            Value::Bool(false) => {}
+            Value::Bool(true) => args.push(format!("--{}", node.name).into()),
+            Value::Number(value) | Value::String(value) => {
+                args.push(format!("--{}", node.name).into());
+                args.push(value.into());
+            }
+            _ => return Err(format!("option {} has an unsupported value", node.name).into()),
+        }
+    }
+    args.extend(std::env::args_os().skip(1));
+    let mut conf = Configuration::try_parse_from(args)?;
+    conf.gen.weights = weights;
+    Ok(conf)
+}
+
+fn shrink(cmd: &str, spec_version: u32, out: Option<&Path>, timeout: Duration, input: &Path) -> Result<(), Box<dyn Error>> {
+    let src = fs::read_to_string(input)?;
+    let failure = run(cmd, &src, timeout)?;
+    match failure {
+        Some(status) if status.success() => return Err(format!("`{}` doesn't fail on {}", cmd, input.display()).into()),
+        Some(status) => std::io::stderr().write_all(format!("failure: {}\n", status).as_bytes())?,
+        None => std::io::stderr().write_all(format!("failure: timed out after {}s\n", timeout.as_secs()).as_bytes())?,
+    }
+
+    let shrunk = kdl_gen::shrink::shrink(&src, spec_version, |candidate| {
+        run(cmd, candidate, timeout).is_ok_and(|status| status == failure)
+    })?;
+    std::io::stderr().write_all(format!("shrunk {} bytes to {}\n", src.len(), shrunk.len()).as_bytes())?;
+    match out {
+        Some(path) => fs::write(path, shrunk)?,
+        None => std::io::stdout().write_all(shrunk.as_bytes())?,
+    }
+    Ok(())
+}
+
+// Run the parser command on a document, passing it in a temporary file if the
+// command has a `{}` placeholder and on stdin otherwise. Returns None if it
+// was still running at the timeout.
+fn run(cmd: &str, doc: &str, timeout: Duration) -> io::Result<Option<ExitStatus>> {
+    let mut command = process::Command::new("sh");
+    command.stdout(Stdio::null()).stderr(Stdio::null());
+    if cmd.contains("{}") {
+        let file = TempFile(std::env::temp_dir().join(format!("kdl-gen-shrink-{}.kdl", process::id())));
+        fs::write(&file.0, doc)?;
+        let child = command.arg("-c").arg(cmd.replace("{}", &file.0.to_string_lossy())).spawn()?;
+        wait_timeout(child, timeout)
+    } else {
+        let mut child = command.arg("-c").arg(cmd).stdin(Stdio::piped()).spawn()?;
+        let mut stdin = child.stdin.take().unwrap();
+        let doc = doc.to_string();
+        thread::spawn(move || {
+            // The parser may exit without reading all of its input.
+            let _ = stdin.write_all(doc.as_bytes());
+        });
+        wait_timeout(child, timeout)
+    }
+}
+
+// A file which is removed however the function that made it returns
+struct TempFile(PathBuf);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+// Wait for a child to exit, killing it if it's still running at the timeout
+fn wait_timeout(mut child: process::Child, timeout: Duration) -> io::Result<Option<ExitStatus>> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            child.kill()?;
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(5));
+    }
+}
+
+fn parse_parser(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((name, cmd)) if !name.is_empty() && !cmd.is_empty() => Ok((name.to_string(), cmd.to_string())),
+        _ => Err(format!("expected NAME=COMMAND, not `{}`", arg)),
+    }
+}
+
+// Run every parser on count documents, keeping each document they don't all
+// agree on in out_dir along with a report of its seed and what each parser
+// made of it. Returns how many documents were kept.
+fn diff(parsers: &[(String, String)], count: usize, timeout: Duration, out_dir: &Path, seed: Option<u64>, gen: &GeneratorConfig) -> Result<usize, Box<dyn Error>> {
+    let seed = seed.unwrap_or_else(|| thread_rng().next_u64());
+    std::io::stderr().write_all(format!("seed: {}\n", seed).as_bytes())?;
+    fs::create_dir_all(out_dir)?;
+    let generator = Generator::new(gen.clone())?;
+    let width = count.saturating_sub(1).to_string().len().max(4);
+    let mut kept = 0;
+    for index in 0..count {
+        let doc_seed = Generator::derive_seed(seed, index as u64);
+        let mut doc = Vec::new();
+        generator.generate_to_writer(&mut doc, doc_seed)?;
+        let outcomes = parsers.iter()
+            .map(|(_, cmd)| run_parser(cmd, &doc, timeout, gen.spec_version))
+            .collect::<io::Result<Vec<_>>>()?;
+        let Some(reason) = diff::disagreement(&outcomes) else { continue };
+        let file = format!("{:0width$}.kdl", index, width = width);
+        fs::write(out_dir.join(&file), &doc)?;
+        let report = Json::object(vec![
+            ("file", Json::String(file.clone())),
+            ("seed", Json::Number(doc_seed.to_string())),
+            ("reason", Json::String(reason.to_string())),
+            ("parsers", Json::object(parsers.iter().zip(&outcomes)
+                .map(|((name, _), outcome)| (name.clone(), outcome.to_json()))
+                .collect())),
+        ]);
+        fs::write(out_dir.join(format!("{:0width$}.json", index, width = width)), report.pretty())?;
+        std::io::stderr().write_all(format!("{} (seed {}): {}\n", file, doc_seed, reason).as_bytes())?;
+        kept += 1;
+    }
+    std::io::stderr().write_all(format!("kept {} of {} documents in {}\n", kept, count, out_dir.display()).as_bytes())?;
+    Ok(kept)
+}
+
+// Run a parser on a document given on its stdin, killing it if it's still
+// running at the timeout. Its stdin and stdout are handled on their own
+// threads so that a parser which stops reading, or prints more than a pipe
+// holds, can't stall the run.
+fn run_parser(cmd: &str, doc: &[u8], timeout: Duration, spec_version: u32) -> io::Result<Outcome> {
+    let mut child = process::Command::new("sh")
+        .arg("-c").arg(cmd)
+        .stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::null())
+        .spawn()?;
+    let mut stdin = child.stdin.take().unwrap();
+    let doc = doc.to_vec();
+    thread::spawn(move || {
+        // The parser may exit without reading all of its input.
+        let _ = stdin.write_all(&doc);
+    });
+    // Anything the shell started may still hold stdout open once it has
+    // exited, or after a timeout, so the reader sends what it reads as it
+    // goes and is left to finish on its own.
+    let mut stdout = child.stdout.take().unwrap();
+    let (chunks, read) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buf = [0; 8192];
+        while let Ok(len @ 1..) = stdout.read(&mut buf) {
+            if chunks.send(buf[..len].to_vec()).is_err() {
+                break;
+            }
+        }
+    });
+    let deadline = Instant::now() + timeout;
+    let Some(status) = wait_timeout(child, timeout)? else { return Ok(Outcome::TimedOut) };
+    // Output which is still open at the deadline never finished either
+    let mut out = Vec::new();
+    loop {
+        match read.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(chunk) => out.extend(chunk),
+            Err(RecvTimeoutError::Disconnected) => break,
+            Err(RecvTimeoutError::Timeout) => return Ok(Outcome::TimedOut),
+        }
+    }
+    Ok(Outcome::new(status, &out, spec_version))
+}
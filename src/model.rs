@@ -0,0 +1,571 @@
+use crate::json::Json;
+use crate::parse;
+
+/// The meaning of a generated document, as a parser is expected to see it.
+/// Slashdashed nodes, entries and children are not part of the model.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Document {
+    pub nodes: Vec<Node>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Node {
+    pub ty: Option<String>,
+    pub name: String,
+    pub args: Vec<Entry>,
+    /// Props in the order their keys first appeared, holding the last value
+    /// given for each key.
+    pub props: Vec<(String, Entry)>,
+    pub children: Vec<Node>,
+}
+
+// Freed from a stack rather than recursively, as parse::Node is.
+impl Drop for Node {
+    fn drop(&mut self) {
+        let mut nodes = std::mem::take(&mut self.children);
+        while let Some(mut node) = nodes.pop() {
+            nodes.append(&mut node.children);
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub ty: Option<String>,
+    pub value: Value,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    String(String),
+    /// A number in JSON syntax, kept as text so no precision is lost.
+    Number(String),
+    /// One of 'inf', '-inf' or 'nan'.
+    NonFinite(&'static str),
+    Bool(bool),
+    Null,
+}
+
+impl Document {
+    pub fn to_json(&self) -> Json {
+        Json::object(vec![
+            ("nodes", Json::Array(self.nodes.iter().map(Node::to_json).collect())),
+        ])
+    }
+
+    /// The document in the canonical form of the KDL test suite's
+    /// `expected_kdl` files: one node per line, children indented by four
+    /// spaces, args in order followed by props sorted by key, strings quoted
+    /// only where they have to be, and numbers in decimal.
+    pub fn to_kdl(&self, spec_version: u32) -> String {
+        let mut out = String::new();
+        write_nodes(&mut out, &self.nodes, spec_version);
+        out
+    }
+}
+
+// Writes from a stack of the nodes left to write at each depth rather than
+// recursively, so that documents of any depth can be written.
+fn write_nodes(out: &mut String, nodes: &[Node], spec_version: u32) {
+    let mut stack = vec![nodes.iter()];
+    while !stack.is_empty() {
+        let indent = "    ".repeat(stack.len() - 1);
+        let Some(node) = stack.last_mut().unwrap().next() else {
+            stack.pop();
+            if !stack.is_empty() {
+                out.push_str(&"    ".repeat(stack.len() - 1));
+                out.push_str("}\n");
+            }
+            continue;
+        };
+        out.push_str(&indent);
+        if let Some(ty) = &node.ty {
+            out.push_str(&format!("({})", identifier_to_kdl(ty, spec_version)));
+        }
+        out.push_str(&identifier_to_kdl(&node.name, spec_version));
+        for arg in &node.args {
+            out.push_str(&format!(" {}", arg.to_kdl(spec_version)));
+        }
+        let mut props: Vec<_> = node.props.iter().collect();
+        props.sort_by(|(a, _), (b, _)| a.cmp(b));
+        for (key, value) in props {
+            out.push_str(&format!(" {}={}", identifier_to_kdl(key, spec_version), value.to_kdl(spec_version)));
+        }
+        if node.children.is_empty() {
+            out.push('\n');
+        } else {
+            out.push_str(" {\n");
+            stack.push(node.children.iter());
+        }
+    }
+}
+
+impl Node {
+    pub fn to_json(&self) -> Json {
+        // Built from a stack of the nodes being converted, each with the
+        // children it has left to convert and the ones already converted,
+        // rather than recursively
+        let mut stack = vec![(self, self.children.iter(), Vec::new())];
+        loop {
+            let (_, left, _) = stack.last_mut().unwrap();
+            if let Some(child) = left.next() {
+                stack.push((child, child.children.iter(), Vec::new()));
+                continue;
+            }
+            let (node, _, children) = stack.pop().unwrap();
+            let json = Json::object(vec![
+                ("type", ty_to_json(&node.ty)),
+                ("name", Json::String(node.name.clone())),
+                ("args", Json::Array(node.args.iter().map(Entry::to_json).collect())),
+                ("props", Json::object(node.props.iter().map(|(k, v)| (k.clone(), v.to_json())).collect())),
+                ("children", Json::Array(children)),
+            ]);
+            match stack.last_mut() {
+                Some((_, _, siblings)) => siblings.push(json),
+                None => return json,
+            }
+        }
+    }
+
+    pub(crate) fn set_prop(&mut self, key: String, entry: Entry) {
+        match self.props.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, existing)) => *existing = entry,
+            None => self.props.push((key, entry)),
+        }
+    }
+}
+
+impl Entry {
+    pub fn to_json(&self) -> Json {
+        Json::object(vec![
+            ("type", ty_to_json(&self.ty)),
+            ("value", self.value.to_json()),
+        ])
+    }
+
+    fn to_kdl(&self, spec_version: u32) -> String {
+        match &self.ty {
+            Some(ty) => format!("({}){}", identifier_to_kdl(ty, spec_version), self.value.to_kdl(spec_version)),
+            None => self.value.to_kdl(spec_version),
+        }
+    }
+}
+
+impl Value {
+    pub fn to_json(&self) -> Json {
+        match self {
+            Value::String(s) => Json::String(s.clone()),
+            Value::Number(n) => Json::Number(n.clone()),
+            Value::NonFinite(f) => Json::object(vec![("float", Json::String(f.to_string()))]),
+            Value::Bool(b) => Json::Bool(*b),
+            Value::Null => Json::Null,
+        }
+    }
+}
+
+impl Value {
+    // v1 values can't be bare identifiers, and v2 keywords start with '#'
+    fn to_kdl(&self, spec_version: u32) -> String {
+        let keyword = |name: &str| match spec_version {
+            1 => name.to_string(),
+            _ => format!("#{}", name),
+        };
+        match self {
+            Value::String(s) if spec_version == 1 => quote(s),
+            Value::String(s) => identifier_to_kdl(s, spec_version),
+            Value::Number(n) => number_to_kdl(n),
+            Value::NonFinite(f) => keyword(f),
+            Value::Bool(b) => keyword(&b.to_string()),
+            Value::Null => keyword("null"),
+        }
+    }
+}
+
+// A string as a bare identifier where it can be one, and quoted otherwise
+fn identifier_to_kdl(s: &str, spec_version: u32) -> String {
+    match is_bare_identifier(s, spec_version) {
+        true => s.to_string(),
+        false => quote(s),
+    }
+}
+
+// Whether a string can be written as a bare identifier: it isn't empty, a
+// keyword, or something that starts like a number, and has only identifier
+// characters
+pub(crate) fn is_bare_identifier(s: &str, spec_version: u32) -> bool {
+    let keywords: &[&str] = match spec_version {
+        1 => &["true", "false", "null"],
+        _ => &["true", "false", "null", "inf", "-inf", "nan"],
+    };
+    let unsigned = s.strip_prefix(['+', '-']).unwrap_or(s);
+    let number_like = match spec_version {
+        1 => unsigned.starts_with(|c: char| c.is_ascii_digit()),
+        _ => unsigned.strip_prefix('.').unwrap_or(unsigned).starts_with(|c: char| c.is_ascii_digit()),
+    };
+    !s.is_empty() && !number_like && !keywords.contains(&s) && s.chars().all(|c| parse::is_identifier_char(c, spec_version))
+}
+
+// A quoted string with the fewest escapes: the named ones, and unicode escapes
+// for control characters, newlines and disallowed code points
+fn quote(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\u{8}' => out.push_str("\\b"),
+            '\u{c}' => out.push_str("\\f"),
+            c if c.is_control() || parse::is_newline(c, 2) || parse::is_disallowed(c) => {
+                out.push_str(&format!("\\u{{{:x}}}", c as u32));
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// A number from decode_number with its exponent as 'E' and a sign, and an
+// integer zero without one
+fn number_to_kdl(n: &str) -> String {
+    match n.split_once('e') {
+        Some((mantissa, exponent)) => {
+            let (sign, digits) = match exponent.strip_prefix('-') {
+                Some(digits) => ('-', digits),
+                None => ('+', exponent),
+            };
+            let digits = digits.trim_start_matches('0');
+            format!("{}E{}{}", mantissa, sign, if digits.is_empty() { "0" } else { digits })
+        }
+        None if n == "-0" => "0".to_string(),
+        None => n.to_string(),
+    }
+}
+
+fn ty_to_json(ty: &Option<String>) -> Json {
+    match ty {
+        Some(ty) => Json::String(ty.clone()),
+        None => Json::Null,
+    }
+}
+
+/// The parts of the model which enclose other parts. A slashdash discards the
+/// innermost open scope.
+#[derive(Clone, Copy, Debug)]
+pub enum Scope {
+    Node,
+    Entry,
+    Children,
+}
+
+/// What a captured piece of output represents.
+#[derive(Clone, Copy, Debug)]
+pub enum Capture {
+    Type,
+    Name,
+    Key,
+    Value,
+}
+
+enum Frame {
+    Root(Vec<Node>),
+    Node(Node, bool),
+    Entry(Option<String>, Option<String>, Option<Value>, bool),
+    Children(Vec<Node>, bool),
+}
+
+/// Builds a Document from the events emitted while a document is written.
+pub struct Builder {
+    spec_version: u32,
+    stack: Vec<Frame>,
+}
+
+impl Builder {
+    pub fn new(spec_version: u32) -> Builder {
+        Builder { spec_version, stack: vec![Frame::Root(Vec::new())] }
+    }
+
+    pub fn begin(&mut self, scope: Scope) {
+        self.stack.push(match scope {
+            Scope::Node => Frame::Node(Node::default(), false),
+            Scope::Entry => Frame::Entry(None, None, None, false),
+            Scope::Children => Frame::Children(Vec::new(), false),
+        });
+    }
+
+    pub fn end(&mut self) {
+        let frame = self.stack.pop().unwrap();
+        match (frame, self.stack.last_mut().unwrap()) {
+            (Frame::Node(node, false), Frame::Root(nodes) | Frame::Children(nodes, _)) => nodes.push(node),
+            (Frame::Entry(ty, key, Some(value), false), Frame::Node(node, _)) => {
+                let entry = Entry { ty, value };
+                match key {
+                    Some(key) => node.set_prop(key, entry),
+                    None => node.args.push(entry),
+                }
+            }
+            (Frame::Children(children, false), Frame::Node(node, _)) => node.children.extend(children),
+            _ => {}
+        }
+    }
+
+    pub fn slashdash(&mut self) {
+        match self.stack.last_mut().unwrap() {
+            Frame::Node(_, discarded) | Frame::Entry(.., discarded) | Frame::Children(_, discarded) => *discarded = true,
+            Frame::Root(_) => {}
+        }
+    }
+
+    pub fn capture(&mut self, capture: Capture, text: &str) {
+        let spec_version = self.spec_version;
+        match (capture, self.stack.last_mut().unwrap()) {
+            (Capture::Type, Frame::Node(node, _)) => node.ty = Some(decode_string(text, spec_version)),
+            (Capture::Type, Frame::Entry(ty, ..)) => *ty = Some(decode_string(text, spec_version)),
+            (Capture::Name, Frame::Node(node, _)) => node.name = decode_string(text, spec_version),
+            (Capture::Key, Frame::Entry(_, key, ..)) => *key = Some(decode_string(text, spec_version)),
+            (Capture::Value, Frame::Entry(_, _, value, _)) => *value = Some(decode_value(text, spec_version)),
+            _ => {}
+        }
+    }
+
+    pub fn finish(mut self) -> Document {
+        match self.stack.swap_remove(0) {
+            Frame::Root(nodes) => Document { nodes },
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// Decode the text of a keyword, number or string value.
+pub fn decode_value(text: &str, spec_version: u32) -> Value {
+    let keyword = match spec_version {
+        1 => Some(text),
+        _ => text.strip_prefix('#'),
+    };
+    match keyword {
+        Some("true") => return Value::Bool(true),
+        Some("false") => return Value::Bool(false),
+        Some("null") => return Value::Null,
+        _ => {}
+    }
+    match text {
+        "#inf" if spec_version > 1 => return Value::NonFinite("inf"),
+        "#-inf" if spec_version > 1 => return Value::NonFinite("-inf"),
+        "#nan" if spec_version > 1 => return Value::NonFinite("nan"),
+        _ => {}
+    }
+
+    let unsigned = text.strip_prefix(['+', '-']).unwrap_or(text);
+    if unsigned.starts_with(|c: char| c.is_ascii_digit()) {
+        Value::Number(decode_number(text))
+    } else {
+        Value::String(decode_string(text, spec_version))
+    }
+}
+
+/// Convert any KDL number into JSON number syntax.
+pub fn decode_number(text: &str) -> String {
+    let negative = text.starts_with('-');
+    let unsigned = text.strip_prefix(['+', '-']).unwrap_or(text);
+    let digits: String = unsigned.chars().filter(|&c| c != '_').collect();
+
+    let radix = match digits.get(..2) {
+        Some("0x") => 16,
+        Some("0o") => 8,
+        Some("0b") => 2,
+        _ => 10,
+    };
+
+    if radix != 10 {
+        let decimal = to_decimal(&digits[2..], radix);
+        return match (negative, decimal.as_str()) {
+            (true, d) if d != "0" => format!("-{}", d),
+            _ => decimal,
+        };
+    }
+
+    let (mantissa, exponent) = match digits.find(['e', 'E']) {
+        Some(i) => (&digits[..i], Some(&digits[i + 1..])),
+        None => (&digits[..], None),
+    };
+    let (integer, fraction) = match mantissa.find('.') {
+        Some(i) => (&mantissa[..i], Some(&mantissa[i + 1..])),
+        None => (mantissa, None),
+    };
+
+    let mut out = String::new();
+    if negative {
+        out.push('-');
+    }
+    let integer = integer.trim_start_matches('0');
+    out.push_str(if integer.is_empty() { "0" } else { integer });
+    if let Some(fraction) = fraction {
+        out.push('.');
+        out.push_str(fraction);
+    }
+    if let Some(exponent) = exponent {
+        out.push('e');
+        out.push_str(exponent.strip_prefix('+').unwrap_or(exponent));
+    }
+    out
+}
+
+fn to_decimal(digits: &str, radix: u32) -> String {
+    // Little-endian base 10 digits, so integers of any length can be converted.
+    let mut decimal: Vec<u32> = vec![0];
+    for c in digits.chars() {
+        let mut carry = c.to_digit(radix).unwrap();
+        for d in decimal.iter_mut() {
+            let v = *d * radix + carry;
+            *d = v % 10;
+            carry = v / 10;
+        }
+        while carry > 0 {
+            decimal.push(carry % 10);
+            carry /= 10;
+        }
+    }
+    while decimal.len() > 1 && *decimal.last().unwrap() == 0 {
+        decimal.pop();
+    }
+    decimal.iter().rev().map(|d| char::from_digit(*d, 10).unwrap()).collect()
+}
+
+/// Decode the text of an identifier or string into the string it represents.
+pub fn decode_string(text: &str, spec_version: u32) -> String {
+    match spec_version {
+        1 => decode_string_v1(text),
+        _ => decode_string_v2(text),
+    }
+}
+
+fn decode_string_v1(text: &str) -> String {
+    if let Some(rest) = text.strip_prefix('r') {
+        let hashes = rest.len() - rest.trim_start_matches('#').len();
+        if rest[hashes..].starts_with('"') {
+            return rest[hashes + 1..rest.len() - hashes - 1].to_string();
+        }
+    }
+    match text.strip_prefix('"') {
+        Some(quoted) => unescape(&quoted[..quoted.len() - 1], 1),
+        None => text.to_string(),
+    }
+}
+
+fn decode_string_v2(text: &str) -> String {
+    if text.starts_with('#') {
+        let hashes = text.len() - text.trim_start_matches('#').len();
+        let quoted = &text[hashes..text.len() - hashes];
+        return match quoted.strip_prefix("\"\"\"") {
+            Some(multi) => dedent(&multi[..multi.len() - 3]),
+            None => quoted[1..quoted.len() - 1].to_string(),
+        };
+    }
+    if let Some(multi) = text.strip_prefix("\"\"\"") {
+        let resolved = resolve_ws_escapes(&multi[..multi.len() - 3]);
+        return unescape(&dedent(&resolved), 2);
+    }
+    match text.strip_prefix('"') {
+        Some(quoted) => unescape(&quoted[..quoted.len() - 1], 2),
+        None => text.to_string(),
+    }
+}
+
+fn is_newline(c: char) -> bool {
+    matches!(c, '\r' | '\n' | '\u{000B}' | '\u{000C}' | '\u{0085}' | '\u{2028}' | '\u{2029}')
+}
+
+fn is_whitespace(c: char) -> bool {
+    is_newline(c) || matches!(c, '\t' | ' ' | '\u{00A0}' | '\u{1680}' | '\u{2000}'..='\u{200A}'
+        | '\u{202F}' | '\u{205F}' | '\u{3000}')
+}
+
+// Removes whitespace escapes but leaves every other escape in place, as they
+// have to be resolved after dedenting.
+fn resolve_ws_escapes(text: &str) -> String {
+    let mut out = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.peek() {
+            Some(&next) if is_whitespace(next) => {
+                while chars.peek().is_some_and(|&c| is_whitespace(c)) {
+                    chars.next();
+                }
+            }
+            Some(&next) => {
+                out.push(c);
+                out.push(next);
+                chars.next();
+            }
+            None => out.push(c),
+        }
+    }
+    out
+}
+
+// The body of a multi-line string, starting with the newline after the opening
+// quotes and ending with the whitespace before the closing ones.
+fn dedent(body: &str) -> String {
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    let mut chars = body.chars().peekable();
+    while let Some(c) = chars.next() {
+        if is_newline(c) {
+            if c == '\r' && chars.peek() == Some(&'\n') {
+                chars.next();
+            }
+            lines.push(std::mem::take(&mut line));
+        } else {
+            line.push(c);
+        }
+    }
+    let indent = line;
+
+    lines.iter()
+        .skip(1)
+        .map(|line| match line.chars().all(is_whitespace) {
+            true => "",
+            false => line.strip_prefix(indent.as_str()).unwrap_or(line),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn unescape(text: &str, spec_version: u32) -> String {
+    let mut out = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some('t') => out.push('\t'),
+            Some('b') => out.push('\u{8}'),
+            Some('f') => out.push('\u{c}'),
+            Some('s') if spec_version > 1 => out.push(' '),
+            Some('u') => {
+                let hex: String = chars.by_ref().skip(1).take_while(|&c| c != '}').collect();
+                let code = u32::from_str_radix(&hex, 16).unwrap();
+                out.push(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER));
+            }
+            Some(c) if spec_version > 1 && is_whitespace(c) => {
+                while chars.peek().is_some_and(|&c| is_whitespace(c)) {
+                    chars.next();
+                }
+            }
+            Some(c) => out.push(c),
+            None => {}
+        }
+    }
+    out
+}
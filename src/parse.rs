@@ -0,0 +1,822 @@
+//! A KDL parser written straight from the spec grammars, independent of the
+//! generator. It keeps the span of every node, entry, children block, comment
+//! and escline so documents can be edited without losing their structure.
+
+use std::error::Error;
+use std::fmt;
+use std::ops::Range;
+
+use crate::model;
+
+#[derive(Clone, Debug, Default)]
+pub struct Document {
+    pub nodes: Vec<Node>,
+    /// Single and multi-line comments, including the newline which ends a
+    /// single-line comment.
+    pub comments: Vec<Range<usize>>,
+    pub esclines: Vec<Range<usize>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Node {
+    /// From the slashdash, type or name up to and including the terminator.
+    pub span: Range<usize>,
+    pub slashdash: bool,
+    pub ty: Option<Token>,
+    pub name: Token,
+    pub entries: Vec<Entry>,
+    pub children: Vec<Children>,
+}
+
+// Nodes are freed from a stack rather than recursively, so that dropping a
+// deeply nested document doesn't overflow the call stack.
+impl Drop for Node {
+    fn drop(&mut self) {
+        let mut nodes: Vec<Node> = self.children.drain(..).flat_map(|children| children.nodes).collect();
+        while let Some(mut node) = nodes.pop() {
+            nodes.extend(node.children.drain(..).flat_map(|children| children.nodes));
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Entry {
+    /// Includes the node-space before the entry.
+    pub span: Range<usize>,
+    pub slashdash: bool,
+    pub key: Option<Token>,
+    pub ty: Option<Token>,
+    pub value: Token,
+}
+
+#[derive(Clone, Debug)]
+pub struct Children {
+    /// Includes any node-space before the block.
+    pub span: Range<usize>,
+    pub slashdash: bool,
+    pub nodes: Vec<Node>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Token {
+    pub span: Range<usize>,
+    pub kind: TokenKind,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenKind {
+    String,
+    Number,
+    Keyword,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    pub offset: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "offset {}: {}", self.offset, self.message)
+    }
+}
+
+impl Error for ParseError {}
+
+type Result<T> = std::result::Result<T, ParseError>;
+
+/// Parse a document according to the given spec version.
+pub fn parse(src: &str, spec_version: u32) -> Result<Document> {
+    let mut parser = Parser { src, pos: 0, v2: spec_version > 1, doc: Document::default() };
+    parser.document()?;
+    Ok(parser.doc)
+}
+
+impl Document {
+    /// What a parser should make of the document, with slashdashed parts removed.
+    pub fn to_model(&self, src: &str, spec_version: u32) -> model::Document {
+        model::Document { nodes: nodes_to_model(&self.nodes, src, spec_version) }
+    }
+}
+
+// Converts the nodes from a stack of the nodes being converted, each with the
+// nodes of its children blocks left to convert and the ones already converted,
+// rather than recursively, so that documents of any depth can be converted.
+fn nodes_to_model(nodes: &[Node], src: &str, spec_version: u32) -> Vec<model::Node> {
+    let text = |token: &Token| &src[token.span.clone()];
+    let string = |token: &Token| model::decode_string(text(token), spec_version);
+
+    let roots: Vec<&Node> = nodes.iter().filter(|node| !node.slashdash).collect();
+    let mut stack = vec![(None::<model::Node>, roots.into_iter(), Vec::new())];
+    loop {
+        let (_, left, _) = stack.last_mut().unwrap();
+        let Some(node) = left.next() else {
+            let (node, _, children) = stack.pop().unwrap();
+            match (node, stack.last_mut()) {
+                (Some(mut node), Some((_, _, siblings))) => {
+                    node.children = children;
+                    siblings.push(node);
+                    continue;
+                }
+                _ => return children,
+            }
+        };
+        let mut out = model::Node {
+            ty: node.ty.as_ref().map(string),
+            name: string(&node.name),
+            args: Vec::new(),
+            props: Vec::new(),
+            children: Vec::new(),
+        };
+        for entry in node.entries.iter().filter(|entry| !entry.slashdash) {
+            let value = model::Entry {
+                ty: entry.ty.as_ref().map(string),
+                value: model::decode_value(text(&entry.value), spec_version),
+            };
+            match &entry.key {
+                Some(key) => out.set_prop(string(key), value),
+                None => out.args.push(value),
+            }
+        }
+        let children: Vec<_> = node.children.iter()
+            .filter(|children| !children.slashdash)
+            .flat_map(|children| &children.nodes)
+            .filter(|node| !node.slashdash)
+            .collect();
+        stack.push((Some(out), children.into_iter(), Vec::new()));
+    }
+}
+
+pub fn is_newline(c: char, spec_version: u32) -> bool {
+    matches!(c, '\r' | '\n' | '\u{0085}' | '\u{000C}' | '\u{2028}' | '\u{2029}')
+        || (spec_version > 1 && c == '\u{000B}')
+}
+
+pub fn is_unicode_space(c: char) -> bool {
+    matches!(c, '\t' | ' ' | '\u{00A0}' | '\u{1680}' | '\u{2000}'..='\u{200A}'
+        | '\u{202F}' | '\u{205F}' | '\u{3000}')
+}
+
+pub fn is_disallowed(c: char) -> bool {
+    matches!(c, '\u{0000}'..='\u{0008}' | '\u{000E}'..='\u{001F}' | '\u{007F}'
+        | '\u{200E}' | '\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}' | '\u{FEFF}')
+}
+
+pub fn is_identifier_char(c: char, spec_version: u32) -> bool {
+    if is_unicode_space(c) || is_newline(c, spec_version) {
+        return false;
+    }
+    match spec_version {
+        1 => !matches!(c, '\\' | '/' | '(' | ')' | '{' | '}' | '<' | '>' | ';' | '[' | ']' | '=' | ',' | '"' | '\u{FEFF}'),
+        _ => !matches!(c, '\\' | '/' | '(' | ')' | '{' | '}' | ';' | '[' | ']' | '"' | '#' | '=') && !is_disallowed(c),
+    }
+}
+
+struct Parser<'s> {
+    src: &'s str,
+    pos: usize,
+    v2: bool,
+    doc: Document,
+}
+
+// A children block still being parsed: the node it belongs to, where its span
+// starts, whether it is slashdashed and where its '{' is, and the nodes before
+// that node in the block it is in.
+struct OpenChildren {
+    node: Node,
+    start: usize,
+    slashdash: bool,
+    brace: usize,
+    siblings: Vec<Node>,
+}
+
+impl<'s> Parser<'s> {
+    fn version(&self) -> u32 {
+        if self.v2 { 2 } else { 1 }
+    }
+
+    fn rest(&self) -> &'s str {
+        &self.src[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn peek_nth(&self, n: usize) -> Option<char> {
+        self.rest().chars().nth(n)
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn eat(&mut self, s: &str) -> bool {
+        if self.rest().starts_with(s) {
+            self.pos += s.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn error<T>(&self, offset: usize, message: impl Into<String>) -> Result<T> {
+        Err(ParseError { offset, message: message.into() })
+    }
+
+    fn is_newline(&self, c: char) -> bool {
+        is_newline(c, self.version())
+    }
+
+    fn is_identifier_char(&self, c: char) -> bool {
+        is_identifier_char(c, self.version())
+    }
+
+    // document := bom? nodes  (2.0)
+    // nodes := linespace* (node nodes?)? linespace*  (1.0)
+    fn document(&mut self) -> Result<()> {
+        if self.v2 {
+            if let Some((offset, c)) = self.src.char_indices().find(|&(i, c)| is_disallowed(c) && !(i == 0 && c == '\u{FEFF}')) {
+                return self.error(offset, format!("disallowed code point U+{:04X}", c as u32));
+            }
+            self.eat("\u{FEFF}");
+        }
+        self.doc.nodes = self.nodes()?;
+        Ok(())
+    }
+
+    // Children blocks are parsed from a stack of the nodes they belong to
+    // rather than recursively, so that documents can be nested as deep as the
+    // generator writes them.
+    fn nodes(&mut self) -> Result<Vec<Node>> {
+        let mut open: Vec<OpenChildren> = Vec::new();
+        let mut nodes = Vec::new();
+        loop {
+            self.line_space_many()?;
+            let mut node = match self.peek() {
+                None => match open.last() {
+                    Some(block) => return self.error(block.brace, "unclosed children block"),
+                    None => return Ok(nodes),
+                },
+                Some('}') => {
+                    let Some(block) = open.pop() else {
+                        return self.error(self.pos, "unexpected '}'");
+                    };
+                    self.bump();
+                    let mut node = block.node;
+                    let children = std::mem::replace(&mut nodes, block.siblings);
+                    node.children.push(Children { span: block.start..self.pos, slashdash: block.slashdash, nodes: children });
+                    if !self.v2 {
+                        self.ws_many()?;
+                    }
+                    node
+                }
+                _ => self.node_head()?,
+            };
+            match self.node_rest(&mut node, !open.is_empty())? {
+                Some((start, slashdash)) => {
+                    let brace = self.pos;
+                    self.bump();
+                    open.push(OpenChildren { node, start, slashdash, brace, siblings: std::mem::take(&mut nodes) });
+                }
+                None => nodes.push(node),
+            }
+        }
+    }
+
+    // The slashdash, type and name of a node
+    fn node_head(&mut self) -> Result<Node> {
+        let start = self.pos;
+        let slashdash = self.slashdash()?;
+
+        let ty = match self.peek() {
+            Some('(') => Some(self.type_annotation()?),
+            _ => None,
+        };
+        if self.v2 && ty.is_some() {
+            self.node_space_many()?;
+        }
+        let name = self.string_token("node name")?;
+        Ok(Node { span: start..start, slashdash, ty, name, entries: Vec::new(), children: Vec::new() })
+    }
+
+    // The entries and children blocks of a node, up to its terminator. Stops
+    // at the '{' of a children block, returning where its span starts and
+    // whether it is slashdashed, to be called again once the block is closed.
+    fn node_rest(&mut self, node: &mut Node, in_children: bool) -> Result<Option<(usize, bool)>> {
+        loop {
+            let space_start = self.pos;
+            let spaced = self.node_space_many()?;
+
+            match self.peek() {
+                None => break,
+                Some(';') => {
+                    self.bump();
+                    break;
+                }
+                Some('}') if in_children => break,
+                Some(c) if self.is_newline(c) => {
+                    self.newline();
+                    break;
+                }
+                Some('/') if self.peek_nth(1) == Some('/') => {
+                    self.single_line_comment();
+                    break;
+                }
+                _ => {}
+            }
+
+            let item_start = self.pos;
+            let slashdash = self.slashdash()?;
+            if self.peek() == Some('{') {
+                if !slashdash && node.children.iter().any(|c| !c.slashdash) {
+                    return self.error(self.pos, "a node can only have one children block");
+                }
+                return Ok(Some((space_start, slashdash)));
+            }
+            if !node.children.is_empty() {
+                return self.error(item_start, "entries can't follow children");
+            }
+            if !(spaced || self.v2 && slashdash) {
+                return self.error(item_start, "expected whitespace before an entry");
+            }
+            let mut entry = self.entry()?;
+            entry.span.start = space_start;
+            entry.slashdash = slashdash;
+            node.entries.push(entry);
+        }
+
+        node.span.end = self.pos;
+        Ok(None)
+    }
+
+    // slashdash := '/-' line-space*  (2.0)
+    // '/-' node-space*  (1.0)
+    fn slashdash(&mut self) -> Result<bool> {
+        if !self.eat("/-") {
+            return Ok(false);
+        }
+        if self.v2 {
+            self.line_space_many()?;
+        } else {
+            self.node_space_many()?;
+        }
+        Ok(true)
+    }
+
+    // prop := string node-space* '=' node-space* value  (2.0)
+    // prop := identifier '=' value  (1.0)
+    // value := type? node-space* (string | number | keyword)
+    fn entry(&mut self) -> Result<Entry> {
+        let start = self.pos;
+        if self.peek() == Some('(') {
+            let ty = Some(self.type_annotation()?);
+            if self.v2 {
+                self.node_space_many()?;
+            }
+            let value = self.value_token()?;
+            return Ok(Entry { span: start..self.pos, slashdash: false, key: None, ty, value });
+        }
+
+        let token = self.any_token()?;
+        let after_token = self.pos;
+        if self.v2 {
+            self.node_space_many()?;
+        }
+        if token.kind == TokenKind::String && self.eat("=") {
+            if self.v2 {
+                self.node_space_many()?;
+            }
+            let ty = match self.peek() {
+                Some('(') => Some(self.type_annotation()?),
+                _ => None,
+            };
+            if self.v2 && ty.is_some() {
+                self.node_space_many()?;
+            }
+            let value = self.value_token()?;
+            return Ok(Entry { span: start..self.pos, slashdash: false, key: Some(token), ty, value });
+        }
+        self.pos = after_token;
+
+        if !self.v2 && token.kind == TokenKind::String && self.is_bare(&token) {
+            return self.error(token.span.start, "bare identifiers can't be used as values");
+        }
+        Ok(Entry { span: start..self.pos, slashdash: false, key: None, ty: None, value: token })
+    }
+
+    // v1 strings can be quoted, raw or bare identifiers, and only the first two are values
+    fn is_bare(&self, token: &Token) -> bool {
+        let text = &self.src[token.span.clone()];
+        !(text.starts_with('"') || text.starts_with('r') && text[1..].trim_start_matches('#').starts_with('"'))
+    }
+
+    // type := '(' node-space* string node-space* ')'
+    fn type_annotation(&mut self) -> Result<Token> {
+        self.bump();
+        if self.v2 {
+            self.node_space_many()?;
+        }
+        let ty = self.string_token("type annotation")?;
+        if self.v2 {
+            self.node_space_many()?;
+        }
+        if !self.eat(")") {
+            return self.error(self.pos, "expected ')' to close the type annotation");
+        }
+        Ok(ty)
+    }
+
+    fn string_token(&mut self, what: &str) -> Result<Token> {
+        let token = self.any_token()?;
+        if token.kind != TokenKind::String {
+            return self.error(token.span.start, format!("expected a string for the {}", what));
+        }
+        Ok(token)
+    }
+
+    fn value_token(&mut self) -> Result<Token> {
+        let token = self.any_token()?;
+        if !self.v2 && token.kind == TokenKind::String && self.is_bare(&token) {
+            return self.error(token.span.start, "bare identifiers can't be used as values");
+        }
+        Ok(token)
+    }
+
+    // Any string, number or keyword.
+    fn any_token(&mut self) -> Result<Token> {
+        let start = self.pos;
+        let kind = match self.peek() {
+            Some('"') if self.v2 && self.rest().starts_with("\"\"\"") => self.multi_line_string(0)?,
+            Some('"') => self.quoted_string()?,
+            Some('#') if self.v2 => self.hash_token()?,
+            Some('r') if !self.v2 && matches!(self.rest()[1..].trim_start_matches('#').chars().next(), Some('"')) => {
+                self.raw_string()?
+            }
+            Some(c) if self.is_identifier_char(c) => self.bare_token()?,
+            Some(c) => return self.error(start, format!("unexpected {:?}", c)),
+            None => return self.error(start, "unexpected end of input"),
+        };
+        Ok(Token { span: start..self.pos, kind })
+    }
+
+    // A run of identifier characters, which is a number, keyword or identifier.
+    fn bare_token(&mut self) -> Result<TokenKind> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| self.is_identifier_char(c)) {
+            self.bump();
+        }
+        let text = &self.src[start..self.pos];
+        let unsigned = text.strip_prefix(['+', '-']).unwrap_or(text);
+
+        if unsigned.starts_with(|c: char| c.is_ascii_digit()) {
+            return match valid_number(text) {
+                true => Ok(TokenKind::Number),
+                false => self.error(start, format!("invalid number {:?}", text)),
+            };
+        }
+        if self.v2 {
+            if unsigned.starts_with('.') && unsigned[1..].starts_with(|c: char| c.is_ascii_digit()) {
+                return self.error(start, format!("identifiers can't look like numbers: {:?}", text));
+            }
+            if matches!(text, "true" | "false" | "null" | "inf" | "-inf" | "nan") {
+                return self.error(start, format!("{:?} can't be used as an identifier", text));
+            }
+            return Ok(TokenKind::String);
+        }
+        match text {
+            "true" | "false" | "null" => Ok(TokenKind::Keyword),
+            _ => Ok(TokenKind::String),
+        }
+    }
+
+    // keyword := '#true' | '#false' | '#null' | '#inf' | '#-inf' | '#nan', or a raw string
+    fn hash_token(&mut self) -> Result<TokenKind> {
+        let start = self.pos;
+        let hashes = self.rest().len() - self.rest().trim_start_matches('#').len();
+        if self.rest()[hashes..].starts_with('"') {
+            self.pos += hashes;
+            return match self.rest().starts_with("\"\"\"") {
+                true => self.multi_line_string(hashes),
+                false => self.raw_string_body(hashes),
+            };
+        }
+
+        self.bump();
+        while self.peek().is_some_and(|c| self.is_identifier_char(c)) {
+            self.bump();
+        }
+        match &self.src[start..self.pos] {
+            "#true" | "#false" | "#null" => Ok(TokenKind::Keyword),
+            "#inf" | "#-inf" | "#nan" => Ok(TokenKind::Number),
+            text => self.error(start, format!("unknown keyword {:?}", text)),
+        }
+    }
+
+    // raw-string := 'r' raw-string-hash  (1.0)
+    fn raw_string(&mut self) -> Result<TokenKind> {
+        let start = self.pos;
+        self.bump();
+        let hashes = self.rest().len() - self.rest().trim_start_matches('#').len();
+        self.pos += hashes + 1;
+        let terminator = format!("\"{}", "#".repeat(hashes));
+        match self.rest().find(&terminator) {
+            Some(end) => {
+                self.pos += end + terminator.len();
+                Ok(TokenKind::String)
+            }
+            None => self.error(start, "unclosed raw string"),
+        }
+    }
+
+    // '"' single-line-raw-string-body '"' followed by the hashes  (2.0)
+    fn raw_string_body(&mut self, hashes: usize) -> Result<TokenKind> {
+        let start = self.pos - hashes;
+        self.bump();
+        let terminator = format!("\"{}", "#".repeat(hashes));
+        loop {
+            if self.eat(&terminator) {
+                return Ok(TokenKind::String);
+            }
+            match self.bump() {
+                Some(c) if self.is_newline(c) => return self.error(self.pos - c.len_utf8(), "newline in a single-line raw string"),
+                Some(_) => {}
+                None => return self.error(start, "unclosed raw string"),
+            }
+        }
+    }
+
+    // escaped-string := '"' character* '"'  (1.0)
+    // '"' single-line-string-body '"'  (2.0)
+    fn quoted_string(&mut self) -> Result<TokenKind> {
+        let start = self.pos;
+        self.bump();
+        loop {
+            match self.bump() {
+                Some('"') => return Ok(TokenKind::String),
+                Some('\\') => self.escape()?,
+                Some(c) if self.v2 && self.is_newline(c) => {
+                    return self.error(self.pos - c.len_utf8(), "newline in a single-line string");
+                }
+                Some(_) => {}
+                None => return self.error(start, "unclosed string"),
+            }
+        }
+    }
+
+    // escape := ["\\/bfnrt] | 'u{' hex-digit{1, 6} '}'  (1.0)
+    // escape := ["\\bfnrts] | 'u{' hex-unicode '}' | ws-escape  (2.0)
+    fn escape(&mut self) -> Result<()> {
+        let start = self.pos - 1;
+        match self.bump() {
+            Some('"' | '\\' | 'b' | 'f' | 'n' | 'r' | 't') => Ok(()),
+            Some('/') if !self.v2 => Ok(()),
+            Some('s') if self.v2 => Ok(()),
+            Some(c) if self.v2 && (is_unicode_space(c) || self.is_newline(c)) => {
+                while self.peek().is_some_and(|c| is_unicode_space(c) || self.is_newline(c)) {
+                    self.bump();
+                }
+                Ok(())
+            }
+            Some('u') => {
+                if !self.eat("{") {
+                    return self.error(start, "expected '{' in a unicode escape");
+                }
+                let digits_start = self.pos;
+                while self.peek().is_some_and(|c| c.is_ascii_hexdigit()) {
+                    self.bump();
+                }
+                let digits = &self.src[digits_start..self.pos];
+                if digits.is_empty() || digits.len() > 6 || !self.eat("}") {
+                    return self.error(start, "unicode escapes need 1 to 6 hex digits between braces");
+                }
+                match char::from_u32(u32::from_str_radix(digits, 16).unwrap()) {
+                    Some(_) => Ok(()),
+                    None => self.error(start, format!("\\u{{{}}} is not a unicode scalar value", digits)),
+                }
+            }
+            _ => self.error(start, "invalid escape"),
+        }
+    }
+
+    // multi-line-string := '"""' newline (multi-line-string-body newline)? (unicode-space | ws-escape)* '"""'
+    // With hashes > 0 this is the raw form, which has no escapes.
+    fn multi_line_string(&mut self, hashes: usize) -> Result<TokenKind> {
+        let start = self.pos - hashes;
+        self.pos += 3;
+        let body_start = self.pos;
+        match self.bump() {
+            Some(c) if self.is_newline(c) => {}
+            _ => return self.error(start, "multi-line strings must start with a newline"),
+        }
+
+        let terminator = format!("\"\"\"{}", "#".repeat(hashes));
+        let body_end = loop {
+            if self.rest().starts_with(&terminator) {
+                let end = self.pos;
+                self.pos += terminator.len();
+                break end;
+            }
+            match self.bump() {
+                Some('\\') if hashes == 0 => self.escape()?,
+                Some(_) => {}
+                None => return self.error(start, "unclosed multi-line string"),
+            }
+        };
+
+        let body = &self.src[body_start..body_end];
+        let body = match hashes {
+            0 => resolve_ws_escapes(body, self.version()),
+            _ => body.to_string(),
+        };
+        let mut lines = split_lines(&body, self.version());
+        let indent = lines.pop().unwrap();
+        if !indent.chars().all(is_unicode_space) {
+            return self.error(start, "the closing line of a multi-line string can only contain whitespace");
+        }
+        for line in lines.iter().skip(1) {
+            if !line.chars().all(is_unicode_space) && !line.starts_with(indent.as_str()) {
+                return self.error(start, "every line of a multi-line string must start with the closing line's indentation");
+            }
+        }
+        Ok(TokenKind::String)
+    }
+
+    fn line_space_many(&mut self) -> Result<()> {
+        loop {
+            match self.peek() {
+                Some(c) if self.is_newline(c) => self.newline(),
+                Some('/') if self.peek_nth(1) == Some('/') => self.single_line_comment(),
+                Some('\\') if self.v2 => self.escline()?,
+                Some(c) if self.is_ws_start(c) => self.ws()?,
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    // node-space := ws* escline ws* | ws+
+    fn node_space_many(&mut self) -> Result<bool> {
+        let start = self.pos;
+        loop {
+            match self.peek() {
+                Some('\\') => self.escline()?,
+                Some(c) if self.is_ws_start(c) => self.ws()?,
+                _ => return Ok(self.pos > start),
+            }
+        }
+    }
+
+    fn ws_many(&mut self) -> Result<()> {
+        while self.peek().is_some_and(|c| self.is_ws_start(c)) {
+            self.ws()?;
+        }
+        Ok(())
+    }
+
+    fn is_ws_start(&self, c: char) -> bool {
+        is_unicode_space(c)
+            || (!self.v2 && c == '\u{FEFF}')
+            || (c == '/' && self.peek_nth(1) == Some('*'))
+    }
+
+    // ws := bom | unicode-space | multi-line-comment
+    fn ws(&mut self) -> Result<()> {
+        if self.rest().starts_with("/*") {
+            return self.multi_line_comment();
+        }
+        self.bump();
+        Ok(())
+    }
+
+    fn newline(&mut self) {
+        if !self.eat("\r\n") {
+            self.bump();
+        }
+    }
+
+    // escline := '\\' ws* (single-line-comment | newline | eof)
+    fn escline(&mut self) -> Result<()> {
+        let start = self.pos;
+        self.bump();
+        self.ws_many()?;
+        match self.peek() {
+            Some('/') if self.peek_nth(1) == Some('/') => self.single_line_comment(),
+            Some(c) if self.is_newline(c) => self.newline(),
+            None if self.v2 => {}
+            _ => return self.error(start, "expected a newline after the line continuation"),
+        }
+        self.doc.esclines.push(start..self.pos);
+        Ok(())
+    }
+
+    // single-line-comment := '//' ^newline* (newline | eof)
+    fn single_line_comment(&mut self) {
+        let start = self.pos;
+        while let Some(c) = self.bump() {
+            if self.is_newline(c) {
+                if c == '\r' {
+                    self.eat("\n");
+                }
+                break;
+            }
+        }
+        self.doc.comments.push(start..self.pos);
+    }
+
+    // multi-line-comment := '/*' commented-block
+    fn multi_line_comment(&mut self) -> Result<()> {
+        let start = self.pos;
+        let mut depth = 0;
+        loop {
+            if self.eat("/*") {
+                depth += 1;
+            } else if self.eat("*/") {
+                depth -= 1;
+                if depth == 0 {
+                    break;
+                }
+            } else if self.bump().is_none() {
+                return self.error(start, "unclosed multi-line comment");
+            }
+        }
+        self.doc.comments.push(start..self.pos);
+        Ok(())
+    }
+}
+
+// decimal := sign? integer ('.' integer)? exponent?
+// hex := sign? '0x' hex-digit (hex-digit | '_')*, and the same for octal and binary
+pub fn valid_number(text: &str) -> bool {
+    let unsigned = text.strip_prefix(['+', '-']).unwrap_or(text);
+    let radix_digits = |digits: &str, radix: u32| {
+        digits.starts_with(|c: char| c.is_digit(radix)) && digits.chars().all(|c| c == '_' || c.is_digit(radix))
+    };
+    let integer = |digits: &str| radix_digits(digits, 10);
+
+    if let Some(digits) = unsigned.strip_prefix("0x") {
+        return radix_digits(digits, 16);
+    }
+    if let Some(digits) = unsigned.strip_prefix("0o") {
+        return radix_digits(digits, 8);
+    }
+    if let Some(digits) = unsigned.strip_prefix("0b") {
+        return radix_digits(digits, 2);
+    }
+
+    let (mantissa, exponent) = match unsigned.find(['e', 'E']) {
+        Some(i) => (&unsigned[..i], Some(&unsigned[i + 1..])),
+        None => (unsigned, None),
+    };
+    let (whole, fraction) = match mantissa.find('.') {
+        Some(i) => (&mantissa[..i], Some(&mantissa[i + 1..])),
+        None => (mantissa, None),
+    };
+    integer(whole)
+        && fraction.is_none_or(integer)
+        && exponent.is_none_or(|e| integer(e.strip_prefix(['+', '-']).unwrap_or(e)))
+}
+
+fn resolve_ws_escapes(body: &str, spec_version: u32) -> String {
+    let is_ws = |c: char| is_unicode_space(c) || is_newline(c, spec_version);
+    let mut out = String::new();
+    let mut chars = body.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.peek() {
+            Some(&next) if is_ws(next) => {
+                while chars.peek().is_some_and(|&c| is_ws(c)) {
+                    chars.next();
+                }
+            }
+            Some(&next) => {
+                out.push(c);
+                out.push(next);
+                chars.next();
+            }
+            None => out.push(c),
+        }
+    }
+    out
+}
+
+fn split_lines(body: &str, spec_version: u32) -> Vec<String> {
+    let mut lines = vec![String::new()];
+    let mut chars = body.chars().peekable();
+    while let Some(c) = chars.next() {
+        if is_newline(c, spec_version) {
+            if c == '\r' && chars.peek() == Some(&'\n') {
+                chars.next();
+            }
+            lines.push(String::new());
+        } else {
+            lines.last_mut().unwrap().push(c);
+        }
+    }
+    lines
+}
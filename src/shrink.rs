@@ -0,0 +1,199 @@
+//! Grammar-aware shrinking of a KDL document that makes a parser fail.
+//!
+//! Each step edits the document along its parse tree, so every candidate is
+//! still valid KDL, and keeps the edit only if the failure is still there.
+
+use std::ops::Range;
+
+use crate::model;
+use crate::parse::{self, Node, ParseError, TokenKind};
+
+/// A single edit: replace `range` of the document with `with`.
+struct Edit {
+    range: Range<usize>,
+    with: With,
+}
+
+/// What an edit writes in place of its range.
+enum With {
+    Text(String),
+    /// Another range of the document, followed by a newline. Kept as a range
+    /// so that hoisting deeply nested children doesn't copy them for every
+    /// node above them.
+    Hoisted(Range<usize>),
+}
+
+impl Edit {
+    // The document with the edit made, if that makes it any shorter
+    fn apply(&self, src: &str) -> Option<String> {
+        let (with, newline) = match &self.with {
+            With::Text(text) => (text.as_str(), ""),
+            With::Hoisted(range) => (&src[range.clone()], "\n"),
+        };
+        if with.len() + newline.len() >= self.range.len() {
+            return None;
+        }
+        Some([&src[..self.range.start], with, newline, &src[self.range.end..]].concat())
+    }
+}
+
+/// Shrink `src` for as long as `still_fails` holds for a smaller, valid
+/// document, and return the smallest one found. `src` must itself be valid.
+pub fn shrink<F: FnMut(&str) -> bool>(src: &str, spec_version: u32, mut still_fails: F) -> Result<String, ParseError> {
+    let mut current = src.to_string();
+    let mut doc = parse::parse(&current, spec_version)?;
+    // After an edit is kept, the edits of the smaller document are tried from
+    // the same place in the list, rather than from the top again, and only a
+    // pass over all of them which keeps none ends the shrinking
+    let mut start = 0;
+    'pass: loop {
+        for (i, edit) in edits(&doc, &current, spec_version).into_iter().enumerate().skip(start) {
+            let Some(candidate) = edit.apply(&current) else {
+                continue;
+            };
+            if let Ok(candidate_doc) = parse::parse(&candidate, spec_version) {
+                if still_fails(&candidate) {
+                    current = candidate;
+                    doc = candidate_doc;
+                    start = i;
+                    continue 'pass;
+                }
+            }
+        }
+        if start == 0 {
+            return Ok(current);
+        }
+        start = 0;
+    }
+}
+
+// Candidate edits, roughly from the ones which remove the most to the least.
+fn edits(doc: &parse::Document, src: &str, spec_version: u32) -> Vec<Edit> {
+    let nodes = collect_nodes(&doc.nodes);
+
+    let mut edits = Vec::new();
+    for node in &nodes {
+        edits.push(remove(node.span.clone()));
+    }
+    for node in &nodes {
+        for children in &node.children {
+            edits.push(remove(children.span.clone()));
+            // Hoist the children up in place of the node.
+            if let (Some(first), Some(last)) = (children.nodes.first(), children.nodes.last()) {
+                edits.push(Edit { range: node.span.clone(), with: With::Hoisted(first.span.start..last.span.end) });
+            }
+        }
+    }
+    for node in &nodes {
+        for entry in &node.entries {
+            edits.push(remove(entry.span.clone()));
+        }
+        // A node's span takes in its terminator, and a ';' can go wherever the
+        // node is followed by a newline or the end of a block
+        if src[node.span.clone()].ends_with(';') {
+            edits.push(remove(node.span.end - 1..node.span.end));
+        }
+    }
+    for range in doc.comments.iter().chain(&doc.esclines) {
+        edits.push(replace(range.clone(), " "));
+        edits.push(replace(range.clone(), "\n"));
+    }
+    // Runs of whitespace and newlines between tokens.
+    let is_space = |c: char| parse::is_unicode_space(c) || parse::is_newline(c, spec_version) || c == '\u{FEFF}';
+    let mut chars = src.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        if is_space(c) {
+            let mut end = start + c.len_utf8();
+            while let Some((i, c)) = chars.next_if(|&(_, c)| is_space(c)) {
+                end = i + c.len_utf8();
+            }
+            edits.push(remove(start..end));
+            edits.push(replace(start..end, " "));
+            edits.push(replace(start..end, "\n"));
+        }
+    }
+    for node in &nodes {
+        let types = node.ty.iter().chain(node.entries.iter().filter_map(|entry| entry.ty.as_ref()));
+        for ty in types {
+            if let (Some(open), Some(close)) = (src[..ty.span.start].rfind('('), src[ty.span.end..].find(')')) {
+                edits.push(remove(open..ty.span.end + close + 1));
+            }
+        }
+    }
+    for node in &nodes {
+        let tokens = std::iter::once(&node.name)
+            .chain(node.ty.iter())
+            .chain(node.entries.iter().flat_map(|entry| entry.key.iter().chain(entry.ty.iter()).chain([&entry.value])));
+        for token in tokens {
+            let text = &src[token.span.clone()];
+            match token.kind {
+                TokenKind::String => {
+                    let value = model::decode_string(text, spec_version);
+                    let chars: Vec<char> = value.chars().collect();
+                    let len = chars.len();
+                    for shorter in [&chars[..0], &chars[..len / 2], &chars[len / 2..], &chars[..len.saturating_sub(1)], &chars[len.min(1)..]] {
+                        let shorter: String = shorter.iter().collect();
+                        edits.push(replace(token.span.clone(), &quoted_string(&shorter, spec_version)));
+                    }
+                    edits.push(replace(token.span.clone(), "a"));
+                }
+                TokenKind::Number => {
+                    edits.push(replace(token.span.clone(), "0"));
+                    if text.len() > 2 {
+                        edits.push(replace(token.span.clone(), &text[..text.len() / 2]));
+                    }
+                }
+                TokenKind::Keyword => {}
+            }
+        }
+    }
+    edits
+}
+
+// Every node, each before its children, walked from a stack rather than
+// recursively so that documents of any depth can be shrunk
+fn collect_nodes(nodes: &[Node]) -> Vec<&Node> {
+    let mut out = Vec::new();
+    let mut stack = vec![nodes.iter()];
+    while let Some(siblings) = stack.last_mut() {
+        match siblings.next() {
+            Some(node) => {
+                out.push(node);
+                stack.extend(node.children.iter().rev().map(|children| children.nodes.iter()));
+            }
+            None => {
+                stack.pop();
+            }
+        }
+    }
+    out
+}
+
+fn remove(range: Range<usize>) -> Edit {
+    replace(range, "")
+}
+
+fn replace(range: Range<usize>, with: &str) -> Edit {
+    Edit { range, with: With::Text(with.to_string()) }
+}
+
+// quoted-string := '"' string-character* '"', escaping anything that isn't
+// safe to write literally in either spec version.
+fn quoted_string(value: &str, spec_version: u32) -> String {
+    let mut out = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if parse::is_newline(c, spec_version) || parse::is_disallowed(c) || c.is_control() => {
+                out.push_str(&format!("\\u{{{:x}}}", c as u32));
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
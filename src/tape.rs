@@ -0,0 +1,257 @@
+//! A record of every decision made while generating a document, which can be
+//! replayed to regenerate it without the seed.
+//!
+//! The text form starts with a header holding every option the document was
+//! generated with, including the weights from a config file if there were
+//! any, followed by one token per decision:
+//!
+//! ```text
+//! kdl-gen-tape spec-version=2 ascii-only=false depth-max=3 nodes-per-child-min=0 nodes-per-child-max=3
+//! extra-space-max=3 props-or-args-max=10 ...
+//! r2 m1 s0 "node" r1 s3 m0 "0x1f" ...
+//! ```
+//!
+//! `s<n>` picks the n-th alternative of a `select`, `m1`/`m0` takes or skips a
+//! `maybe`, `r<n>` is a count or other number drawn from a range, and a quoted
+//! string is the text sampled for a regex. `//` starts a comment. Tokens can be
+//! edited freely: on replay a number out of range is clamped into it, and
+//! wherever the tape runs out, holds the wrong kind of token, holds a string
+//! its regex doesn't match or holds a decision which wouldn't fit under
+//! `max_bytes`, the decision is made at random instead.
+
+use std::fmt;
+
+use crate::config::GeneratorConfig;
+use crate::parse::{self, ParseError};
+use crate::weights::Weights;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Decision {
+    Select(usize),
+    Maybe(bool),
+    Range(u32),
+    Sample(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tape {
+    /// The configuration the tape was recorded with, and is replayed with.
+    pub conf: GeneratorConfig,
+    pub decisions: Vec<Decision>,
+}
+
+const HEADER: &str = "kdl-gen-tape";
+const LINE_LEN_MAX: usize = 100;
+
+impl Tape {
+    /// An empty tape for documents generated with the given configuration.
+    pub fn new(conf: &GeneratorConfig) -> Tape {
+        Tape { conf: conf.clone(), decisions: Vec::new() }
+    }
+
+    pub fn parse(text: &str) -> Result<Tape, ParseError> {
+        let mut tokens = Tokens { text, pos: 0 };
+        let mut tape = match tokens.next()? {
+            Some((_, HEADER)) => Tape::new(&GeneratorConfig::default()),
+            _ => return Err(ParseError { offset: 0, message: format!("expected a `{}` header", HEADER) }),
+        };
+        while let Some((offset, token)) = tokens.next()? {
+            let error = |message: &str| ParseError { offset, message: format!("{}: `{}`", message, token) };
+            if let Some((key, value)) = token.split_once('=').filter(|_| !token.starts_with('"')) {
+                if !tape.decisions.is_empty() {
+                    return Err(error("options have to come before the decisions"));
+                }
+                set_option(&mut tape.conf, key, value).map_err(|message| error(&message))?;
+                continue;
+            }
+            let decision = match token.split_at(token.chars().next().unwrap().len_utf8()) {
+                ("\"", _) => Decision::Sample(unescape(&token[1..token.len() - 1])),
+                ("s", i) => Decision::Select(i.parse().map_err(|_| error("bad select"))?),
+                ("m", "0" | "1") => Decision::Maybe(token == "m1"),
+                ("r", n) => Decision::Range(n.parse().map_err(|_| error("bad range"))?),
+                _ => return Err(error("unknown decision")),
+            };
+            tape.decisions.push(decision);
+        }
+        Ok(tape)
+    }
+}
+
+impl fmt::Display for Tape {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let options = options(&self.conf).into_iter().map(|(key, value)| format!("{}={}", key, value));
+        write_lines(f, std::iter::once(HEADER.to_string()).chain(options))?;
+        write_lines(f, self.decisions.iter().map(|decision| match decision {
+            Decision::Select(i) => format!("s{}", i),
+            Decision::Maybe(taken) => format!("m{}", *taken as u8),
+            Decision::Range(n) => format!("r{}", n),
+            Decision::Sample(s) => escape(s),
+        }))
+    }
+}
+
+// Tokens separated by spaces, on lines broken before they get too long
+fn write_lines(f: &mut fmt::Formatter<'_>, tokens: impl Iterator<Item = String>) -> fmt::Result {
+    let mut line_len = 0;
+    for token in tokens {
+        if line_len > 0 && line_len + token.len() >= LINE_LEN_MAX {
+            writeln!(f)?;
+            line_len = 0;
+        } else if line_len > 0 {
+            write!(f, " ")?;
+            line_len += 1;
+        }
+        write!(f, "{}", token)?;
+        line_len += token.len();
+    }
+    writeln!(f)
+}
+
+// Every option in the configuration, named as on the command line, leaving
+// out the ones which aren't set
+fn options(conf: &GeneratorConfig) -> Vec<(&'static str, String)> {
+    let mut options = vec![
+        ("spec-version", conf.spec_version.to_string()),
+        ("ascii-only", conf.ascii_only.to_string()),
+        ("depth-max", conf.depth_max.to_string()),
+        ("nodes-per-child-min", conf.nodes_per_child_min.to_string()),
+        ("nodes-per-child-max", conf.nodes_per_child_max.to_string()),
+        ("extra-space-max", conf.extra_space_max.to_string()),
+        ("props-or-args-max", conf.props_or_args_max.to_string()),
+        ("blank-lines-max", conf.blank_lines_max.to_string()),
+        ("identifier-len-max", conf.identifier_len_max.to_string()),
+        ("string-len-max", conf.string_len_max.to_string()),
+        ("num-len-max", conf.num_len_max.to_string()),
+        ("comment-len-max", conf.comment_len_max.to_string()),
+        ("raw-hash-max", conf.raw_hash_max.to_string()),
+        ("comment-nesting-max", conf.comment_nesting_max.to_string()),
+        ("raw-string-near-misses", conf.raw_string_near_misses.to_string()),
+        ("near-keywords", conf.near_keywords.to_string()),
+        ("debug", conf.debug.to_string()),
+    ];
+    options.extend(conf.total_nodes.map(|nodes| ("total-nodes", nodes.to_string())));
+    options.extend(conf.max_bytes.map(|bytes| ("max-bytes", bytes.to_string())));
+    if !conf.weights.is_empty() {
+        options.push(("weights", escape(&conf.weights.to_string())));
+    }
+    options
+}
+
+// Sets an option from the header, as `options` writes it
+fn set_option(conf: &mut GeneratorConfig, key: &str, value: &str) -> Result<(), String> {
+    let flag = match value {
+        "true" => Some(true),
+        "false" => Some(false),
+        _ => None,
+    };
+    match (key, value.parse::<u32>(), flag) {
+        ("spec-version", Ok(version @ 1..=2), _) => conf.spec_version = version,
+        ("ascii-only", _, Some(ascii_only)) => conf.ascii_only = ascii_only,
+        ("depth-max", Ok(depth), _) => conf.depth_max = depth,
+        ("nodes-per-child-min", Ok(nodes), _) => conf.nodes_per_child_min = nodes,
+        ("nodes-per-child-max", Ok(nodes), _) => conf.nodes_per_child_max = nodes,
+        ("extra-space-max", Ok(spaces), _) => conf.extra_space_max = spaces,
+        ("props-or-args-max", Ok(entries), _) => conf.props_or_args_max = entries,
+        ("blank-lines-max", Ok(lines), _) => conf.blank_lines_max = lines,
+        ("identifier-len-max", Ok(len), _) => conf.identifier_len_max = len,
+        ("string-len-max", Ok(len), _) => conf.string_len_max = len,
+        ("num-len-max", Ok(len), _) => conf.num_len_max = len,
+        ("comment-len-max", Ok(len), _) => conf.comment_len_max = len,
+        ("raw-hash-max", Ok(hashes), _) => conf.raw_hash_max = hashes,
+        ("comment-nesting-max", Ok(nesting), _) => conf.comment_nesting_max = nesting,
+        ("raw-string-near-misses", _, Some(near_misses)) => conf.raw_string_near_misses = near_misses,
+        ("near-keywords", _, Some(near_keywords)) => conf.near_keywords = near_keywords,
+        ("debug", _, Some(debug)) => conf.debug = debug,
+        ("total-nodes", Ok(nodes), _) => conf.total_nodes = Some(nodes),
+        ("max-bytes", ..) if value.parse::<usize>().is_ok() => conf.max_bytes = value.parse().ok(),
+        ("weights", ..) if value.len() > 1 && value.starts_with('"') && value.ends_with('"') => {
+            let text = unescape(&value[1..value.len() - 1]);
+            let nodes = parse::parse(&text, 2).map_err(|e| format!("bad weights at {}", e))?.to_model(&text, 2).nodes;
+            conf.weights = Weights::from_nodes(&nodes).map_err(|e| format!("bad weights: {}", e))?;
+        }
+        _ => return Err("unknown option".to_string()),
+    }
+    Ok(())
+}
+
+// Splits a tape into whitespace separated tokens, skipping comments and
+// keeping quoted strings whole.
+struct Tokens<'s> {
+    text: &'s str,
+    pos: usize,
+}
+
+impl<'s> Tokens<'s> {
+    fn next(&mut self) -> Result<Option<(usize, &'s str)>, ParseError> {
+        loop {
+            let rest = &self.text[self.pos..];
+            let trimmed = rest.trim_start();
+            self.pos += rest.len() - trimmed.len();
+            if !trimmed.starts_with("//") {
+                break;
+            }
+            self.pos += trimmed.find('\n').unwrap_or(trimmed.len());
+        }
+        let start = self.pos;
+        let rest = &self.text[start..];
+        // A quoted string is a token of its own, or an option's value
+        let word = rest.find(|c: char| c.is_whitespace() || c == '"').unwrap_or(rest.len());
+        let len = if rest.is_empty() {
+            return Ok(None);
+        } else if rest[word..].starts_with('"') && (word == 0 || rest[..word].ends_with('=')) {
+            match quoted_len(&rest[word..]) {
+                Some(len) => word + len,
+                None => return Err(ParseError { offset: start, message: "unclosed string".to_string() }),
+            }
+        } else {
+            rest.find(char::is_whitespace).unwrap_or(rest.len())
+        };
+        self.pos += len;
+        Ok(Some((start, &rest[..len])))
+    }
+}
+
+// The length of the quoted string at the start of s, quotes and all
+fn quoted_len(s: &str) -> Option<usize> {
+    let mut escaped = false;
+    let end = s[1..].find(|c| {
+        let end = c == '"' && !escaped;
+        escaped = c == '\\' && !escaped;
+        end
+    });
+    end.map(|end| end + 2)
+}
+
+fn escape(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c == ' ' || c.is_alphanumeric() || c.is_ascii_punctuation() => out.push(c),
+            c => out.push_str(&format!("\\u{{{:x}}}", c as u32)),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn unescape(s: &str) -> String {
+    let mut out = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('u') => {
+                let hex: String = chars.by_ref().skip(1).take_while(|&c| c != '}').collect();
+                out.extend(u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32));
+            }
+            Some(escaped) => out.push(escaped),
+            None => {}
+        }
+    }
+    out
+}
@@ -0,0 +1,90 @@
+//! Where each production of the grammar was written in a document, so that a
+//! parser's diagnostics can be mapped back to the rule which wrote the text.
+
+use crate::json::Json;
+use crate::parse;
+
+/// The span of the document a production wrote. Offsets are in bytes, and
+/// lines and columns count from 1, with columns in characters.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceSpan {
+    pub rule: &'static str,
+    pub start: usize,
+    pub end: usize,
+    pub start_line: usize,
+    pub start_column: usize,
+    pub end_line: usize,
+    pub end_column: usize,
+    /// How many traced productions this one is inside of.
+    pub depth: usize,
+}
+
+/// The spans of every production with a `--debug` tag, in the order they
+/// were started.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Trace {
+    pub spans: Vec<TraceSpan>,
+}
+
+impl Trace {
+    pub fn to_json(&self) -> Json {
+        let number = |n: usize| Json::Number(n.to_string());
+        Json::Array(self.spans.iter()
+            .map(|span| Json::object(vec![
+                ("rule", Json::String(span.rule.to_string())),
+                ("start", number(span.start)),
+                ("end", number(span.end)),
+                ("start_line", number(span.start_line)),
+                ("start_column", number(span.start_column)),
+                ("end_line", number(span.end_line)),
+                ("end_column", number(span.end_column)),
+                ("depth", number(span.depth)),
+            ]))
+            .collect())
+    }
+
+    // Fill in the lines and columns of the spans from the document they are
+    // in, walking it once for all of the offsets in order
+    pub(crate) fn locate(&mut self, doc: &str, spec_version: u32) {
+        let mut offsets: Vec<usize> = self.spans.iter().flat_map(|span| [span.start, span.end]).collect();
+        offsets.sort_unstable();
+        offsets.dedup();
+        let positions = positions(doc, &offsets, spec_version);
+        let locate = |offset: usize| positions[offsets.binary_search(&offset).unwrap()];
+        for span in &mut self.spans {
+            (span.start_line, span.start_column) = locate(span.start);
+            (span.end_line, span.end_column) = locate(span.end);
+        }
+    }
+
+    /// The innermost span which the byte at the offset was written in.
+    pub fn innermost(&self, offset: usize) -> Option<&TraceSpan> {
+        self.spans.iter()
+            .filter(|span| span.start <= offset && offset < span.end)
+            .max_by_key(|span| span.depth)
+    }
+}
+
+/// The line and column of a byte offset in a document, counting from 1.
+pub fn line_column(doc: &str, offset: usize, spec_version: u32) -> (usize, usize) {
+    positions(doc, &[offset], spec_version)[0]
+}
+
+// The lines and columns of offsets in ascending order
+fn positions(doc: &str, offsets: &[usize], spec_version: u32) -> Vec<(usize, usize)> {
+    let mut positions = Vec::with_capacity(offsets.len());
+    let (mut line, mut column) = (1, 1);
+    let mut chars = doc.char_indices().peekable();
+    for &offset in offsets {
+        while let Some((_, c)) = chars.next_if(|&(i, _)| i < offset) {
+            let crlf = c == '\r' && chars.peek().is_some_and(|&(_, next)| next == '\n');
+            if parse::is_newline(c, spec_version) && !crlf {
+                (line, column) = (line + 1, 1);
+            } else {
+                column += 1;
+            }
+        }
+        positions.push((line, column));
+    }
+    positions
+}
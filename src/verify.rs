@@ -0,0 +1,44 @@
+//! Checking generated documents with the parser in `parse`, which is written
+//! straight from the spec grammars and shares none of the generator's code, so
+//! that a document the generator gets wrong is caught where it was written.
+
+use std::error::Error;
+use std::fmt;
+
+use crate::parse::{self, ParseError};
+use crate::trace::{self, Trace, TraceSpan};
+
+/// A generated document which the parser rejected: why, where, and the
+/// innermost production with a `--debug` tag which wrote the text there.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rejection {
+    pub error: ParseError,
+    pub line: usize,
+    pub column: usize,
+    pub span: Option<TraceSpan>,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (line {}, column {})", self.error, self.line, self.column)?;
+        if let Some(span) = &self.span {
+            write!(f, ", in the {} from {}:{} to {}:{} (bytes {}..{})", span.rule,
+                   span.start_line, span.start_column, span.end_line, span.end_column, span.start, span.end)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for Rejection {}
+
+/// Parse a generated document, using the trace of how it was written to say
+/// which production wrote the text the parser rejected.
+pub fn check(doc: &str, trace: &Trace, spec_version: u32) -> Result<(), Rejection> {
+    let error = match parse::parse(doc, spec_version) {
+        Ok(_) => return Ok(()),
+        Err(error) => error,
+    };
+    let (line, column) = trace::line_column(doc, error.offset, spec_version);
+    let span = trace.innermost(error.offset).cloned();
+    Err(Rejection { error, line, column, span })
+}
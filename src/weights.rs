@@ -0,0 +1,208 @@
+//! Weights for the choices the grammar makes, set in a KDL config file.
+//!
+//! Each node names a production of the grammar, and its children weigh the
+//! choices written directly in that production:
+//!
+//! ```kdl
+//! value {
+//!     select string=3 number=1 keyword=1
+//! }
+//! escape {
+//!     select 4 1
+//! }
+//! node {
+//!     maybe 0.1 type=0.8
+//! }
+//! ```
+//!
+//! The n-th `select` child weighs the alternatives of the n-th `select` in
+//! the production, in the order the rule is written, and an empty one leaves
+//! that select alone. The `maybe` child gives the probability of taking each
+//! `maybe` in the production. Either can be given by position, or by the name
+//! of the production or literal an alternative is, which sets every one with
+//! that name. Anything left out keeps its default: equal weights, and a maybe
+//! taken half of the time. `kdl-gen --print-weights` writes out every choice
+//! with its default.
+
+use std::error::Error;
+use std::fmt;
+
+use crate::json::Json;
+use crate::model::{Node, Value};
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Weights {
+    pub(crate) productions: Vec<ProductionWeights>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct ProductionWeights {
+    pub(crate) name: String,
+    pub(crate) selects: Vec<Vec<(Key, f64)>>,
+    pub(crate) maybes: Vec<(Key, f64)>,
+}
+
+/// Which alternative, or which maybe, a weight is for.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Key {
+    Index(usize),
+    Name(String),
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Key::Index(i) => write!(f, "position {}", i),
+            Key::Name(name) => write!(f, "{}", key(name)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WeightsError(pub String);
+
+impl fmt::Display for WeightsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for WeightsError {}
+
+impl Weights {
+    /// Read weights from the nodes of a config file, one per production.
+    /// Whether the productions exist is only checked once a document is
+    /// generated, as it depends on the spec version.
+    pub fn from_nodes(nodes: &[Node]) -> Result<Weights, WeightsError> {
+        let mut weights = Weights::default();
+        for node in nodes {
+            if !node.args.is_empty() || !node.props.is_empty() {
+                return Err(WeightsError(format!("{} only takes `select` and `maybe` children", node.name)));
+            }
+            let mut production = ProductionWeights { name: node.name.clone(), selects: Vec::new(), maybes: Vec::new() };
+            for child in &node.children {
+                match child.name.as_str() {
+                    "select" => production.selects.push(values(child, f64::INFINITY)?),
+                    "maybe" => production.maybes.extend(values(child, 1.0)?),
+                    name => return Err(WeightsError(format!("unknown choice `{}` in {}", name, node.name))),
+                }
+            }
+            weights.productions.push(production);
+        }
+        Ok(weights)
+    }
+
+    /// A config file which gives every choice in the grammar of the spec
+    /// version its default weight, as a starting point for editing.
+    pub fn defaults(spec_version: u32) -> String {
+        crate::gen::default_weights(spec_version)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.productions.is_empty()
+    }
+
+    /// The weights by production, keying each one by its position or name.
+    pub fn to_json(&self) -> Json {
+        let values = |values: &[(Key, f64)]| Json::object(values.iter()
+            .map(|(key, value)| {
+                let key = match key {
+                    Key::Index(i) => i.to_string(),
+                    Key::Name(name) => name.clone(),
+                };
+                (key, Json::Number(value.to_string()))
+            })
+            .collect());
+        Json::object(self.productions.iter()
+            .map(|production| (production.name.clone(), Json::object(vec![
+                ("select", Json::Array(production.selects.iter().map(|select| values(select)).collect())),
+                ("maybe", values(&production.maybes)),
+            ])))
+            .collect())
+    }
+}
+
+/// The weights as the children of a config file's `weights` node, which
+/// `from_nodes` reads back as they are.
+impl fmt::Display for Weights {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for production in &self.productions {
+            writeln!(f, "{} {{", key(&production.name))?;
+            for select in &production.selects {
+                writeln!(f, "    select{}", entries(select))?;
+            }
+            // The maybe children were read into one list, so one is started
+            // wherever the next weight couldn't have come from the last
+            let maybes = &production.maybes;
+            let mut start = 0;
+            for end in 1..=maybes.len() {
+                let next = match maybes.get(end) {
+                    Some((Key::Index(i), _)) => *i != end - start || maybes[start..end].iter().any(|(key, _)| matches!(key, Key::Name(_))),
+                    Some((key, _)) => maybes[start..end].iter().any(|(other, _)| other == key),
+                    None => true,
+                };
+                if next {
+                    writeln!(f, "    maybe{}", entries(&maybes[start..end]))?;
+                    start = end;
+                }
+            }
+            writeln!(f, "}}")?;
+        }
+        Ok(())
+    }
+}
+
+// Weights as the args and props of a select or maybe node
+fn entries(values: &[(Key, f64)]) -> String {
+    let mut out = String::new();
+    for (key, value) in values {
+        // An infinite weight can only have been read from a number too big
+        // for an f64
+        let value = match value.is_infinite() {
+            true => "1e999".to_string(),
+            false => value.to_string(),
+        };
+        match key {
+            Key::Index(_) => out.push_str(&format!(" {}", value)),
+            Key::Name(name) => out.push_str(&format!(" {}={}", self::key(name), value)),
+        }
+    }
+    out
+}
+
+// The numbers given to a select or maybe node, none of which may be over max
+fn values(node: &Node, max: f64) -> Result<Vec<(Key, f64)>, WeightsError> {
+    let args = node.args.iter().enumerate().map(|(i, entry)| (Key::Index(i), &entry.value));
+    let props = node.props.iter().map(|(name, entry)| (Key::Name(name.clone()), &entry.value));
+    args.chain(props)
+        .map(|(key, value)| match value {
+            Value::Number(n) => match n.parse::<f64>() {
+                Ok(n) if (0.0..=max).contains(&n) => Ok((key, n)),
+                _ => Err(WeightsError(format!("{} for {} in `{}` is out of range", n, key, node.name))),
+            },
+            _ => Err(WeightsError(format!("{} in `{}` has to be a number", key, node.name))),
+        })
+        .collect()
+}
+
+// A name as a KDL identifier, quoted unless it is a plain word which isn't a
+// keyword in either spec version
+pub(crate) fn key(name: &str) -> String {
+    let plain = name.starts_with(|c: char| c.is_ascii_lowercase())
+        && name.chars().all(|c| c.is_ascii_lowercase() || c == '-')
+        && !matches!(name, "true" | "false" | "null" | "inf" | "nan");
+    if plain {
+        return name.to_string();
+    }
+    let mut out = String::from("\"");
+    for c in name.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_ascii_graphic() => out.push(c),
+            c => out.push_str(&format!("\\u{{{:x}}}", c as u32)),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -0,0 +1,93 @@
+//! Batches of documents written to a directory by the binary.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use kdl_gen::json::Json;
+use kdl_gen::Generator;
+
+// Runs kdl-gen with --out-dir set to a fresh directory named for the test,
+// returning the directory
+fn batch(name: &str, args: &[&str]) -> PathBuf {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let _ = fs::remove_dir_all(&dir);
+    let output = Command::new(env!("CARGO_BIN_EXE_kdl-gen"))
+        .args(args)
+        .arg("--out-dir")
+        .arg(&dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    dir
+}
+
+// Every file under dir, by its path from there
+fn files(dir: &Path) -> BTreeMap<PathBuf, Vec<u8>> {
+    let mut files = BTreeMap::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(next) = dirs.pop() {
+        for entry in fs::read_dir(next).unwrap() {
+            let path = entry.unwrap().path();
+            match path.is_dir() {
+                true => dirs.push(path),
+                false => {
+                    files.insert(path.strip_prefix(dir).unwrap().to_path_buf(), fs::read(&path).unwrap());
+                }
+            }
+        }
+    }
+    files
+}
+
+// The value of a field of a JSON object
+fn field<'j>(json: &'j Json, key: &str) -> &'j Json {
+    match json {
+        Json::Object(fields) => &fields.iter().find(|(k, _)| k == key).unwrap_or_else(|| panic!("no {}", key)).1,
+        _ => panic!("not an object: {:?}", json),
+    }
+}
+
+#[test]
+fn manifests_describe_each_document() {
+    let dir = batch("manifest", &["--seed", "3", "--count", "12", "-s", "20", "-c", "20"]);
+    let manifest = Json::parse(&fs::read_to_string(dir.join("manifest.json")).unwrap()).unwrap();
+    assert_eq!(field(&manifest, "seed"), &Json::Number("3".to_string()));
+    assert_eq!(field(field(&manifest, "config"), "string_len_max"), &Json::Number("20".to_string()));
+    for key in ["raw_hashes", "comment_nesting"] {
+        assert!(matches!(field(&manifest, key), Json::Number(_)), "{}", key);
+    }
+    let Json::Array(documents) = field(&manifest, "documents") else {
+        panic!("documents isn't an array");
+    };
+    assert_eq!(documents.len(), 12);
+    for (index, document) in documents.iter().enumerate() {
+        // Numbered with at least four digits
+        let file = format!("{:04}.kdl", index);
+        let seed = Generator::derive_seed(3, index as u64);
+        assert_eq!(field(document, "file"), &Json::String(file.clone()));
+        assert_eq!(field(document, "seed"), &Json::Number(seed.to_string()));
+        let doc = fs::read(dir.join(&file)).unwrap();
+        assert_eq!(field(document, "size"), &Json::Number(doc.len().to_string()));
+        assert!(matches!(field(field(document, "rules"), "nodes"), Json::Number(_)), "{}", file);
+
+        // The document's own seed regenerates it alone
+        let alone = Command::new(env!("CARGO_BIN_EXE_kdl-gen"))
+            .args(["--seed", &seed.to_string(), "-s", "20", "-c", "20"])
+            .output()
+            .unwrap();
+        assert!(alone.stdout == doc, "{}", file);
+    }
+}
+
+#[test]
+fn batches_are_the_same_on_any_number_of_threads() {
+    let args = ["--seed", "11", "--spec-version", "2", "--count", "40", "--test-suite", "--variants", "1", "-s", "20", "-c", "20"];
+    let one = files(&batch("threads-1", &[&args[..], &["--threads", "1"]].concat()));
+    assert_eq!(one.len(), 1 + 40 * 4);
+    for threads in ["2", "7"] {
+        let many = files(&batch(&format!("threads-{}", threads), &[&args[..], &["--threads", threads]].concat()));
+        assert!(one == many, "{} threads wrote a different batch", threads);
+    }
+}
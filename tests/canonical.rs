@@ -0,0 +1,90 @@
+//! The canonical form `--expected-kdl` and `--test-suite` write, which should
+//! match the KDL test suite's expected_kdl files.
+
+use kdl_gen::{Generator, GeneratorConfig};
+use kdl_gen::model::{Document, Node};
+use kdl_gen::parse;
+
+const SEEDS: u64 = 200;
+
+fn canonical(src: &str, spec_version: u32) -> String {
+    parse::parse(src, spec_version).unwrap().to_model(src, spec_version).to_kdl(spec_version)
+}
+
+#[test]
+fn v1_examples() {
+    let cases = [
+        ("node 1 1.0 1.0e10 1.0e-10 0x01 0o07 0b10 \"arg\" r\"arg\\\\\" true false null\n",
+         "node 1 1.0 1.0E+10 1.0E-10 1 7 2 \"arg\" \"arg\\\\\\\\\" true false null\n"),
+        ("/-node\n\"quoted\" /* comment */ key=1 \\\n    \"a b\"=r#\"x\"# // trailing\n",
+         "quoted \"a b\"=\"x\" key=1\n"),
+        ("parent { child; /-skipped; }\nempty {}\n", "parent {\n    child\n}\nempty\n"),
+        ("node b=1 a=2 b=3 (u8)0xff \"true\" \"1a\" \"\"=\"\\u{1}\"\n",
+         "node (u8)255 \"true\" \"1a\" \"\"=\"\\u{1}\" a=2 b=3\n"),
+    ];
+    for (input, expected) in cases {
+        assert_eq!(canonical(input, 1), expected, "{:?}", input);
+    }
+}
+
+#[test]
+fn v2_examples() {
+    let cases = [
+        ("node \"arg\" #\"raw\\\"# #inf #-inf #nan #true #null 1_000 -0.5e+7\n",
+         "node arg \"raw\\\\\" #inf #-inf #nan #true #null 1000 -0.5E+7\n"),
+        ("node \"true\" \"1a\" \".5\" \"-x\" \"\" \"a=b\"\n",
+         "node \"true\" \"1a\" \".5\" -x \"\" \"a=b\"\n"),
+        ("node \"\"\"\n    line one\n      two\n    \"\"\"\n", "node \"line one\\n  two\"\n"),
+        ("(t)node /-arg key = (u8) 1 { /-child; }\n", "(t)node key=(u8)1\n"),
+    ];
+    for (input, expected) in cases {
+        assert_eq!(canonical(input, 2), expected, "{:?}", input);
+    }
+}
+
+// The canonical form parses, and is its own canonical form, so it means the
+// same as the document it came from with the props put in order
+#[test]
+fn canonical_form_is_a_fixed_point() {
+    for spec_version in [1, 2] {
+        for ascii_only in [false, true] {
+            let generator = Generator::new(GeneratorConfig::default()
+                .spec_version(spec_version)
+                .ascii_only(ascii_only)
+                .string_len_max(20)
+                .comment_len_max(20)).unwrap();
+            for seed in 0..SEEDS {
+                let mut out = Vec::new();
+                let model = generator.generate_with_model(&mut out, &mut Generator::rng_for_seed(seed)).unwrap();
+                let expected = model.to_kdl(spec_version);
+                let reparsed = parse::parse(&expected, spec_version)
+                    .unwrap_or_else(|e| panic!("v{} seed {}: {}\n{}", spec_version, seed, e, expected))
+                    .to_model(&expected, spec_version);
+                assert_eq!(reparsed.to_kdl(spec_version), expected, "v{} seed {}", spec_version, seed);
+            }
+        }
+    }
+}
+
+// Deeper than the writers could go if they recursed, on a test thread's stack.
+// Every line is indented by its depth, so the output grows with the square of
+// it, and this is kept to a few thousand.
+#[test]
+fn deep_documents_are_written_out() {
+    const DEPTH: usize = 2000;
+    let mut node = Node::default();
+    for _ in 0..DEPTH {
+        let mut parent = Node::default();
+        parent.name = "n".to_string();
+        parent.children.push(node);
+        node = parent;
+    }
+    let model = Document { nodes: vec![node] };
+
+    let expected = model.to_kdl(2);
+    assert_eq!(expected.lines().filter(|line| line.ends_with('{')).count(), DEPTH);
+    assert_eq!(canonical(&expected, 2), expected);
+
+    let json = model.to_json().pretty();
+    assert_eq!(json.matches("\"children\": [\n").count(), DEPTH);
+}
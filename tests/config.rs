@@ -0,0 +1,41 @@
+//! Config files, read by the binary.
+
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+// Runs kdl-gen with the given config file contents
+fn run_with_config(name: &str, config: &str) -> Output {
+    let path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(format!("{}.kdl", name));
+    fs::write(&path, config).unwrap();
+    Command::new(env!("CARGO_BIN_EXE_kdl-gen"))
+        .args(["--seed", "1", "--config"])
+        .arg(&path)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn readme_example_loads() {
+    let readme = include_str!("../README.md");
+    let section = &readme[readme.find("Config Files\n").unwrap()..];
+    let start = section.find("```kdl\n").unwrap() + "```kdl\n".len();
+    let example = &section[start..start + section[start..].find("```").unwrap()];
+    let output = run_with_config("readme", example);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(!output.stdout.is_empty());
+}
+
+#[test]
+fn weights_are_resolved_by_name() {
+    let output = run_with_config("by-name", "spec-version 2\nweights {\n    node {\n        maybe type=1\n    }\n}\n");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let output = run_with_config("unknown-name", "weights {\n    node {\n        maybe nope=1\n    }\n}\n");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).ends_with("\nnode has no choice for nope\n"));
+
+    let output = run_with_config("unknown-production", "weights {\n    nope {\n        select 1\n    }\n}\n");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).ends_with("\nthere is no production named nope\n"));
+}
@@ -0,0 +1,63 @@
+//! Coverage of the grammar, and batches directed by it.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+use kdl_gen::{parse, Coverage, Generator, GeneratorConfig};
+
+#[test]
+fn coverage_reports_list_every_production() {
+    for spec_version in [1, 2] {
+        // near-keyword can only be reached with near keywords on, and a
+        // production which can't be reached isn't reported
+        let conf = GeneratorConfig::default().spec_version(spec_version).near_keywords(true);
+        let coverage = Coverage::new(&conf);
+        let productions: BTreeSet<&str> = coverage.production_hits().map(|(name, _)| name).collect();
+
+        // Every production with a choice to weigh is one
+        let weights = kdl_gen::weights::Weights::defaults(spec_version);
+        let doc = parse::parse(&weights, 2).unwrap().to_model(&weights, 2);
+        for production in &doc.nodes[0].children {
+            assert!(productions.contains(production.name.as_str()), "v{}: {}", spec_version, production.name);
+        }
+
+        // Before any document, the report says every one was never used
+        let report = coverage.to_string();
+        let never_used: BTreeSet<&str> = report.lines().filter_map(|line| line.strip_prefix("  never used: ")).collect();
+        assert_eq!(never_used, productions, "v{}", spec_version);
+        assert!(report.starts_with(&format!("productions: 0/{}\n", productions.len())), "v{}:\n{}", spec_version, report);
+    }
+}
+
+#[test]
+fn until_covered_stops_once_everything_is_used() {
+    for spec_version in [1, 2] {
+        let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(format!("until-covered-{}", spec_version));
+        let _ = fs::remove_dir_all(&dir);
+        let version = spec_version.to_string();
+        let output = Command::new(env!("CARGO_BIN_EXE_kdl-gen"))
+            .args(["--seed", "1", "--spec-version", &version, "--until-covered", "--out-dir"])
+            .arg(&dir)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        let count = fs::read_dir(&dir).unwrap().filter(|entry| entry.as_ref().unwrap().path().extension().is_some_and(|ext| ext == "kdl")).count();
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.contains(&format!("\n{} documents\n", count)), "v{}:\n{}", spec_version, stderr);
+
+        // The same documents, directed the same way, only cover everything
+        // with the last of them
+        let generator = Generator::new(GeneratorConfig::default().spec_version(spec_version)).unwrap();
+        let mut coverage = Coverage::new(generator.config()).directed(true);
+        for index in 0..count {
+            assert!(!coverage.is_complete(), "v{}: complete after {} of {}", spec_version, index, count);
+            let rng = &mut Generator::rng_for_seed(Generator::derive_seed(1, index as u64));
+            let mut doc = Vec::new();
+            generator.generate_with_coverage(&mut doc, rng, &mut coverage).unwrap();
+            assert_eq!(doc, fs::read(dir.join(format!("{:04}.kdl", index))).unwrap(), "v{} document {}", spec_version, index);
+        }
+        assert!(coverage.is_complete(), "v{}:\n{}", spec_version, coverage);
+    }
+}
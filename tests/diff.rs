@@ -0,0 +1,80 @@
+//! Normalizing parser output so that parsers which print the same thing
+//! differently agree, and the `diff` subcommand which runs them.
+
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+use kdl_gen::diff::{self, Outcome};
+use kdl_gen::json::Json;
+
+#[test]
+fn json_is_compared_with_sorted_fields() {
+    let a = diff::normalize("{\"b\": [1, 2.5e3], \"a\": {\"y\": null, \"x\": \"\\u00e9\\n\"}}\n", 2);
+    let b = diff::normalize("{\"a\":{\"x\":\"é\\n\",\"y\":null},\"b\":[1,2.5e3]}", 2);
+    assert_eq!(a, b);
+    assert_eq!(a, "{\"a\":{\"x\":\"é\\n\",\"y\":null},\"b\":[1,2.5e3]}");
+    assert_eq!(Json::parse("{\"a\": 1,}"), None);
+    assert_eq!(Json::parse("\"\\ud83d\\ude00\""), Some(Json::String("😀".to_string())));
+    assert_eq!(Json::parse("\"\\ud83d\\u0041\""), None);
+    assert_eq!(Json::parse("\"\\ud83d\\ue000\""), None);
+    assert_eq!(Json::parse("\"\\ude00\""), None);
+}
+
+#[test]
+fn json_numbers_are_compared_exactly() {
+    for (a, b) in [("1", "1.0"), ("1", "1e0"), ("100", "1E+2"), ("0.00125", "12.50e-4"), ("-0", "0.0"), ("-7.5", "-75e-1")] {
+        assert_eq!(diff::normalize(a, 2), diff::normalize(b, 2), "{} {}", a, b);
+    }
+    assert_eq!(diff::normalize("[100, 0.00125, -0]", 2), "[1e2,1.25e-3,0]");
+    assert_ne!(diff::normalize("12345678901234567890", 2), diff::normalize("12345678901234567891", 2));
+}
+
+#[test]
+fn deep_json_is_compared() {
+    const DEPTH: usize = 100_000;
+    let output = format!("{}{{\"b\": 1.0, \"a\": null}}{}", "[".repeat(DEPTH), "]".repeat(DEPTH));
+    let expected = format!("{}{{\"a\":null,\"b\":1}}{}", "[".repeat(DEPTH), "]".repeat(DEPTH));
+    assert_eq!(diff::normalize(&output, 2), expected);
+}
+
+#[test]
+fn kdl_is_compared_in_its_canonical_form() {
+    assert_eq!(diff::normalize("node b=2 a=0x01 { child; }", 1), diff::normalize("node a=1 b=2 {\n    child\n}\n", 1));
+    assert_eq!(diff::normalize("  not kdl \" \n", 2), "not kdl \"");
+}
+
+#[test]
+fn crashes_timeouts_and_disagreements_are_kept() {
+    let parsed = |output: &str| Outcome::Parsed(output.to_string());
+    assert_eq!(diff::disagreement(&[parsed("a"), parsed("a")]), None);
+    assert_eq!(diff::disagreement(&[Outcome::Rejected(1), Outcome::Rejected(2)]), None);
+    assert_eq!(diff::disagreement(&[parsed("a"), parsed("b")]), Some("disagreement"));
+    assert_eq!(diff::disagreement(&[parsed("a"), Outcome::Rejected(1)]), Some("disagreement"));
+    assert_eq!(diff::disagreement(&[parsed("a"), Outcome::TimedOut]), Some("timeout"));
+    assert_eq!(diff::disagreement(&[Outcome::TimedOut, Outcome::Crashed(Some(11))]), Some("crash"));
+}
+
+// The second parser exits at once, but leaves a process behind which holds its
+// stdout open for longer than the timeout
+#[test]
+fn parsers_which_leave_stdout_open_time_out() {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("diff");
+    let _ = fs::remove_dir_all(&dir);
+    let start = Instant::now();
+    let output = Command::new(env!("CARGO_BIN_EXE_kdl-gen"))
+        .args(["diff", "--parser", "a=cat", "--parser", "b=cat; sleep 10 &", "--count", "2", "--timeout", "1", "--seed", "0", "--out-dir"])
+        .arg(&dir)
+        .output()
+        .unwrap();
+    assert!(start.elapsed() < Duration::from_secs(8), "took {:?}", start.elapsed());
+    assert_eq!(output.status.code(), Some(1), "{}", String::from_utf8_lossy(&output.stderr));
+    for index in 0..2 {
+        // Numbered with at least four digits, like batches
+        let report = Json::parse(&fs::read_to_string(dir.join(format!("{:04}.json", index))).unwrap()).unwrap();
+        let Json::Object(fields) = &report else { panic!("{:?}", report) };
+        assert!(fields.contains(&("reason".to_string(), Json::String("timeout".to_string()))), "{:?}", fields);
+        assert!(dir.join(format!("{:04}.kdl", index)).exists());
+    }
+}
@@ -0,0 +1,100 @@
+//! Unicode escapes: the generator only writes ones which name a unicode scalar
+//! value, and `--invalid` only writes ones a parser has to reject.
+
+use kdl_gen::{Category, Generator, GeneratorConfig};
+use kdl_gen::parse::{self, TokenKind};
+use kdl_gen::weights::Weights;
+
+const SEEDS: u64 = 200;
+
+// Strings made of nothing but unicode escapes, in either spec version
+fn escapes_only(spec_version: u32) -> Generator {
+    let weights = match spec_version {
+        1 => "character {\n    select 1 0\n}\nescape {\n    select 0 1\n}\n",
+        _ => "string-character {\n    select 1 0 0\n}\nescape {\n    select 0 1\n}\n",
+    };
+    let nodes = parse::parse(weights, 2).unwrap().to_model(weights, 2).nodes;
+    Generator::new(GeneratorConfig::default()
+        .spec_version(spec_version)
+        .weights(Weights::from_nodes(&nodes).unwrap()))
+        .unwrap()
+}
+
+// The hex digits of each `\u{..}` escape in a quoted string, skipping over
+// other escapes so that an escaped '\' isn't taken for the start of one
+fn unicode_escapes(quoted: &str) -> Vec<&str> {
+    let mut escapes = Vec::new();
+    let mut rest = quoted;
+    while let Some(i) = rest.find('\\') {
+        rest = &rest[i + 1..];
+        if let Some(hex) = rest.strip_prefix("u{") {
+            let end = hex.find('}').unwrap();
+            escapes.push(&hex[..end]);
+            rest = &hex[end + 1..];
+        } else if let Some(c) = rest.chars().next() {
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    escapes
+}
+
+#[test]
+fn unicode_escapes_are_scalar_values() {
+    for spec_version in [1, 2] {
+        let generator = escapes_only(spec_version);
+        let mut count = 0;
+        for seed in 0..SEEDS {
+            let doc = generator.generate_string(seed);
+            let parsed = parse::parse(&doc, spec_version).unwrap();
+            let mut nodes: Vec<&parse::Node> = parsed.nodes.iter().collect();
+            while let Some(node) = nodes.pop() {
+                nodes.extend(node.children.iter().flat_map(|children| &children.nodes));
+                let tokens = std::iter::once(&node.name)
+                    .chain(node.ty.iter())
+                    .chain(node.entries.iter().flat_map(|entry| entry.key.iter().chain(entry.ty.iter()).chain([&entry.value])));
+                for token in tokens.filter(|token| token.kind == TokenKind::String) {
+                    let text = &doc[token.span.clone()];
+                    if !text.starts_with('"') {
+                        continue;
+                    }
+                    for hex in unicode_escapes(text) {
+                        let value = u32::from_str_radix(hex, 16).unwrap();
+                        assert!((1..=6).contains(&hex.len()), "v{}: seed {} wrote \\u{{{}}}", spec_version, seed, hex);
+                        assert!(char::from_u32(value).is_some(), "v{}: seed {} wrote \\u{{{}}}", spec_version, seed, hex);
+                        count += 1;
+                    }
+                }
+            }
+        }
+        assert!(count > 1000, "v{}: only {} escapes were written", spec_version, count);
+    }
+}
+
+// Each of the ways an escape is corrupted: a surrogate, a value past 10FFFF,
+// more than 6 digits, or none at all
+#[test]
+fn corrupted_unicode_escapes_are_rejected() {
+    for spec_version in [1, 2] {
+        let generator = escapes_only(spec_version);
+        let mut seen = [false; 4];
+        for seed in 0..SEEDS {
+            let mut out = Vec::new();
+            let corruption = generator.generate_invalid(&mut out, &mut Generator::rng_for_seed(seed)).unwrap();
+            if corruption.category != Category::InvalidUnicodeEscape {
+                continue;
+            }
+            let doc = String::from_utf8(out).unwrap();
+            let hex = &doc[corruption.offset..corruption.offset + doc[corruption.offset..].find('}').unwrap()];
+            let kind = match u32::from_str_radix(hex, 16) {
+                Err(_) if hex.is_empty() => 3,
+                _ if hex.len() > 6 => 2,
+                Ok(value) if value > 0x10FFFF => 1,
+                Ok(0xD800..=0xDFFF) => 0,
+                _ => panic!("v{}: seed {} corrupted an escape to \\u{{{}}}", spec_version, seed, hex),
+            };
+            seen[kind] = true;
+            assert!(parse::parse(&doc, spec_version).is_err(), "v{}: seed {} still parses with \\u{{{}}}", spec_version, seed, hex);
+        }
+        assert_eq!(seen, [true; 4], "v{}: not every corruption was made", spec_version);
+    }
+}
@@ -0,0 +1,109 @@
+//! The options and entry points of `Generator`.
+
+use std::io;
+
+use kdl_gen::{Generator, GeneratorConfig};
+use kdl_gen::parse;
+
+#[test]
+fn writers_and_rngs_can_be_borrowed() {
+    let generator = Generator::new(GeneratorConfig::default()).unwrap();
+    let mut out = Vec::new();
+    let mut rng = Generator::rng_for_seed(7);
+    // Neither `&mut Vec<u8>` nor `&mut ChaCha8Rng` is 'static.
+    let len = generator.generate_with_rng(&mut &mut out, &mut &mut rng).unwrap();
+    assert_eq!(len, out.len());
+    assert_eq!(out, generator.generate_string(7).into_bytes());
+}
+
+#[test]
+fn total_nodes_writes_exactly_that_many_nodes() {
+    for spec_version in [1, 2] {
+        for (total, depth_max) in [(0, 3), (1, 3), (7, 1), (40, 3), (100, 2)] {
+            let conf = GeneratorConfig::default()
+                .spec_version(spec_version)
+                .total_nodes(Some(total))
+                .depth_max(depth_max)
+                .string_len_max(20)
+                .comment_len_max(20);
+            let generator = Generator::new(conf).unwrap();
+            for seed in 0..10 {
+                let mut out = Vec::new();
+                let trace = generator.generate_with_trace(&mut out, &mut Generator::rng_for_seed(seed)).unwrap();
+                let nodes = trace.spans.iter().filter(|span| span.rule == "node").count();
+                assert_eq!(nodes, total as usize, "v{} depth {} seed {}", spec_version, depth_max, seed);
+            }
+        }
+    }
+}
+
+#[test]
+fn max_bytes_caps_valid_documents() {
+    for spec_version in [1, 2] {
+        for max_bytes in [0, 1, 10, 100, 1000] {
+            let conf = GeneratorConfig::default()
+                .spec_version(spec_version)
+                .max_bytes(Some(max_bytes))
+                .string_len_max(20)
+                .comment_len_max(20);
+            let generator = Generator::new(conf.clone()).unwrap();
+            let nodes = Generator::new(conf.total_nodes(Some(50))).unwrap();
+            for seed in 0..20 {
+                for generator in [&generator, &nodes] {
+                    let mut out = Vec::new();
+                    generator.generate_verified(&mut out, &mut Generator::rng_for_seed(seed), None).unwrap();
+                    assert!(out.len() <= max_bytes, "v{} max {} seed {}: {} bytes", spec_version, max_bytes, seed, out.len());
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn unknown_spec_versions_are_rejected() {
+    for spec_version in [0, 3] {
+        let e = Generator::new(GeneratorConfig::default().spec_version(spec_version)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput, "v{}", spec_version);
+    }
+
+    let generator = Generator::new(GeneratorConfig::default()).unwrap();
+    let mut tape = generator.generate_recording(&mut Vec::new(), &mut Generator::rng_for_seed(0)).unwrap();
+    tape.conf.spec_version = 3;
+    assert_eq!(generator.replay(&mut Vec::new(), &tape).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+}
+
+// Fuzzer input of every shape: none at all, too little for a single decision,
+// all of one byte, and bytes which vary
+#[test]
+fn any_bytes_give_a_valid_document() {
+    let mut corpus: Vec<Vec<u8>> = vec![Vec::new(), vec![0], vec![0xFF], vec![0x80, 0x7F, 0x01]];
+    for len in [8, 64, 4096] {
+        corpus.push(vec![0; len]);
+        corpus.push(vec![0xFF; len]);
+        corpus.push(vec![0xAA; len]);
+    }
+    corpus.push((0..=255).collect());
+    corpus.push((0..=255).rev().collect());
+    // A fixed xorshift stream, so that the bytes vary the same way every run
+    let mut x: u32 = 0x2545F491;
+    for len in [16, 256, 4096] {
+        corpus.push((0..len).map(|_| {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            x as u8
+        }).collect());
+    }
+
+    for spec_version in [1, 2] {
+        for ascii_only in [false, true] {
+            let generator = Generator::new(GeneratorConfig::default().spec_version(spec_version).ascii_only(ascii_only)).unwrap();
+            for bytes in &corpus {
+                let doc = generator.generate_from_bytes(bytes);
+                if let Err(e) = parse::parse(&doc, spec_version) {
+                    panic!("v{} ascii {}: {} bytes starting {:02x?} were rejected: {}", spec_version, ascii_only, bytes.len(), &bytes[..bytes.len().min(8)], e);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,64 @@
+//! Documents broken by `--invalid`, and the error files written beside them.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+use kdl_gen::json::Json;
+use kdl_gen::parse;
+
+const SEEDS: u64 = 100;
+
+// The value of a field of a JSON object
+fn field<'j>(json: &'j Json, key: &str) -> &'j Json {
+    match json {
+        Json::Object(fields) => &fields.iter().find(|(k, _)| k == key).unwrap_or_else(|| panic!("no {}", key)).1,
+        _ => panic!("not an object: {:?}", json),
+    }
+}
+
+fn kdl_gen(args: &[&str]) -> Vec<u8> {
+    let output = Command::new(env!("CARGO_BIN_EXE_kdl-gen")).args(args).output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    output.stdout
+}
+
+// The same seed without --invalid writes the document before it was broken,
+// so the error file's offset can be checked against what was there
+#[test]
+fn invalid_documents_are_rejected_where_they_were_broken() {
+    let path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("invalid.json");
+    for spec_version in ["1", "2"] {
+        let mut categories = BTreeSet::new();
+        for seed in 0..SEEDS {
+            let seed = seed.to_string();
+            let args = ["--spec-version", spec_version, "--seed", &seed, "-s", "20", "-c", "20"];
+            let valid = String::from_utf8(kdl_gen(&args)).unwrap();
+            let doc = String::from_utf8(kdl_gen(&[&args[..], &["--invalid", path.to_str().unwrap()]].concat())).unwrap();
+            let error = Json::parse(&fs::read_to_string(&path).unwrap()).unwrap();
+            let (Json::String(category), Json::Number(offset)) = (field(&error, "category"), field(&error, "offset")) else {
+                panic!("v{} seed {}: {:?}", spec_version, seed, error);
+            };
+            let offset: usize = offset.parse().unwrap();
+
+            assert!(parse::parse(&doc, spec_version.parse().unwrap()).is_err(), "v{} seed {}: still parses after {}", spec_version, seed, category);
+            assert_eq!(doc[..offset], valid[..offset], "v{} seed {}: {} changed the document before {}", spec_version, seed, category, offset);
+            let (before, after) = (&doc[..offset], &doc[offset..]);
+            let broken = match category.as_str() {
+                "unclosed-children" => valid[offset..].starts_with('}'),
+                "keyword-identifier" => ["true", "false", "null", "inf", "nan"].iter().any(|keyword| after.starts_with(keyword)),
+                "hex-leading-underscore" => before.ends_with("0x") && after.starts_with('_'),
+                "raw-string-terminator" => after.starts_with('"'),
+                "invalid-unicode-escape" => before.ends_with("\\u{"),
+                "stray-brace" => after == "}",
+                _ => panic!("v{} seed {}: unknown category {}", spec_version, seed, category),
+            };
+            assert!(broken, "v{} seed {}: no {} at {}", spec_version, seed, category, offset);
+            categories.insert(category.clone());
+        }
+        for category in ["unclosed-children", "keyword-identifier", "hex-leading-underscore", "raw-string-terminator", "invalid-unicode-escape"] {
+            assert!(categories.contains(category), "v{}: no {}", spec_version, category);
+        }
+    }
+}
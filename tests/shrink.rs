@@ -0,0 +1,62 @@
+//! Shrinking documents against a predicate standing in for a failing parser.
+
+use kdl_gen::model::Node;
+use kdl_gen::{parse, shrink, Generator, GeneratorConfig};
+
+// How deeply the nodes of a valid document are nested
+fn depth(doc: &str, spec_version: u32) -> usize {
+    let model = parse::parse(doc, spec_version).unwrap().to_model(doc, spec_version);
+    let mut stack: Vec<(&Node, usize)> = model.nodes.iter().map(|node| (node, 1)).collect();
+    let mut depth = 0;
+    while let Some((node, node_depth)) = stack.pop() {
+        depth = depth.max(node_depth);
+        stack.extend(node.children.iter().map(|child| (child, node_depth + 1)));
+    }
+    depth
+}
+
+#[test]
+fn failing_documents_shrink_to_the_least_that_fails() {
+    for spec_version in [1, 2] {
+        let generator = Generator::new(GeneratorConfig::default()
+            .spec_version(spec_version)
+            .string_len_max(20)
+            .comment_len_max(20)).unwrap();
+        let mut shrunk = 0;
+        for seed in 0..40 {
+            let doc = generator.generate_string(seed);
+            if depth(&doc, spec_version) < 2 {
+                continue;
+            }
+            let fails = |doc: &str| depth(doc, spec_version) >= 2;
+            let minimal = shrink::shrink(&doc, spec_version, fails).unwrap();
+            assert!(fails(&minimal), "v{} seed {}: {:?}", spec_version, seed, minimal);
+            // Taking away any one character more leaves a document which is
+            // either invalid or doesn't fail
+            for (i, c) in minimal.char_indices() {
+                let smaller = format!("{}{}", &minimal[..i], &minimal[i + c.len_utf8()..]);
+                let still_fails = parse::parse(&smaller, spec_version).is_ok() && fails(&smaller);
+                assert!(!still_fails, "v{} seed {}: {:?} shrinks to {:?}", spec_version, seed, minimal, smaller);
+            }
+            shrunk += 1;
+        }
+        assert!(shrunk > 0, "v{}: no document had children", spec_version);
+    }
+}
+
+// Deeper than the shrinker could go if it recursed, on a test thread's stack
+#[test]
+fn deep_documents_are_shrunk() {
+    let doc = "a {\n".repeat(200_000) + &"}\n".repeat(200_000);
+    assert_eq!(shrink::shrink(&doc, 1, |_| true).unwrap(), "");
+
+    // Each node kept is tried again from where it was, not from the top
+    let doc = "a {\n".repeat(2000) + &"}\n".repeat(2000);
+    let mut tries = 0;
+    let minimal = shrink::shrink(&doc, 1, |doc| {
+        tries += 1;
+        depth(doc, 1) >= 100
+    }).unwrap();
+    assert_eq!(depth(&minimal, 1), 100);
+    assert!(tries < 10_000, "{} tries", tries);
+}
@@ -0,0 +1,67 @@
+//! KDL 2.0 documents: valid under the 2.0 grammar, and written with the
+//! syntax which 2.0 added.
+
+use std::collections::BTreeSet;
+
+use kdl_gen::{Generator, GeneratorConfig};
+use kdl_gen::parse::{self, TokenKind};
+
+const SEEDS: u64 = 300;
+
+// The text of every name, type, key and value in a parsed document
+fn tokens<'d>(doc: &'d str, parsed: &'d parse::Document) -> Vec<(TokenKind, &'d str)> {
+    let mut tokens = Vec::new();
+    let mut nodes: Vec<&parse::Node> = parsed.nodes.iter().collect();
+    while let Some(node) = nodes.pop() {
+        nodes.extend(node.children.iter().flat_map(|children| &children.nodes));
+        let node_tokens = std::iter::once(&node.name)
+            .chain(node.ty.iter())
+            .chain(node.entries.iter().flat_map(|entry| entry.key.iter().chain(entry.ty.iter()).chain([&entry.value])));
+        tokens.extend(node_tokens.map(|token| (token.kind, &doc[token.span.clone()])));
+    }
+    tokens
+}
+
+#[test]
+fn v2_documents_use_v2_syntax() {
+    let generator = Generator::new(GeneratorConfig::default().spec_version(2).string_len_max(20).comment_len_max(20)).unwrap();
+    let mut keywords = BTreeSet::new();
+    let (mut raw, mut multi_line, mut ws_escape) = (false, false, false);
+    for seed in 0..SEEDS {
+        let doc = generator.generate_string(seed);
+        let parsed = match parse::parse(&doc, 2) {
+            Ok(parsed) => parsed,
+            Err(e) => panic!("seed {} was rejected at {}", seed, e),
+        };
+        for (kind, text) in tokens(&doc, &parsed) {
+            match kind {
+                TokenKind::Keyword | TokenKind::Number if text.starts_with('#') => {
+                    keywords.insert(text.to_string());
+                }
+                TokenKind::String => {
+                    raw |= text.starts_with("#\"") || text.starts_with("##");
+                    multi_line |= text.trim_start_matches('#').starts_with("\"\"\"");
+                    ws_escape |= text.starts_with('"') && text.split('\\').skip(1).any(|rest| rest.starts_with(|c: char| c.is_whitespace()));
+                }
+                _ => {}
+            }
+        }
+    }
+    let expected: BTreeSet<String> = ["#true", "#false", "#null", "#inf", "#-inf", "#nan"].map(String::from).into();
+    assert_eq!(keywords, expected);
+    assert!(raw, "no raw strings");
+    assert!(multi_line, "no multi-line strings");
+    assert!(ws_escape, "no whitespace escapes");
+}
+
+#[test]
+fn v1_documents_have_no_v2_syntax() {
+    let generator = Generator::new(GeneratorConfig::default().string_len_max(20).comment_len_max(20)).unwrap();
+    for seed in 0..SEEDS {
+        let doc = generator.generate_string(seed);
+        let parsed = parse::parse(&doc, 1).unwrap();
+        for (kind, text) in tokens(&doc, &parsed) {
+            assert!(!text.starts_with('#'), "seed {} has the {:?} {}", seed, kind, text);
+        }
+    }
+}
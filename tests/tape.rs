@@ -0,0 +1,178 @@
+//! Recording decision tapes, and replaying them as written or edited.
+
+use std::path::PathBuf;
+use std::process::Command;
+
+use rand::Rng;
+
+use kdl_gen::tape::{Decision, Tape};
+use kdl_gen::weights::Weights;
+use kdl_gen::{parse, Generator, GeneratorConfig};
+
+const SEEDS: u64 = 50;
+
+fn record(generator: &Generator, seed: u64) -> (Vec<u8>, Tape) {
+    let mut out = Vec::new();
+    let tape = generator.generate_recording(&mut out, &mut Generator::rng_for_seed(seed)).unwrap();
+    (out, tape)
+}
+
+fn replay(tape: &Tape) -> Vec<u8> {
+    let mut out = Vec::new();
+    Generator::new(GeneratorConfig::default()).unwrap().replay(&mut out, tape).unwrap();
+    out
+}
+
+// Replayed by a generator with the default options, so everything which
+// changes the document has to come from the tape's header
+#[test]
+fn tapes_replay_the_document_they_recorded() {
+    for spec_version in [1, 2] {
+        let base = GeneratorConfig::default()
+            .spec_version(spec_version)
+            .depth_max(4)
+            .string_len_max(20)
+            .comment_len_max(20);
+        for (options, conf) in [
+            ("", base.clone()),
+            (" near misses", base.clone().raw_string_near_misses(true).near_keywords(true).identifier_len_max(4)),
+            (" limits", base.clone().raw_hash_max(20).comment_nesting_max(1)),
+            (" total nodes", base.clone().total_nodes(Some(12))),
+            (" max bytes", base.clone().total_nodes(Some(30)).max_bytes(Some(300))),
+        ] {
+            let generator = Generator::new(conf).unwrap();
+            for seed in 0..SEEDS {
+                let (doc, tape) = record(&generator, seed);
+                let parsed = Tape::parse(&tape.to_string()).unwrap();
+                assert_eq!(parsed, tape, "v{}{} seed {}", spec_version, options, seed);
+                assert_eq!(replay(&parsed), doc, "v{}{} seed {}", spec_version, options, seed);
+            }
+        }
+    }
+}
+
+// Every option changes the document, so the header has to hold all of them
+#[test]
+fn every_option_is_recorded() {
+    let weights = "node {\n    maybe 0.1 type=0.8\n    maybe 0.3\n}\n\"escape\" {\n    select 4 1\n}\nvalue {\n    select string=3 number=1\n}\n";
+    let weights = Weights::from_nodes(&parse::parse(weights, 2).unwrap().to_model(weights, 2).nodes).unwrap();
+    let conf = GeneratorConfig::default()
+        .spec_version(2)
+        .ascii_only(true)
+        .depth_max(2)
+        .nodes_per_child_min(1)
+        .nodes_per_child_max(9)
+        .extra_space_max(0)
+        .props_or_args_max(1)
+        .blank_lines_max(2)
+        .identifier_len_max(7)
+        .string_len_max(9)
+        .num_len_max(3)
+        .comment_len_max(11)
+        .raw_hash_max(2)
+        .comment_nesting_max(1)
+        .raw_string_near_misses(true)
+        .near_keywords(true)
+        .debug(true)
+        .total_nodes(Some(25))
+        .max_bytes(Some(5000))
+        .weights(weights);
+    let generator = Generator::new(conf).unwrap();
+    for seed in 0..10 {
+        let (doc, tape) = record(&generator, seed);
+        let parsed = Tape::parse(&tape.to_string()).unwrap();
+        assert_eq!(&parsed.conf, generator.config(), "seed {}", seed);
+        assert_eq!(replay(&parsed), doc, "seed {}", seed);
+    }
+}
+
+#[test]
+fn replay_tape_alone_writes_the_recorded_document() {
+    let path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("total-nodes.tape");
+    let recorded = Command::new(env!("CARGO_BIN_EXE_kdl-gen"))
+        .args(["--seed", "3", "--spec-version", "2", "--total-nodes", "9", "--record-tape"])
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert!(recorded.status.success());
+    let replayed = Command::new(env!("CARGO_BIN_EXE_kdl-gen")).arg("--replay-tape").arg(&path).output().unwrap();
+    assert!(replayed.status.success());
+    assert_eq!(String::from_utf8_lossy(&replayed.stdout), String::from_utf8_lossy(&recorded.stdout));
+}
+
+#[test]
+fn edited_tapes_replay_valid_documents() {
+    for spec_version in [1, 2] {
+        let generator = Generator::new(GeneratorConfig::default()
+            .spec_version(spec_version)
+            .string_len_max(20)
+            .comment_len_max(20)).unwrap();
+        for seed in 0..SEEDS {
+            let (_, mut tape) = record(&generator, seed);
+            for decision in tape.decisions.iter_mut().rev().step_by(3) {
+                match decision {
+                    Decision::Select(i) => *i += 1,
+                    Decision::Maybe(taken) => *taken = !*taken,
+                    Decision::Range(n) => *n = n.saturating_mul(2),
+                    Decision::Sample(_) => {}
+                }
+            }
+            let edited = String::from_utf8(replay(&tape)).unwrap();
+            assert!(parse::parse(&edited, spec_version).is_ok(), "v{} seed {}:\n{}", spec_version, seed, edited);
+
+            // Cut short, the rest of the document is made up at random
+            tape.decisions.truncate(tape.decisions.len() / 2);
+            let truncated = String::from_utf8(replay(&tape)).unwrap();
+            assert!(parse::parse(&truncated, spec_version).is_ok(), "v{} seed {}:\n{}", spec_version, seed, truncated);
+        }
+    }
+}
+
+// Replayed decisions are held to --max-bytes like random ones, so an edit
+// which would go over it is made at random instead
+#[test]
+fn edited_tapes_keep_to_max_bytes() {
+    const MAX_BYTES: usize = 120;
+    for spec_version in [1, 2] {
+        let generator = Generator::new(GeneratorConfig::default()
+            .spec_version(spec_version)
+            .max_bytes(Some(MAX_BYTES))).unwrap();
+        for seed in 0..SEEDS {
+            let (_, mut tape) = record(&generator, seed);
+            let rng = &mut Generator::rng_for_seed(seed);
+            for decision in tape.decisions.iter_mut() {
+                match decision {
+                    Decision::Select(i) if rng.gen_bool(0.3) => *i = rng.gen_range(0..4),
+                    Decision::Maybe(taken) if rng.gen_bool(0.3) => *taken = !*taken,
+                    Decision::Range(n) if rng.gen_bool(0.3) => *n = rng.gen_range(0..100),
+                    _ => {}
+                }
+            }
+            let edited = String::from_utf8(replay(&tape)).unwrap();
+            assert!(edited.len() <= MAX_BYTES, "v{} seed {}: {} bytes", spec_version, seed, edited.len());
+            assert!(parse::parse(&edited, spec_version).is_ok(), "v{} seed {}:\n{}", spec_version, seed, edited);
+        }
+    }
+}
+
+#[test]
+fn tapes_are_read_with_comments_and_rejected_when_malformed() {
+    let tape = Tape::parse("// hand written\nkdl-gen-tape spec-version=1 depth-max=2\ns1 m0 // why\nr3 \"a \\\"b\\\"\"\n").unwrap();
+    assert_eq!((tape.conf.spec_version, tape.conf.depth_max), (1, 2));
+    assert_eq!(tape.decisions, vec![
+        Decision::Select(1),
+        Decision::Maybe(false),
+        Decision::Range(3),
+        Decision::Sample("a \"b\"".to_string()),
+    ]);
+
+    for (text, message) in [
+        ("s1", "expected a `kdl-gen-tape` header"),
+        ("kdl-gen-tape depth=3", "unknown option: `depth=3`"),
+        ("kdl-gen-tape s1 depth-max=3", "options have to come before the decisions: `depth-max=3`"),
+        ("kdl-gen-tape m2", "unknown decision: `m2`"),
+        ("kdl-gen-tape \"open", "unclosed string"),
+    ] {
+        assert_eq!(Tape::parse(text).unwrap_err().message, message, "{}", text);
+    }
+}
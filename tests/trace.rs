@@ -0,0 +1,90 @@
+//! Traces: each span covers exactly the text its production wrote, and is
+//! placed on the lines and columns a parser would count.
+
+use kdl_gen::{Generator, GeneratorConfig};
+use kdl_gen::trace::{self, TraceSpan};
+
+const SEEDS: u64 = 40;
+
+// Writes the document from `pos` on with --debug tags around every span, as
+// a --debug run writes them, up to the end of the last span, and returns
+// where that is. Each span is followed by the deeper ones inside it.
+fn tagged(doc: &str, spans: &[TraceSpan], depth: usize, mut pos: usize, out: &mut String) -> usize {
+    let mut i = 0;
+    while i < spans.len() && spans[i].depth >= depth {
+        let span = &spans[i];
+        out.push_str(&doc[pos..span.start]);
+        out.push_str(&format!("<{}>", span.rule.to_uppercase()));
+        let inner = spans[i + 1..].iter().take_while(|inner| inner.depth > span.depth).count();
+        pos = tagged(doc, &spans[i + 1..i + 1 + inner], depth + 1, span.start, out);
+        out.push_str(&doc[pos..span.end]);
+        out.push_str(&format!("</{}>", span.rule.to_uppercase()));
+        pos = span.end;
+        i += 1 + inner;
+    }
+    pos
+}
+
+// The line and column of every char boundary, counted afresh: CRLF is one
+// newline, and VT is only one in spec version 2
+fn positions(doc: &str, spec_version: u32) -> Vec<Option<(usize, usize)>> {
+    let mut positions = vec![None; doc.len() + 1];
+    let (mut line, mut column) = (1, 1);
+    for (i, c) in doc.char_indices() {
+        positions[i] = Some((line, column));
+        let newline = match c {
+            '\r' => !doc[i + 1..].starts_with('\n'),
+            '\u{000B}' => spec_version == 2,
+            '\n' | '\u{0085}' | '\u{000C}' | '\u{2028}' | '\u{2029}' => true,
+            _ => false,
+        };
+        (line, column) = if newline { (line + 1, 1) } else { (line, column + 1) };
+    }
+    positions[doc.len()] = Some((line, column));
+    positions
+}
+
+#[test]
+fn spans_match_what_their_productions_wrote() {
+    for spec_version in [1, 2] {
+        let conf = GeneratorConfig::default()
+            .spec_version(spec_version)
+            .string_len_max(20)
+            .comment_len_max(20)
+            .blank_lines_max(3);
+        let generator = Generator::new(conf.clone()).unwrap();
+        let debug = Generator::new(conf.debug(true)).unwrap();
+        let (mut crlf, mut vt) = (false, false);
+        for seed in 0..SEEDS {
+            let mut out = Vec::new();
+            let trace = generator.generate_with_trace(&mut out, &mut Generator::rng_for_seed(seed)).unwrap();
+            let doc = String::from_utf8(out).unwrap();
+            assert!(!trace.spans.is_empty());
+
+            let mut expected = String::new();
+            let pos = tagged(&doc, &trace.spans, 0, 0, &mut expected);
+            expected.push_str(&doc[pos..]);
+            assert!(expected == debug.generate_string(seed), "v{}: seed {} has spans other than its --debug tags", spec_version, seed);
+
+            let positions = positions(&doc, spec_version);
+            for span in &trace.spans {
+                assert_eq!(positions[span.start], Some((span.start_line, span.start_column)), "v{}: seed {} {:?}", spec_version, seed, span);
+                assert_eq!(positions[span.end], Some((span.end_line, span.end_column)), "v{}: seed {} {:?}", spec_version, seed, span);
+            }
+            crlf |= doc.contains("\r\n");
+            vt |= doc.contains('\u{000B}');
+        }
+        assert!(crlf, "v{}: no document has a CRLF", spec_version);
+        assert!(vt || spec_version == 1, "v{}: no document has a VT", spec_version);
+    }
+}
+
+// v1 documents never have a VT outside of a string, so it is checked here
+#[test]
+fn newlines_are_counted_by_spec_version() {
+    let doc = "a\r\nb\u{000B}c\rd";
+    for (offset, v1, v2) in [(2, (1, 3), (1, 3)), (3, (2, 1), (2, 1)), (5, (2, 3), (3, 1)), (8, (3, 2), (4, 2))] {
+        assert_eq!(trace::line_column(doc, offset, 1), v1, "v1 offset {}", offset);
+        assert_eq!(trace::line_column(doc, offset, 2), v2, "v2 offset {}", offset);
+    }
+}
@@ -0,0 +1,63 @@
+//! Variants of a document, which should be written differently but mean the
+//! same.
+
+use kdl_gen::{Generator, GeneratorConfig};
+use kdl_gen::parse;
+
+const SEEDS: u64 = 50;
+const VARIANTS: usize = 2;
+
+#[test]
+fn variants_mean_the_same_as_the_document() {
+    for spec_version in [1, 2] {
+        for ascii_only in [false, true] {
+            let generator = Generator::new(GeneratorConfig::default()
+                .spec_version(spec_version)
+                .ascii_only(ascii_only)
+                .string_len_max(20)
+                .comment_len_max(20)).unwrap();
+            // An empty or tiny document can be written again just as it was
+            let mut different = 0;
+            for seed in 0..SEEDS {
+                let rng = &mut Generator::rng_for_seed(seed);
+                let mut out = Vec::new();
+                let model = generator.generate_with_model(&mut out, rng).unwrap();
+                let expected = model.to_kdl(spec_version);
+                for variant in 0..VARIANTS {
+                    let mut doc = Vec::new();
+                    generator.generate_variant(&mut doc, rng, &model)
+                        .unwrap_or_else(|e| panic!("v{} seed {} variant {}: {}", spec_version, seed, variant, e));
+                    let doc = String::from_utf8(doc).unwrap();
+                    let parsed = parse::parse(&doc, spec_version).unwrap().to_model(&doc, spec_version);
+                    assert_eq!(parsed.to_kdl(spec_version), expected, "v{} seed {} variant {}", spec_version, seed, variant);
+                    if doc.as_bytes() != out {
+                        different += 1;
+                    }
+                }
+            }
+            assert!(different > SEEDS as usize * VARIANTS * 9 / 10, "v{}: only {} variants differ", spec_version, different);
+        }
+    }
+}
+
+// Deeper than the variant writer could go if it recursed, on a test thread's stack
+#[test]
+fn deep_models_are_written_as_variants() {
+    const DEPTH: usize = 3000;
+    for spec_version in [1, 2] {
+        let text = "a {".repeat(DEPTH) + &"}".repeat(DEPTH);
+        let model = parse::parse(&text, spec_version).unwrap().to_model(&text, spec_version);
+        let generator = Generator::new(GeneratorConfig::default()
+            .spec_version(spec_version)
+            .string_len_max(1)
+            .comment_len_max(1)
+            .extra_space_max(0)
+            .blank_lines_max(0)).unwrap();
+        let mut doc = Vec::new();
+        generator.generate_variant(&mut doc, &mut Generator::rng_for_seed(0), &model)
+            .unwrap_or_else(|e| panic!("v{}: {}", spec_version, e));
+        let doc = String::from_utf8(doc).unwrap();
+        let parsed = parse::parse(&doc, spec_version).unwrap().to_model(&doc, spec_version);
+        assert_eq!(parsed.to_kdl(spec_version), model.to_kdl(spec_version), "v{}", spec_version);
+    }
+}
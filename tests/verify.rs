@@ -0,0 +1,112 @@
+//! Properties every generated document should have, checked over thousands of
+//! seeds with the crate's own parser.
+
+use kdl_gen::{Generator, GeneratorConfig};
+use kdl_gen::parse;
+use kdl_gen::weights::Weights;
+
+const SEEDS: u64 = 125;
+
+// Both spec versions, with and without ascii_only, under the options which
+// change the most about how a document is written. Strings and comments are
+// kept short so that thousands of documents can be checked quickly.
+fn generators() -> Vec<(String, Generator)> {
+    let mut generators = Vec::new();
+    for spec_version in [1, 2] {
+        for ascii_only in [false, true] {
+            let base = GeneratorConfig::default()
+                .spec_version(spec_version)
+                .ascii_only(ascii_only)
+                .string_len_max(20)
+                .comment_len_max(20);
+            let name = format!("v{}{}", spec_version, if ascii_only { " ascii" } else { "" });
+            for (options, conf) in [
+                ("", base.clone()),
+                (" near misses", base.clone().raw_string_near_misses(true).near_keywords(true).identifier_len_max(4)),
+                (" total nodes", base.clone().total_nodes(Some(8)).depth_max(5)),
+                (" max bytes", base.clone().max_bytes(Some(500)).raw_hash_max(20)),
+            ] {
+                generators.push((format!("{}{}", name, options), Generator::new(conf).unwrap()));
+            }
+        }
+    }
+    generators
+}
+
+#[test]
+fn documents_are_valid() {
+    for (name, generator) in generators() {
+        for seed in 0..SEEDS {
+            let mut out = Vec::new();
+            if let Err(e) = generator.generate_verified(&mut out, &mut Generator::rng_for_seed(seed), None) {
+                panic!("{}: seed {} was rejected at {}", name, seed, e);
+            }
+            if let Some(max) = generator.config().max_bytes {
+                assert!(out.len() <= max, "{}: seed {} wrote {} bytes", name, seed, out.len());
+            }
+        }
+    }
+}
+
+#[test]
+fn documents_mean_what_the_generator_meant() {
+    for (name, generator) in generators() {
+        let spec_version = generator.config().spec_version;
+        for seed in 0..SEEDS {
+            let mut out = Vec::new();
+            let model = generator.generate_with_model(&mut out, &mut Generator::rng_for_seed(seed)).unwrap();
+            let doc = String::from_utf8(out).unwrap();
+            let parsed = parse::parse(&doc, spec_version).unwrap().to_model(&doc, spec_version);
+            assert!(parsed == model, "{}: seed {} parses to something other than its model", name, seed);
+        }
+    }
+}
+
+#[test]
+fn invalid_documents_are_rejected() {
+    for (name, generator) in generators() {
+        let spec_version = generator.config().spec_version;
+        for seed in 0..SEEDS {
+            let mut out = Vec::new();
+            let corruption = generator.generate_invalid(&mut out, &mut Generator::rng_for_seed(seed)).unwrap();
+            let doc = String::from_utf8_lossy(&out);
+            assert!(parse::parse(&doc, spec_version).is_err(), "{}: seed {} still parses after {:?}", name, seed, corruption);
+        }
+    }
+}
+
+// One node nested depth_max deep, as in the README, with as little else as
+// possible so that it is quick to write
+fn deep(depth_max: u32) -> GeneratorConfig {
+    let weights = "node {\n    maybe 0 0 1\n}\nnode-children {\n    maybe 0\n}\n";
+    let nodes = parse::parse(weights, 2).unwrap().to_model(weights, 2).nodes;
+    GeneratorConfig::default()
+        .depth_max(depth_max)
+        .nodes_per_child_min(1)
+        .nodes_per_child_max(1)
+        .props_or_args_max(0)
+        .identifier_len_max(1)
+        .string_len_max(1)
+        .comment_len_max(1)
+        .extra_space_max(0)
+        .blank_lines_max(0)
+        .weights(Weights::from_nodes(&nodes).unwrap())
+}
+
+// Deeper than the parser could go if it recursed, on a test thread's stack
+#[test]
+fn deep_documents_are_verified() {
+    const DEPTH: u32 = 100_000;
+    let generator = Generator::new(deep(DEPTH)).unwrap();
+    let mut out = Vec::new();
+    generator.generate_verified(&mut out, &mut Generator::rng_for_seed(0), None).unwrap();
+    let doc = String::from_utf8(out).unwrap();
+    let model = parse::parse(&doc, 1).unwrap().to_model(&doc, 1);
+    let mut depth = 0;
+    let mut nodes = &model.nodes;
+    while let [node] = nodes.as_slice() {
+        depth += 1;
+        nodes = &node.children;
+    }
+    assert_eq!(depth, DEPTH + 1);
+}